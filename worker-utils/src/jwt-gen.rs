@@ -2,23 +2,60 @@ use jsonwebtoken::{EncodingKey, Header, get_current_timestamp};
 use std::{env, fs};
 use yral_worker_utils::jwt;
 
+const DEFAULT_EXPIRY_DAYS: u64 = 180;
+
+fn random_jti() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("failed to generate jti");
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
 fn main() {
     let jwt_pem_file = env::var("JWT_PEM_FILE").expect("JWT_PEM_FILE is required");
     let jwt_aud = env::var("JWT_AUD").expect("JWT_AUD is required");
+    // comma separated, e.g. "hon:vote,hon:withdraw" or "*" for every scope
+    let jwt_scopes = env::var("JWT_SCOPES").expect("JWT_SCOPES is required");
+    let jwt_sub = env::var("JWT_SUB").ok();
+    // key id of JWT_PEM_FILE, must match a key in the worker's key set
+    let jwt_kid = env::var("JWT_KID").ok();
+    let expiry_days = env::var("JWT_EXPIRY_DAYS")
+        .map(|days| days.parse::<u64>().expect("invalid JWT_EXPIRY_DAYS"))
+        .unwrap_or(DEFAULT_EXPIRY_DAYS);
+
     let enc_key_raw = fs::read(jwt_pem_file).expect("JWT_PEM_FILE is not valid");
     let enc_key = EncodingKey::from_ed_pem(&enc_key_raw).expect("invalid JWT_PEM_FILE");
 
-    let header = Header::new(jsonwebtoken::Algorithm::EdDSA);
-    // 180 days
-    let expiry = get_current_timestamp() + (180 * 24 * 60 * 60);
+    let mut header = Header::new(jsonwebtoken::Algorithm::EdDSA);
+    if jwt_kid.is_none() {
+        eprintln!("JWT_KID not set, token will only verify against the fallback key");
+    }
+    header.kid = jwt_kid;
+    let now = get_current_timestamp();
+    let expiry = now + (expiry_days * 24 * 60 * 60);
+
+    let scopes: Vec<String> = jwt_scopes
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
 
     let claims = jwt::Claims {
         aud: jwt_aud,
         exp: expiry as usize,
+        sub: jwt_sub,
+        iat: Some(now as usize),
+        nbf: Some(now as usize),
+        jti: Some(random_jti()),
+        scopes: Some(scopes),
     };
 
     let token = jsonwebtoken::encode(&header, &claims, &enc_key).expect("failed to encode JWT");
 
-    println!("JWT with 180 days expiry:");
+    println!("JWT with {expiry_days} days expiry:");
+    println!("scopes: {:?}", claims.scopes);
+    println!("jti: {}", claims.jti.as_deref().unwrap_or_default());
+    if let Some(kid) = header.kid.as_deref() {
+        println!("kid: {kid}");
+    }
     println!("{token}");
 }
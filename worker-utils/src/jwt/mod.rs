@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::OnceLock,
+};
+
+use jsonwebtoken::{
+    DecodingKey, Header, TokenData, Validation,
+    errors::{Error as JwtError, ErrorKind},
+    jwk::JwkSet,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use worker::{Env, Request};
+
+use crate::environment::{RunEnv, env_kind};
+
+mod revocation;
+
+pub use revocation::{
+    REVOCATION_KV_BINDING, RevocationList, RevocationReq, RevokedToken, SCOPE_REVOKE,
+    handle_revocation_req,
+};
+
+/// grants every scope
+pub const SCOPE_ALL: &str = "*";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub aud: String,
+    pub exp: usize,
+    /// subject the token was issued to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    /// issued at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<usize>,
+    /// not valid before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<usize>,
+    /// unique token id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// `None` for tokens minted before scopes existed, these keep full access
+    /// until they expire. An empty list grants no scopes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+}
+
+impl Claims {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        let Some(scopes) = &self.scopes else {
+            // legacy token, issued with a 180 day expiry before scopes were introduced
+            return true;
+        };
+
+        scopes.iter().any(|s| s == scope || s == SCOPE_ALL)
+    }
+
+    pub fn require_scope(&self, scope: &str) -> Result<(), (String, u16)> {
+        if self.has_scope(scope) {
+            return Ok(());
+        }
+
+        Err((format!("missing scope {scope}"), 403))
+    }
+
+    /// claims used when JWT verification is skipped
+    fn unverified(aud: String) -> Self {
+        Self {
+            aud,
+            exp: usize::MAX,
+            sub: None,
+            iat: None,
+            nbf: None,
+            jti: None,
+            scopes: Some(vec![SCOPE_ALL.to_string()]),
+        }
+    }
+}
+
+/// Public keys accepted when verifying JWTs
+///
+/// Keys are selected by the `kid` header of the token, so old and new keys
+/// can be accepted side by side while rotating the signing key.
+/// Tokens minted without a `kid` are tried against every fallback key
+#[derive(Clone, Default)]
+pub struct JwtKeySet {
+    keys: HashMap<String, DecodingKey>,
+    fallbacks: Vec<DecodingKey>,
+}
+
+impl JwtKeySet {
+    /// key set with a single fallback key, used for tokens without a `kid`
+    pub fn from_pem(public_key_pem: &str) -> Result<Self, JwtError> {
+        Ok(Self {
+            keys: HashMap::new(),
+            fallbacks: vec![DecodingKey::from_ed_pem(public_key_pem.as_bytes())?],
+        })
+    }
+
+    /// load from a secret containing either a JWKS JSON document or a PEM public key
+    pub fn from_secret(secret: &str) -> Result<Self, JwtError> {
+        let secret = secret.trim();
+        if secret.starts_with('{') {
+            Self::default().with_jwks(secret)
+        } else {
+            Self::from_pem(secret)
+        }
+    }
+
+    /// accept tokens with the given `kid`
+    pub fn with_pem(
+        mut self,
+        kid: impl Into<String>,
+        public_key_pem: &str,
+    ) -> Result<Self, JwtError> {
+        let key = DecodingKey::from_ed_pem(public_key_pem.as_bytes())?;
+        self.keys.insert(kid.into(), key);
+        Ok(self)
+    }
+
+    /// accept every key in a JWKS JSON document
+    /// keys without a `kid` are added as fallback keys
+    pub fn with_jwks(mut self, jwks_json: &str) -> Result<Self, JwtError> {
+        let jwks: JwkSet = serde_json::from_str(jwks_json)?;
+        for jwk in &jwks.keys {
+            let key = DecodingKey::from_jwk(jwk)?;
+            match jwk.common.key_id.clone() {
+                Some(kid) => {
+                    self.keys.insert(kid, key);
+                }
+                None => self.fallbacks.push(key),
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// merge keys from `other`, `kid`s in `other` take precedence
+    /// and its fallback keys are accepted alongside the existing ones
+    pub fn merge(mut self, other: JwtKeySet) -> Self {
+        self.keys.extend(other.keys);
+        self.fallbacks.extend(other.fallbacks);
+        self
+    }
+
+    /// candidate keys for a token, in the order they are tried
+    pub fn keys_for(&self, header: &Header) -> Vec<&DecodingKey> {
+        match header.kid.as_deref() {
+            Some(kid) => self.keys.get(kid).into_iter().collect(),
+            None => self.fallbacks.iter().collect(),
+        }
+    }
+
+    /// decode and validate `jwt` with the key matching its `kid`
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        jwt: &str,
+        validation: &Validation,
+    ) -> Result<TokenData<T>, JwtError> {
+        let header = jsonwebtoken::decode_header(jwt)?;
+
+        let mut res = Err(JwtError::from(ErrorKind::InvalidSignature));
+        for key in self.keys_for(&header) {
+            res = jsonwebtoken::decode::<T>(jwt, key, validation);
+            // only a signature mismatch means another key could verify the token
+            if !matches!(&res, Err(e) if matches!(e.kind(), ErrorKind::InvalidSignature)) {
+                break;
+            }
+        }
+
+        res
+    }
+}
+
+/// secret with extra keys (JWKS JSON or PEM) accepted during a rotation
+pub const JWT_PUBLIC_KEYS_SECRET: &str = "JWT_PUBLIC_KEYS";
+
+/// keys accepted for worker JWTs, parsed once per isolate
+///
+/// `default_pem` verifies tokens minted without a `kid`,
+/// the optional [`JWT_PUBLIC_KEYS_SECRET`] adds keys during a rotation
+pub fn jwt_keys(env: &Env, default_pem: &str) -> worker::Result<&'static JwtKeySet> {
+    static KEYS: OnceLock<JwtKeySet> = OnceLock::new();
+    if let Some(keys) = KEYS.get() {
+        return Ok(keys);
+    }
+
+    let mut keys = JwtKeySet::from_pem(default_pem)
+        .map_err(|e| worker::Error::RustError(format!("invalid default JWT key: {e}")))?;
+    if let Ok(extra) = env.secret(JWT_PUBLIC_KEYS_SECRET) {
+        let extra = JwtKeySet::from_secret(&extra.to_string()).map_err(|e| {
+            worker::Error::RustError(format!("invalid {JWT_PUBLIC_KEYS_SECRET}: {e}"))
+        })?;
+        keys = keys.merge(extra);
+    }
+
+    Ok(KEYS.get_or_init(|| keys))
+}
+
+pub fn verify_jwt(keys: &JwtKeySet, aud: String, jwt: &str) -> Result<Claims, JwtError> {
+    let mut validation = Validation::default();
+    validation.aud = Some(HashSet::from([aud]));
+    validation.algorithms = vec![jsonwebtoken::Algorithm::EdDSA];
+    validation.validate_nbf = true;
+
+    let token = keys.decode::<Claims>(jwt, &validation)?;
+
+    Ok(token.claims)
+}
+
+pub async fn verify_jwt_from_header(
+    keys: &JwtKeySet,
+    revocations: &RevocationList,
+    aud: String,
+    req: &Request,
+) -> Result<Claims, (String, u16)> {
+    if env_kind() == RunEnv::Mock || env_kind() == RunEnv::Local {
+        println!("Skipping JWT verification in mock/local environment");
+        return Ok(Claims::unverified(aud));
+    }
+
+    let jwt = req
+        .headers()
+        .get("Authorization")
+        .ok()
+        .flatten()
+        .ok_or_else(|| ("missing Authorization header".to_string(), 401))?;
+
+    let jwt = jwt.to_string();
+    if !jwt.starts_with("Bearer ") {
+        return Err(("invalid Authorization header".to_string(), 401));
+    }
+
+    let jwt = &jwt[7..];
+    let claims = verify_jwt(keys, aud, jwt).map_err(|_| ("invalid JWT".to_string(), 401))?;
+
+    let revoked = revocations
+        .is_revoked(&claims, jwt)
+        .await
+        .map_err(|e| (format!("failed to check revocation: {e}"), 500))?;
+    if revoked {
+        return Err(("JWT revoked".to_string(), 401));
+    }
+
+    Ok(claims)
+}
+
+/// verify the JWT in the Authorization header and check that it grants `scope`
+pub async fn verify_jwt_scope_from_header(
+    keys: &JwtKeySet,
+    revocations: &RevocationList,
+    aud: String,
+    scope: &str,
+    req: &Request,
+) -> Result<Claims, (String, u16)> {
+    let claims = verify_jwt_from_header(keys, revocations, aud, req).await?;
+    claims.require_scope(scope)?;
+
+    Ok(claims)
+}
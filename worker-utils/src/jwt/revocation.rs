@@ -0,0 +1,172 @@
+use std::{cell::RefCell, collections::HashMap, sync::Once};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::{Date, Env, Request, Response, Result, console_warn, kv::KvStore};
+
+use super::{Claims, JwtKeySet, verify_jwt_scope_from_header};
+
+/// KV namespace holding revoked tokens
+pub const REVOCATION_KV_BINDING: &str = "JWT_REVOCATIONS";
+/// required to add or remove revocations
+pub const SCOPE_REVOKE: &str = "jwt:revoke";
+
+/// how long a lookup is cached inside the isolate
+const CACHE_TTL_MS: u64 = 60 * 1000;
+
+/// warns once per isolate that revocation checks are skipped
+static UNBOUND_WARNING: Once = Once::new();
+
+thread_local! {
+    /// revocation key -> (revoked, cached until)
+    static REVOCATION_CACHE: RefCell<HashMap<String, (bool, u64)>> = RefCell::new(HashMap::new());
+}
+
+/// A revoked token, identified by its `jti` or,
+/// for tokens minted without one, the SHA-256 of the encoded token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RevokedToken {
+    Jti(String),
+    /// hex encoded
+    TokenHash(String),
+}
+
+impl RevokedToken {
+    pub fn from_token(jwt: &str) -> Self {
+        let hash = Sha256::digest(jwt.as_bytes());
+        Self::TokenHash(hash.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    fn kv_key(&self) -> String {
+        match self {
+            Self::Jti(jti) => format!("jti:{jti}"),
+            Self::TokenHash(hash) => format!("sha256:{hash}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevocationReq {
+    pub token: RevokedToken,
+    /// seconds since epoch, the entry is dropped from KV afterwards
+    /// should be the `exp` of the revoked token
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+fn cache_get(key: &str, now: u64) -> Option<bool> {
+    REVOCATION_CACHE.with_borrow(|cache| {
+        cache
+            .get(key)
+            .filter(|(_, until)| *until > now)
+            .map(|(revoked, _)| *revoked)
+    })
+}
+
+fn cache_set(key: String, revoked: bool, now: u64) {
+    REVOCATION_CACHE.with_borrow_mut(|cache| {
+        cache.retain(|_, (_, until)| *until > now);
+        cache.insert(key, (revoked, now + CACHE_TTL_MS));
+    })
+}
+
+/// Revoked worker JWTs, backed by the [`REVOCATION_KV_BINDING`] KV namespace
+///
+/// If the namespace is not bound, revocation checks are skipped with a warning,
+/// revoking a token fails until it is bound
+pub struct RevocationList(Option<KvStore>);
+
+impl RevocationList {
+    pub fn from_env(env: &Env) -> Self {
+        Self(env.kv(REVOCATION_KV_BINDING).ok())
+    }
+
+    async fn contains(&self, kv: &KvStore, token: &RevokedToken) -> Result<bool> {
+        let key = token.kv_key();
+        let now = Date::now().as_millis();
+        if let Some(revoked) = cache_get(&key, now) {
+            return Ok(revoked);
+        }
+
+        let revoked = kv.get(&key).text().await?.is_some();
+        cache_set(key, revoked, now);
+
+        Ok(revoked)
+    }
+
+    /// whether the token was revoked by `jti` or by hash
+    ///
+    /// always `false` while the namespace is not bound
+    pub async fn is_revoked(&self, claims: &Claims, jwt: &str) -> Result<bool> {
+        let Some(kv) = &self.0 else {
+            UNBOUND_WARNING.call_once(|| {
+                console_warn!(
+                    "{REVOCATION_KV_BINDING} is not bound, skipping JWT revocation checks"
+                )
+            });
+            return Ok(false);
+        };
+
+        if let Some(jti) = &claims.jti {
+            if self.contains(kv, &RevokedToken::Jti(jti.clone())).await? {
+                return Ok(true);
+            }
+        }
+
+        self.contains(kv, &RevokedToken::from_token(jwt)).await
+    }
+
+    fn kv(&self) -> Result<&KvStore> {
+        self.0.as_ref().ok_or_else(|| {
+            worker::Error::RustError(format!("{REVOCATION_KV_BINDING} is not bound"))
+        })
+    }
+
+    pub async fn revoke(&self, req: &RevocationReq) -> Result<()> {
+        let key = req.token.kv_key();
+        let mut put = self.kv()?.put(&key, "revoked")?;
+        if let Some(expires_at) = req.expires_at {
+            put = put.expiration(expires_at);
+        }
+        put.execute().await?;
+        cache_set(key, true, Date::now().as_millis());
+
+        Ok(())
+    }
+
+    pub async fn unrevoke(&self, token: &RevokedToken) -> Result<()> {
+        let key = token.kv_key();
+        self.kv()?.delete(&key).await?;
+        cache_set(key, false, Date::now().as_millis());
+
+        Ok(())
+    }
+}
+
+/// admin route for revocations
+///
+/// `POST` revokes the token in the body, `DELETE` removes the revocation
+/// requires a JWT with the [`SCOPE_REVOKE`] scope
+pub async fn handle_revocation_req(
+    mut req: Request,
+    env: &Env,
+    keys: &JwtKeySet,
+    aud: String,
+) -> Result<Response> {
+    let revocations = RevocationList::from_env(env);
+    if let Err((msg, code)) =
+        verify_jwt_scope_from_header(keys, &revocations, aud, SCOPE_REVOKE, &req).await
+    {
+        return Response::error(msg, code);
+    }
+
+    let revocation: RevocationReq = req.json().await?;
+    match req.method() {
+        worker::Method::Post => revocations.revoke(&revocation).await?,
+        worker::Method::Delete => revocations.unrevoke(&revocation.token).await?,
+        _ => return Response::error("method not allowed", 405),
+    }
+
+    Response::ok("done")
+}
@@ -4,6 +4,8 @@ use worker::*;
 pub mod environment;
 pub mod icp;
 pub mod jwt;
+#[cfg(feature = "signed")]
+pub mod signed;
 pub mod storage;
 
 #[derive(Default)]
@@ -0,0 +1,321 @@
+use std::fmt::Display;
+
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+use worker::{Date, Env, Request, Response};
+use yral_identity::{Signature, msg_builder::Message};
+
+mod nonce;
+
+pub use nonce::NonceStore;
+
+/// signed messages older than this are rejected
+pub const DEFAULT_MAX_AGE_MS: u64 = 5 * 60 * 1000;
+/// tolerated clock skew for messages signed "in the future"
+const MAX_CLOCK_SKEW_MS: u64 = 30 * 1000;
+/// signed requests without a [`MessageNonce`] are accepted until this time (ms since epoch)
+/// so clients can move to nonced messages, unless [`UNNONCED_CUTOFF_VAR`] is set
+pub const DEFAULT_UNNONCED_CUTOFF_MS: u64 = 1_798_761_600_000; // 2027-01-01T00:00:00Z
+/// env var overriding [`DEFAULT_UNNONCED_CUTOFF_MS`] per deployment, ms since epoch
+pub const UNNONCED_CUTOFF_VAR: &str = "UNNONCED_CUTOFF_MS";
+
+/// the un-nonced cutoff of this deployment, see [`UNNONCED_CUTOFF_VAR`]
+pub fn unnonced_cutoff_from_env(env: &Env) -> u64 {
+    env.var(UNNONCED_CUTOFF_VAR)
+        .ok()
+        .and_then(|v| v.to_string().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_UNNONCED_CUTOFF_MS)
+}
+
+/// JSON body of a rejected signed request,
+/// encoded like the `WorkerError` the workers return for other failed requests
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SignatureErrorBody {
+    InvalidSignature,
+    Internal(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    InvalidBody(String),
+    InvalidSignature,
+    Expired,
+    Replayed,
+    Storage(String),
+}
+
+impl SignatureError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::InvalidBody(_) => 400,
+            Self::InvalidSignature | Self::Expired => 401,
+            Self::Replayed => 409,
+            Self::Storage(_) => 500,
+        }
+    }
+
+    pub fn to_body(&self) -> SignatureErrorBody {
+        match self {
+            // a payload that can't be read can't be verified either
+            Self::InvalidBody(_) | Self::InvalidSignature | Self::Expired | Self::Replayed => {
+                SignatureErrorBody::InvalidSignature
+            }
+            Self::Storage(e) => SignatureErrorBody::Internal(e.clone()),
+        }
+    }
+
+    pub fn to_response(&self) -> worker::Result<Response> {
+        Ok(Response::from_json(&self.to_body())?.with_status(self.status_code()))
+    }
+}
+
+impl Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidBody(e) => write!(f, "invalid body: {e}"),
+            Self::InvalidSignature => write!(f, "invalid signature"),
+            Self::Expired => write!(f, "signed message expired"),
+            Self::Replayed => write!(f, "signed message already used"),
+            Self::Storage(e) => write!(f, "failed to check message nonce: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+fn check_timestamp(timestamp_ms: u64, now: u64, max_age_ms: u64) -> Result<(), SignatureError> {
+    if timestamp_ms > now + MAX_CLOCK_SKEW_MS {
+        return Err(SignatureError::Expired);
+    }
+    if now.saturating_sub(timestamp_ms) > max_age_ms {
+        return Err(SignatureError::Expired);
+    }
+
+    Ok(())
+}
+
+/// Replay protection carried inside signed messages
+///
+/// The pair is unique per signed message, so a message can only be used once
+/// while it's fresh, and is rejected as expired afterwards
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub struct MessageNonce {
+    /// when the message was signed, ms since epoch
+    pub timestamp_ms: u64,
+    /// chosen by the client, unique for every message it signs
+    pub idempotency_key: String,
+}
+
+/// What a verified request is deduplicated by in a [`NonceStore`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayKey {
+    /// the nonce the request was signed with
+    Nonce(MessageNonce),
+    /// SHA-256 of the signature of a request signed without a nonce, hex encoded
+    SignatureHash(String),
+}
+
+impl ReplayKey {
+    pub fn from_signature(signature: &Signature) -> Self {
+        // the JSON encoding is what clients send, so it's stable for a given signature,
+        // it can't fail for a signature
+        let encoded = serde_json::to_vec(signature).unwrap_or_default();
+        let hash = Sha256::digest(&encoded);
+        Self::SignatureHash(hash.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    /// the same for every replay of the request, usable to make its effects idempotent
+    pub fn idempotency_key(&self) -> String {
+        match self {
+            Self::Nonce(nonce) => nonce.idempotency_key.clone(),
+            Self::SignatureHash(hash) => format!("unnonced-{hash}"),
+        }
+    }
+}
+
+/// A signed request body extended with a [`MessageNonce`]
+///
+/// `{ "request": <original body>, "nonce": { "timestamp_ms", "idempotency_key" } }`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Nonced<T> {
+    pub request: T,
+    pub nonce: MessageNonce,
+}
+
+/// A signed request body, with or without a [`MessageNonce`]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum MaybeNonced<T> {
+    Nonced(Nonced<T>),
+    /// body signed before nonces existed, only accepted until the un-nonced cutoff
+    Unnonced(T),
+}
+
+impl<T> MaybeNonced<T> {
+    pub fn request(&self) -> &T {
+        match self {
+            Self::Nonced(req) => &req.request,
+            Self::Unnonced(req) => req,
+        }
+    }
+
+    pub fn nonce(&self) -> Option<&MessageNonce> {
+        match self {
+            Self::Nonced(req) => Some(&req.nonce),
+            Self::Unnonced(_) => None,
+        }
+    }
+
+    /// split into the request and the key its replays are detected by,
+    /// `signature` returns the signature of an un-nonced request
+    pub fn into_replay_parts(self, signature: impl FnOnce(&T) -> &Signature) -> (T, ReplayKey) {
+        match self {
+            Self::Nonced(req) => (req.request, ReplayKey::Nonce(req.nonce)),
+            Self::Unnonced(req) => {
+                let key = ReplayKey::from_signature(signature(&req));
+                (req, key)
+            }
+        }
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> MaybeNonced<U> {
+        match self {
+            Self::Nonced(req) => MaybeNonced::Nonced(Nonced {
+                request: f(req.request),
+                nonce: req.nonce,
+            }),
+            Self::Unnonced(req) => MaybeNonced::Unnonced(f(req)),
+        }
+    }
+}
+
+/// The signed message of a nonced request, `args` followed by the nonce
+///
+/// `method_name` must differ from the un-nonced message so a signature
+/// over one can't be replayed as the other
+pub fn nonced_message(
+    method_name: &str,
+    args: impl CandidType,
+    nonce: &MessageNonce,
+) -> Result<Message, SignatureError> {
+    Message::default()
+        .method_name(method_name.into())
+        .args((args, nonce.timestamp_ms, &nonce.idempotency_key))
+        .map_err(|e| SignatureError::InvalidBody(e.to_string()))
+}
+
+/// A request payload signed by `sender`, not verified yet
+pub struct SignedRequest<T> {
+    sender: Principal,
+    signature: Signature,
+    payload: T,
+    timestamp_ms: Option<u64>,
+    max_age_ms: u64,
+    unnonced: bool,
+    unnonced_cutoff_ms: u64,
+}
+
+/// A request payload whose signature has been verified
+#[derive(Debug, Clone)]
+pub struct VerifiedRequest<T> {
+    pub sender: Principal,
+    pub payload: T,
+}
+
+impl<T> SignedRequest<T> {
+    pub fn new(sender: Principal, signature: Signature, payload: T) -> Self {
+        Self {
+            sender,
+            signature,
+            payload,
+            timestamp_ms: None,
+            max_age_ms: DEFAULT_MAX_AGE_MS,
+            unnonced: false,
+            unnonced_cutoff_ms: DEFAULT_UNNONCED_CUTOFF_MS,
+        }
+    }
+
+    /// the signed message carries the time it was signed at (ms since epoch)
+    /// so it can be rejected once it's older than the max age
+    pub fn timestamp(mut self, timestamp_ms: u64) -> Self {
+        self.timestamp_ms = Some(timestamp_ms);
+        self
+    }
+
+    /// the signed message carries `nonce`, rejected once it's older than the max age
+    pub fn nonce(self, nonce: &MessageNonce) -> Self {
+        self.timestamp(nonce.timestamp_ms)
+    }
+
+    /// same as [`Self::nonce`], `None` for requests signed without one,
+    /// these are rejected after the un-nonced cutoff
+    pub fn maybe_nonce(mut self, nonce: Option<&MessageNonce>) -> Self {
+        match nonce {
+            Some(nonce) => self.nonce(nonce),
+            None => {
+                self.unnonced = true;
+                self
+            }
+        }
+    }
+
+    /// requests signed without a nonce are rejected from `cutoff_ms` (ms since epoch),
+    /// defaults to [`DEFAULT_UNNONCED_CUTOFF_MS`]
+    pub fn unnonced_cutoff(mut self, cutoff_ms: u64) -> Self {
+        self.unnonced_cutoff_ms = cutoff_ms;
+        self
+    }
+
+    pub fn max_age(mut self, max_age_ms: u64) -> Self {
+        self.max_age_ms = max_age_ms;
+        self
+    }
+
+    fn check_freshness(&self) -> Result<(), SignatureError> {
+        let now = Date::now().as_millis();
+        if self.unnonced && now >= self.unnonced_cutoff_ms {
+            return Err(SignatureError::Expired);
+        }
+        let Some(timestamp_ms) = self.timestamp_ms else {
+            return Ok(());
+        };
+
+        check_timestamp(timestamp_ms, now, self.max_age_ms)
+    }
+
+    /// verify the signature over the message built by `msg_builder`
+    pub fn verify(
+        self,
+        msg_builder: impl FnOnce(&T) -> Result<Message, SignatureError>,
+    ) -> Result<VerifiedRequest<T>, SignatureError> {
+        self.check_freshness()?;
+
+        let msg = msg_builder(&self.payload)?;
+        self.signature
+            .verify_identity(self.sender, msg)
+            .map_err(|_| SignatureError::InvalidSignature)?;
+
+        Ok(VerifiedRequest {
+            sender: self.sender,
+            payload: self.payload,
+        })
+    }
+
+    /// parse the JSON body of `req`, split it into a signed request and verify it
+    pub async fn from_json_body<B: DeserializeOwned>(
+        req: &mut Request,
+        split: impl FnOnce(B) -> SignedRequest<T>,
+        msg_builder: impl FnOnce(&T) -> Result<Message, SignatureError>,
+    ) -> Result<VerifiedRequest<T>, SignatureError> {
+        let raw = req
+            .text()
+            .await
+            .map_err(|e| SignatureError::InvalidBody(e.to_string()))?;
+        let body: B =
+            serde_json::from_str(&raw).map_err(|e| SignatureError::InvalidBody(e.to_string()))?;
+
+        split(body).verify(msg_builder)
+    }
+}
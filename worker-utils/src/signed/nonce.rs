@@ -0,0 +1,113 @@
+use worker::Date;
+
+use super::{DEFAULT_MAX_AGE_MS, MessageNonce, ReplayKey, SignatureError, check_timestamp};
+use crate::storage::{SafeStorage, StorageMap};
+
+/// stale nonces deleted per consumed nonce
+const PRUNE_BATCH: usize = 32;
+
+fn storage_err(e: worker::Error) -> SignatureError {
+    SignatureError::Storage(e.to_string())
+}
+
+/// Per-user store of consumed [`ReplayKey`]s, kept in durable object storage
+///
+/// Nonces are keyed by `nonce-{timestamp}-{idempotency key}`, so stale ones
+/// are listed first and pruned as new nonces are consumed.
+/// Signatures of un-nonced requests never go stale and are kept,
+/// they stop being accepted at the un-nonced cutoff anyway
+pub struct NonceStore {
+    seen: StorageMap<(u64, String), ()>,
+    // `unnonced-sig-{signature hash}`
+    seen_unnonced: StorageMap<String, ()>,
+    max_age_ms: u64,
+}
+
+impl Default for NonceStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_AGE_MS)
+    }
+}
+
+impl NonceStore {
+    pub fn new(max_age_ms: u64) -> Self {
+        Self {
+            seen: StorageMap::new("nonce-"),
+            seen_unnonced: StorageMap::new("unnonced-sig-"),
+            max_age_ms,
+        }
+    }
+
+    /// consume `key`, rejecting it if it's stale or was already used
+    pub async fn consume(
+        &mut self,
+        storage: &mut SafeStorage,
+        key: &ReplayKey,
+    ) -> Result<(), SignatureError> {
+        match key {
+            ReplayKey::Nonce(nonce) => self.consume_nonce(storage, nonce).await,
+            ReplayKey::SignatureHash(hash) => self.consume_signature(storage, hash).await,
+        }
+    }
+
+    async fn consume_signature(
+        &mut self,
+        storage: &mut SafeStorage,
+        hash: &str,
+    ) -> Result<(), SignatureError> {
+        let key = hash.to_string();
+        if self
+            .seen_unnonced
+            .get(storage, &key)
+            .await
+            .map_err(storage_err)?
+            .is_some()
+        {
+            return Err(SignatureError::Replayed);
+        }
+
+        self.seen_unnonced
+            .insert(storage, key, ())
+            .await
+            .map_err(storage_err)
+    }
+
+    async fn consume_nonce(
+        &mut self,
+        storage: &mut SafeStorage,
+        nonce: &MessageNonce,
+    ) -> Result<(), SignatureError> {
+        let now = Date::now().as_millis();
+        check_timestamp(nonce.timestamp_ms, now, self.max_age_ms)?;
+
+        let key = (nonce.timestamp_ms, nonce.idempotency_key.clone());
+        if self
+            .seen
+            .get(storage, &key)
+            .await
+            .map_err(storage_err)?
+            .is_some()
+        {
+            return Err(SignatureError::Replayed);
+        }
+
+        self.prune(storage, now).await.map_err(storage_err)?;
+        self.seen
+            .insert(storage, key, ())
+            .await
+            .map_err(storage_err)
+    }
+
+    async fn prune(&mut self, storage: &mut SafeStorage, now: u64) -> worker::Result<()> {
+        let cutoff = now.saturating_sub(self.max_age_ms);
+        let oldest = self.seen.page(storage, None, PRUNE_BATCH).await?;
+        for (key, _) in oldest {
+            if key.0 >= cutoff {
+                break;
+            }
+            self.seen.remove(storage, &key).await?;
+        }
+
+        Ok(())
+    }
+}
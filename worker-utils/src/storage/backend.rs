@@ -0,0 +1,166 @@
+use std::ops::Deref;
+
+use enum_dispatch::enum_dispatch;
+use serde_bytes::ByteBuf;
+use worker::{ListOptions, Result, Storage};
+
+use super::{TxnOp, list::RawEntry, list::parse_entry, memory::MemoryStorage};
+
+/// Options for listing storage, mirrors [`worker::ListOptions`]
+#[derive(Debug, Clone, Default)]
+pub struct ListQuery {
+    pub(crate) prefix: Option<String>,
+    pub(crate) start: Option<String>,
+    pub(crate) limit: Option<usize>,
+}
+
+impl ListQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// only list keys starting with `prefix`
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// start listing at `start` (inclusive)
+    pub fn start(mut self, start: impl Into<String>) -> Self {
+        self.start = Some(start.into());
+        self
+    }
+
+    /// maximum number of entries to list
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// Raw key value storage behind [`super::SafeStorage`]
+/// values are MessagePack encoded before reaching the backend
+#[enum_dispatch]
+pub(crate) trait RawStorage {
+    async fn get(&self, key: &str) -> Result<Option<ByteBuf>>;
+
+    /// takes `&self` so reads can write back upgraded payloads
+    async fn put(&self, key: &str, v: ByteBuf) -> Result<()>;
+
+    async fn delete(&mut self, key: &str) -> Result<bool>;
+
+    async fn delete_multiple(&mut self, keys: Vec<String>) -> Result<usize>;
+
+    async fn delete_all(&mut self) -> Result<()>;
+
+    /// entries in key order
+    async fn list(&self, query: ListQuery) -> Result<Vec<RawEntry>>;
+
+    /// apply all ops atomically
+    async fn commit(&mut self, ops: Vec<TxnOp>) -> Result<()>;
+
+    /// scheduled alarm time, in milliseconds since epoch
+    async fn get_alarm(&self) -> Result<Option<i64>>;
+
+    /// schedule the alarm `offset_ms` milliseconds from now
+    async fn set_alarm(&mut self, offset_ms: i64) -> Result<()>;
+
+    async fn delete_alarm(&mut self) -> Result<()>;
+}
+
+/// Durable Object storage
+pub struct DurableStorage(Storage);
+
+impl RawStorage for DurableStorage {
+    async fn get(&self, key: &str) -> Result<Option<ByteBuf>> {
+        let res = self.0.get::<ByteBuf>(key).await;
+        match res {
+            Ok(v) => Ok(Some(v)),
+            Err(worker::Error::JsError(err)) if err.contains("No such value in storage") => {
+                Ok(None)
+            }
+            Err(e) => {
+                log_error!("failed to get from storage. Key {}, err: {e}", key);
+                Err(e)
+            }
+        }
+    }
+
+    async fn put(&self, key: &str, v: ByteBuf) -> Result<()> {
+        let v_js = serde_wasm_bindgen::to_value(&v)?;
+        self.0.put_raw(key, v_js).await
+    }
+
+    async fn delete(&mut self, key: &str) -> Result<bool> {
+        self.0.delete(key).await
+    }
+
+    async fn delete_multiple(&mut self, keys: Vec<String>) -> Result<usize> {
+        self.0.delete_multiple(keys).await
+    }
+
+    async fn delete_all(&mut self) -> Result<()> {
+        self.0.delete_all().await
+    }
+
+    async fn list(&self, query: ListQuery) -> Result<Vec<RawEntry>> {
+        let mut list_options = ListOptions::new();
+        if let Some(prefix) = query.prefix.as_deref() {
+            list_options = list_options.prefix(prefix);
+        }
+        if let Some(start) = query.start.as_deref() {
+            list_options = list_options.start(start);
+        }
+        if let Some(limit) = query.limit {
+            list_options = list_options.limit(limit);
+        }
+
+        let v_idx = self.0.list_with_options(list_options).await?;
+
+        Ok(v_idx.entries().into_iter().map(parse_entry).collect())
+    }
+
+    async fn commit(&mut self, ops: Vec<TxnOp>) -> Result<()> {
+        self.0
+            .transaction(move |txn| async move {
+                for op in ops {
+                    match op {
+                        TxnOp::Put(k, v) => txn.put(&k, v).await?,
+                        TxnOp::Delete(k) => {
+                            txn.delete(&k).await?;
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    async fn get_alarm(&self) -> Result<Option<i64>> {
+        self.0.get_alarm().await
+    }
+
+    async fn set_alarm(&mut self, offset_ms: i64) -> Result<()> {
+        self.0.set_alarm(offset_ms).await
+    }
+
+    async fn delete_alarm(&mut self) -> Result<()> {
+        self.0.delete_alarm().await
+    }
+}
+
+#[enum_dispatch(RawStorage)]
+pub(crate) enum RawStorageImpl {
+    Durable(DurableStorage),
+    Memory(MemoryStorage),
+}
+
+impl From<Storage> for RawStorageImpl {
+    fn from(value: Storage) -> Self {
+        Self::Durable(DurableStorage(value))
+    }
+}
+
+pub(crate) fn owned_keys(keys: Vec<impl Deref<Target = str>>) -> Vec<String> {
+    keys.iter().map(|k| k.to_string()).collect()
+}
@@ -0,0 +1,162 @@
+use std::fmt::Display;
+
+use candid::Principal;
+
+/// Failure to decode a storage key
+#[derive(Debug, Clone)]
+pub struct KeyDecodeError {
+    pub key: String,
+    pub reason: String,
+}
+
+impl KeyDecodeError {
+    pub fn new(key: &str, reason: impl Display) -> Self {
+        Self {
+            key: key.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl Display for KeyDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid key {}: {}", self.key, self.reason)
+    }
+}
+
+impl std::error::Error for KeyDecodeError {}
+
+impl From<KeyDecodeError> for worker::Error {
+    fn from(value: KeyDecodeError) -> Self {
+        worker::Error::RustError(value.to_string())
+    }
+}
+
+/// A typed key for durable object storage
+///
+/// Encoded keys must sort the same way as the decoded values
+/// wherever the order is meaningful (e.g integers are zero padded)
+pub trait StorageKey: Sized {
+    fn encode(&self) -> String;
+
+    fn decode(raw: &str) -> Result<Self, KeyDecodeError>;
+}
+
+impl StorageKey for String {
+    fn encode(&self) -> String {
+        self.clone()
+    }
+
+    fn decode(raw: &str) -> Result<Self, KeyDecodeError> {
+        Ok(raw.to_string())
+    }
+}
+
+impl StorageKey for Principal {
+    fn encode(&self) -> String {
+        self.to_text()
+    }
+
+    fn decode(raw: &str) -> Result<Self, KeyDecodeError> {
+        Principal::from_text(raw).map_err(|e| KeyDecodeError::new(raw, e))
+    }
+}
+
+const U64_WIDTH: usize = 20;
+
+/// zero padded to keep numeric order
+impl StorageKey for u64 {
+    fn encode(&self) -> String {
+        format!("{:0width$}", self, width = U64_WIDTH)
+    }
+
+    fn decode(raw: &str) -> Result<Self, KeyDecodeError> {
+        raw.parse().map_err(|e| KeyDecodeError::new(raw, e))
+    }
+}
+
+/// `u64` encoded without padding
+///
+/// matches keys written with plain `format!` before [`StorageKey`] existed
+/// the encoded keys do not keep numeric order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UnpaddedU64(pub u64);
+
+impl StorageKey for UnpaddedU64 {
+    fn encode(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn decode(raw: &str) -> Result<Self, KeyDecodeError> {
+        raw.parse()
+            .map(Self)
+            .map_err(|e| KeyDecodeError::new(raw, e))
+    }
+}
+
+const SEPARATOR: char = '-';
+
+/// encoded as `{a}-{b}`
+/// `-` may appear inside either part (e.g principals), every split is tried from the right
+impl<A: StorageKey, B: StorageKey> StorageKey for (A, B) {
+    fn encode(&self) -> String {
+        format!("{}{SEPARATOR}{}", self.0.encode(), self.1.encode())
+    }
+
+    fn decode(raw: &str) -> Result<Self, KeyDecodeError> {
+        for (idx, _) in raw.rmatch_indices(SEPARATOR) {
+            let (a_raw, b_raw) = (&raw[..idx], &raw[idx + 1..]);
+            if let (Ok(a), Ok(b)) = (A::decode(a_raw), B::decode(b_raw)) {
+                return Ok((a, b));
+            }
+        }
+
+        Err(KeyDecodeError::new(raw, "no valid split for composite key"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<K: StorageKey + PartialEq + std::fmt::Debug>(key: K) {
+        let decoded = K::decode(&key.encode()).unwrap();
+        assert_eq!(decoded, key);
+    }
+
+    #[test]
+    fn u64_keys_round_trip() {
+        for v in [0, 1, 42, u64::MAX] {
+            round_trip(v);
+            round_trip(UnpaddedU64(v));
+        }
+        assert_eq!(42u64.encode(), "00000000000000000042");
+        assert_eq!(UnpaddedU64(42).encode(), "42");
+    }
+
+    #[test]
+    fn padded_u64_keys_sort_numerically() {
+        let values = [0, 9, 10, 99, 100, 12_345, u64::MAX];
+        let mut encoded: Vec<_> = values.iter().map(StorageKey::encode).collect();
+        encoded.sort();
+
+        let decoded: Vec<u64> = encoded.iter().map(|k| u64::decode(k).unwrap()).collect();
+        assert_eq!(decoded, values);
+    }
+
+    #[test]
+    fn composite_keys_round_trip_with_separator_in_a_part() {
+        round_trip((42u64, "idem-key-1".to_string()));
+        round_trip(("post-id".to_string(), 7u64));
+
+        let principal = Principal::from_text("2vxsx-fae").unwrap();
+        round_trip((principal, UnpaddedU64(3)));
+        round_trip((principal, "a-b".to_string()));
+    }
+
+    #[test]
+    fn composite_key_without_a_valid_split_fails() {
+        assert!(<(u64, u64)>::decode("1_2").is_err());
+        assert!(<(u64, u64)>::decode("a-2").is_err());
+    }
+}
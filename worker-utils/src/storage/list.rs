@@ -0,0 +1,252 @@
+use std::{collections::VecDeque, fmt::Display};
+
+use futures::{Stream, stream};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde_bytes::ByteBuf;
+use worker::{Result, wasm_bindgen::JsValue};
+
+use super::{
+    KeyDecodeError, ListQuery, SafeStorage, VersionedSchema,
+    backend::RawStorage,
+    deser_bbuf,
+    migration::{decode_versioned, encode_versioned},
+};
+
+/// Failure to list a single entry
+#[derive(Debug)]
+pub enum ListEntryError {
+    /// fetching a page from storage failed, no further entries are yielded
+    Storage(String),
+    /// the entry returned by storage is not a `(key, bytes)` pair
+    InvalidEntry { key: Option<String> },
+    /// the value stored at `key` could not be decoded
+    Decode { key: String, reason: String },
+    /// the key could not be decoded into a typed key
+    InvalidKey(KeyDecodeError),
+}
+
+impl ListEntryError {
+    /// key of the bad entry, if known
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Self::Storage(_) => None,
+            Self::InvalidEntry { key } => key.as_deref(),
+            Self::Decode { key, .. } => Some(key),
+            Self::InvalidKey(e) => Some(&e.key),
+        }
+    }
+}
+
+impl Display for ListEntryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Storage(e) => write!(f, "failed to list storage: {e}"),
+            Self::InvalidEntry { key: Some(key) } => write!(f, "invalid entry stored at {key}"),
+            Self::InvalidEntry { key: None } => write!(f, "invalid entry stored"),
+            Self::Decode { key, reason } => write!(f, "failed to decode {key}: {reason}"),
+            Self::InvalidKey(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ListEntryError {}
+
+impl From<ListEntryError> for worker::Error {
+    fn from(value: ListEntryError) -> Self {
+        worker::Error::RustError(value.to_string())
+    }
+}
+
+pub(crate) type RawEntry = std::result::Result<(String, ByteBuf), ListEntryError>;
+
+pub(crate) fn parse_entry(entry: std::result::Result<JsValue, JsValue>) -> RawEntry {
+    let raw_entry = entry.map_err(|_| ListEntryError::InvalidEntry { key: None })?;
+    if let Ok(parsed) = serde_wasm_bindgen::from_value::<(String, ByteBuf)>(raw_entry.clone()) {
+        return Ok(parsed);
+    }
+
+    let key = serde_wasm_bindgen::from_value::<(String, IgnoredAny)>(raw_entry)
+        .ok()
+        .map(|(k, _)| k);
+    log_error!("invalid entry in storage. Key {key:?}");
+
+    Err(ListEntryError::InvalidEntry { key })
+}
+
+/// An async stream over all entries with a given prefix
+pub type PrefixStream<'a, T> =
+    std::pin::Pin<Box<dyn Stream<Item = std::result::Result<(String, T), ListEntryError>> + 'a>>;
+
+struct PageCursor {
+    prefix: String,
+    page_size: usize,
+    // last key seen, the next page starts after this key
+    last_key: Option<String>,
+    // entries without a key yielded since `last_key`
+    keyless_after_last: usize,
+    buffer: VecDeque<RawEntry>,
+    exhausted: bool,
+}
+
+impl SafeStorage {
+    /// raw entries matching `list_query`, fails if listing the storage fails
+    pub(crate) async fn try_list_raw(&self, list_query: ListQuery) -> Result<Vec<RawEntry>> {
+        self.0.list(list_query).await.inspect_err(|e| {
+            log_error!("failed to list storage: {e}");
+        })
+    }
+
+    pub(crate) async fn list_raw(
+        &self,
+        list_query: ListQuery,
+    ) -> impl Iterator<Item = Result<(String, ByteBuf)>> + use<> {
+        let entries = match self.try_list_raw(list_query).await {
+            Ok(entries) => entries
+                .into_iter()
+                .map(|entry| entry.map_err(worker::Error::from))
+                .collect(),
+            Err(e) => vec![Err(e)],
+        };
+
+        entries.into_iter()
+    }
+
+    async fn fetch_page(&self, cursor: &mut PageCursor) -> Result<()> {
+        // `start` is inclusive, so fetch an extra entry to account for the last key
+        let skip_first = cursor.last_key.is_some();
+        let limit = cursor.page_size + skip_first as usize;
+
+        let mut list_query = ListQuery::new().prefix(cursor.prefix.as_str()).limit(limit);
+        if let Some(last_key) = cursor.last_key.as_deref() {
+            list_query = list_query.start(last_key);
+        }
+        let page = self.0.list(list_query).await?;
+        let fetched = page.len();
+
+        // keyless entries right after `last_key` were yielded with the previous page
+        let mut skip_keyless = cursor.keyless_after_last;
+        let mut advanced = false;
+        for (idx, entry) in page.into_iter().enumerate() {
+            let key = match &entry {
+                Ok((key, _)) => Some(key.as_str()),
+                Err(e) => e.key(),
+            };
+            if skip_first && idx == 0 && key == cursor.last_key.as_deref() {
+                continue;
+            }
+            match key {
+                Some(key) => {
+                    cursor.last_key = Some(key.to_string());
+                    cursor.keyless_after_last = 0;
+                    skip_keyless = 0;
+                    advanced = true;
+                }
+                None if skip_keyless > 0 => {
+                    skip_keyless -= 1;
+                    continue;
+                }
+                None => cursor.keyless_after_last += 1,
+            }
+            cursor.buffer.push_back(entry);
+        }
+
+        if fetched < limit {
+            cursor.exhausted = true;
+        } else if !advanced {
+            // a full page without a single key, the cursor can't move past it
+            return Err(worker::Error::RustError(format!(
+                "no readable keys in a page of {fetched} entries"
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn stream_raw(
+        &self,
+        prefix: impl Into<String>,
+        page_size: usize,
+    ) -> impl Stream<Item = RawEntry> + '_ {
+        let cursor = PageCursor {
+            prefix: prefix.into(),
+            page_size: page_size.max(1),
+            last_key: None,
+            keyless_after_last: 0,
+            buffer: VecDeque::new(),
+            exhausted: false,
+        };
+
+        stream::unfold(cursor, move |mut cursor| async move {
+            loop {
+                if let Some(entry) = cursor.buffer.pop_front() {
+                    return Some((entry, cursor));
+                }
+                if cursor.exhausted {
+                    return None;
+                }
+                if let Err(e) = self.fetch_page(&mut cursor).await {
+                    cursor.exhausted = true;
+                    return Some((Err(ListEntryError::Storage(e.to_string())), cursor));
+                }
+            }
+        })
+    }
+
+    /// Lazily stream all entries with `prefix`, fetching `page_size` entries at a time
+    ///
+    /// Entries that can't be read or decoded are yielded as errors
+    /// the caller can skip them and continue with the rest
+    pub fn stream_with_prefix<T: DeserializeOwned + 'static>(
+        &self,
+        prefix: impl Into<String>,
+        page_size: usize,
+    ) -> PrefixStream<'_, T> {
+        use futures::StreamExt;
+
+        let stream = self.stream_raw(prefix, page_size).map(|entry| {
+            let (key, v_raw) = entry?;
+            match deser_bbuf(&key, v_raw) {
+                Ok(v) => Ok((key, v)),
+                Err(e) => Err(ListEntryError::Decode {
+                    key,
+                    reason: e.to_string(),
+                }),
+            }
+        });
+
+        Box::pin(stream)
+    }
+
+    /// Same as [`Self::stream_with_prefix`], for values stored with schema `S`
+    /// upgraded payloads are written back
+    pub fn stream_versioned_with_prefix<S: VersionedSchema + 'static>(
+        &self,
+        prefix: impl Into<String>,
+        page_size: usize,
+    ) -> PrefixStream<'_, S::Value> {
+        use futures::StreamExt;
+
+        let stream = self
+            .stream_raw(prefix, page_size)
+            .then(move |entry| async move {
+                let (key, v_raw) = entry?;
+                let decoded = match decode_versioned::<S>(&key, v_raw) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        return Err(ListEntryError::Decode {
+                            key,
+                            reason: e.to_string(),
+                        });
+                    }
+                };
+                if decoded.upgraded {
+                    self.write_back(&key, encode_versioned::<S>(&decoded.value))
+                        .await;
+                }
+
+                Ok((key, decoded.value))
+            });
+
+        Box::pin(stream)
+    }
+}
@@ -0,0 +1,234 @@
+use std::{collections::BTreeMap, pin::Pin};
+
+use futures::{Stream, StreamExt};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_bytes::ByteBuf;
+use worker::Result;
+
+use super::{
+    KeyDecodeError, ListEntryError, ListQuery, SafeStorage, StorageKey, StorageTransaction,
+    VersionedSchema, decode_plain, encode_plain,
+    migration::{Decoded, decode_versioned, encode_versioned},
+};
+
+/// An async stream over the entries of a [`StorageMap`]
+pub type MapStream<'a, K, V> =
+    Pin<Box<dyn Stream<Item = std::result::Result<(K, V), ListEntryError>> + 'a>>;
+
+/// A typed collection of values stored under `{prefix}{key}`
+///
+/// Point lookups are cached, listing always goes to storage
+pub struct StorageMap<K: StorageKey + Ord + Clone, V: Serialize + DeserializeOwned + Clone> {
+    prefix: String,
+    // `None` marks a key known to be absent
+    cache: BTreeMap<K, Option<V>>,
+    decode: fn(&str, ByteBuf) -> Result<Decoded<V>>,
+    encode: fn(&V) -> Result<ByteBuf>,
+}
+
+impl<K, V> StorageMap<K, V>
+where
+    K: StorageKey + Ord + Clone + 'static,
+    V: Serialize + DeserializeOwned + Clone + 'static,
+{
+    pub fn new(prefix: impl AsRef<str>) -> Self {
+        Self {
+            prefix: prefix.as_ref().to_string(),
+            cache: BTreeMap::new(),
+            decode: decode_plain,
+            encode: encode_plain,
+        }
+    }
+
+    /// A map whose values are stored with schema `S`
+    pub fn new_versioned<S: VersionedSchema<Value = V>>(prefix: impl AsRef<str>) -> Self {
+        Self {
+            prefix: prefix.as_ref().to_string(),
+            cache: BTreeMap::new(),
+            decode: decode_versioned::<S>,
+            encode: encode_versioned::<S>,
+        }
+    }
+
+    /// the full storage key for `key`, including the prefix
+    pub fn storage_key(&self, key: &K) -> String {
+        format!("{}{}", self.prefix, key.encode())
+    }
+
+    /// decode a full storage key, including the prefix
+    pub fn decode_storage_key(&self, raw: &str) -> std::result::Result<K, KeyDecodeError> {
+        let Some(key_raw) = raw.strip_prefix(&self.prefix) else {
+            return Err(KeyDecodeError::new(raw, "missing prefix"));
+        };
+
+        K::decode(key_raw)
+    }
+
+    pub async fn get(&mut self, storage: &SafeStorage, key: &K) -> Result<Option<&V>> {
+        if !self.cache.contains_key(key) {
+            let full_key = self.storage_key(key);
+            let v = match storage.get_bbuf(&full_key).await? {
+                Some(v_raw) => {
+                    let decoded = (self.decode)(&full_key, v_raw)?;
+                    if decoded.upgraded {
+                        storage
+                            .write_back(&full_key, (self.encode)(&decoded.value))
+                            .await;
+                    }
+                    Some(decoded.value)
+                }
+                None => None,
+            };
+            self.cache.insert(key.clone(), v);
+        }
+
+        Ok(self.cache.get(key).and_then(|v| v.as_ref()))
+    }
+
+    pub async fn insert(&mut self, storage: &mut SafeStorage, key: K, v: V) -> Result<()> {
+        let v_raw = (self.encode)(&v)?;
+        storage.put_bbuf(&self.storage_key(&key), v_raw).await?;
+        self.cache.insert(key, Some(v));
+
+        Ok(())
+    }
+
+    pub async fn remove(&mut self, storage: &mut SafeStorage, key: &K) -> Result<bool> {
+        let deleted = storage.delete(self.storage_key(key)).await?;
+        self.cache.insert(key.clone(), None);
+
+        Ok(deleted)
+    }
+
+    /// Stage an insert in a transaction, the key is re-read after the transaction
+    pub fn insert_txn(&mut self, txn: &mut StorageTransaction<'_>, key: &K, v: &V) -> Result<()> {
+        txn.put_bbuf(&self.storage_key(key), (self.encode)(v)?);
+        self.cache.remove(key);
+
+        Ok(())
+    }
+
+    /// Stage a removal in a transaction, the key is re-read after the transaction
+    pub fn remove_txn(&mut self, txn: &mut StorageTransaction<'_>, key: &K) {
+        txn.delete(self.storage_key(key));
+        self.cache.remove(key);
+    }
+
+    /// Stage removal of every entry in the map
+    pub async fn clear_txn(&mut self, txn: &mut StorageTransaction<'_>) -> Result<()> {
+        let mut keys = vec![];
+        let mut entries = Box::pin(txn.storage.stream_raw(self.prefix.clone(), 128));
+        while let Some(entry) = entries.next().await {
+            let key = match &entry {
+                Ok((key, _)) => key.as_str(),
+                Err(ListEntryError::Storage(e)) => {
+                    return Err(worker::Error::RustError(e.clone()));
+                }
+                Err(e) => match e.key() {
+                    Some(key) => key,
+                    None => continue,
+                },
+            };
+            keys.push(key.to_string());
+        }
+        drop(entries);
+
+        for key in keys {
+            txn.delete(key);
+        }
+        self.cache.clear();
+
+        Ok(())
+    }
+
+    /// drop all cached entries, e.g after the storage was cleared
+    pub fn invalidate(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Lazily stream all entries, fetching `page_size` entries at a time
+    /// upgraded payloads are written back
+    pub fn stream<'a>(&self, storage: &'a SafeStorage, page_size: usize) -> MapStream<'a, K, V> {
+        let prefix = self.prefix.clone();
+        let decode = self.decode;
+        let encode = self.encode;
+        let stream = storage
+            .stream_raw(self.prefix.clone(), page_size)
+            .then(move |entry| {
+                let prefix = prefix.clone();
+                async move {
+                    let (full_key, v_raw) = entry?;
+                    let key = full_key
+                        .strip_prefix(&prefix)
+                        .ok_or_else(|| KeyDecodeError::new(&full_key, "missing prefix"))
+                        .and_then(K::decode)
+                        .map_err(ListEntryError::InvalidKey)?;
+                    let decoded = match decode(&full_key, v_raw) {
+                        Ok(decoded) => decoded,
+                        Err(e) => {
+                            return Err(ListEntryError::Decode {
+                                key: full_key,
+                                reason: e.to_string(),
+                            });
+                        }
+                    };
+                    if decoded.upgraded {
+                        storage.write_back(&full_key, encode(&decoded.value)).await;
+                    }
+
+                    Ok((key, decoded.value))
+                }
+            });
+
+        Box::pin(stream)
+    }
+
+    /// Up to `limit` entries in key order, starting at `start` (inclusive)
+    ///
+    /// entries that can't be decoded are logged and skipped, upgraded payloads are written back.
+    /// fails if listing the storage fails instead of returning a short page
+    pub async fn page(
+        &self,
+        storage: &SafeStorage,
+        start: Option<&K>,
+        limit: usize,
+    ) -> Result<Vec<(K, V)>> {
+        let mut list_query = ListQuery::new().prefix(&self.prefix).limit(limit);
+        if let Some(start) = start {
+            list_query = list_query.start(self.storage_key(start));
+        }
+
+        let mut page = vec![];
+        for entry in storage.try_list_raw(list_query).await? {
+            let (full_key, v_raw) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    log_error!("skipping entry: {e}");
+                    continue;
+                }
+            };
+            let key = match self.decode_storage_key(&full_key) {
+                Ok(key) => key,
+                Err(e) => {
+                    log_error!("skipping entry: {e}");
+                    continue;
+                }
+            };
+            let decoded = match (self.decode)(&full_key, v_raw) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    log_error!("skipping entry {full_key}: {e}");
+                    continue;
+                }
+            };
+            if decoded.upgraded {
+                storage
+                    .write_back(&full_key, (self.encode)(&decoded.value))
+                    .await;
+            }
+            page.push((key, decoded.value));
+        }
+
+        Ok(page)
+    }
+}
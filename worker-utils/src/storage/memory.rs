@@ -0,0 +1,203 @@
+use std::{cell::RefCell, collections::BTreeMap, ops::Bound, rc::Rc};
+
+use serde_bytes::ByteBuf;
+use worker::Result;
+
+use super::{
+    TxnOp,
+    backend::{ListQuery, RawStorage},
+    list::RawEntry,
+};
+
+#[derive(Default)]
+struct MemoryStorageInner {
+    entries: BTreeMap<String, ByteBuf>,
+    alarm: Option<i64>,
+}
+
+/// In-memory storage with the same semantics as Durable Object storage
+///
+/// Clones share the same underlying map, so a test can hand a clone to the code
+/// under test and inspect the stored values afterwards
+#[derive(Default, Clone)]
+pub struct MemoryStorage(Rc<RefCell<MemoryStorageInner>>);
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// all stored keys, in order
+    pub fn keys(&self) -> Vec<String> {
+        self.0.borrow().entries.keys().cloned().collect()
+    }
+
+    /// the scheduled alarm, in milliseconds since epoch
+    pub fn alarm(&self) -> Option<i64> {
+        self.0.borrow().alarm
+    }
+
+    /// take the scheduled alarm as if it had fired
+    pub fn take_alarm(&self) -> Option<i64> {
+        self.0.borrow_mut().alarm.take()
+    }
+}
+
+fn now_ms() -> i64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        worker::Date::now().as_millis() as i64
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default()
+    }
+}
+
+impl RawStorage for MemoryStorage {
+    async fn get(&self, key: &str) -> Result<Option<ByteBuf>> {
+        Ok(self.0.borrow().entries.get(key).cloned())
+    }
+
+    async fn put(&self, key: &str, v: ByteBuf) -> Result<()> {
+        self.0.borrow_mut().entries.insert(key.to_string(), v);
+        Ok(())
+    }
+
+    async fn delete(&mut self, key: &str) -> Result<bool> {
+        Ok(self.0.borrow_mut().entries.remove(key).is_some())
+    }
+
+    async fn delete_multiple(&mut self, keys: Vec<String>) -> Result<usize> {
+        let mut inner = self.0.borrow_mut();
+        let deleted = keys
+            .iter()
+            .filter(|k| inner.entries.remove(k.as_str()).is_some())
+            .count();
+
+        Ok(deleted)
+    }
+
+    async fn delete_all(&mut self) -> Result<()> {
+        // like Durable Objects, `delete_all` does not clear the alarm
+        self.0.borrow_mut().entries.clear();
+        Ok(())
+    }
+
+    async fn list(&self, query: ListQuery) -> Result<Vec<RawEntry>> {
+        let inner = self.0.borrow();
+        let prefix = query.prefix.unwrap_or_default();
+        let start = match query.start {
+            Some(start) if start > prefix => start,
+            _ => prefix.clone(),
+        };
+
+        let entries = inner
+            .entries
+            .range::<String, _>((Bound::Included(start), Bound::Unbounded))
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .map(|(k, v)| Ok((k.clone(), v.clone())))
+            .collect();
+
+        Ok(entries)
+    }
+
+    async fn commit(&mut self, ops: Vec<TxnOp>) -> Result<()> {
+        let mut inner = self.0.borrow_mut();
+        for op in ops {
+            match op {
+                TxnOp::Put(k, v) => {
+                    inner.entries.insert(k, v);
+                }
+                TxnOp::Delete(k) => {
+                    inner.entries.remove(&k);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get_alarm(&self) -> Result<Option<i64>> {
+        Ok(self.0.borrow().alarm)
+    }
+
+    async fn set_alarm(&mut self, offset_ms: i64) -> Result<()> {
+        self.0.borrow_mut().alarm = Some(now_ms() + offset_ms);
+        Ok(())
+    }
+
+    async fn delete_alarm(&mut self) -> Result<()> {
+        self.0.borrow_mut().alarm = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn storage_with(keys: &[&str]) -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        for key in keys {
+            block_on(storage.put(key, ByteBuf::from(key.as_bytes().to_vec()))).unwrap();
+        }
+
+        storage
+    }
+
+    fn listed_keys(storage: &MemoryStorage, query: ListQuery) -> Vec<String> {
+        block_on(storage.list(query))
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn list_only_returns_keys_with_the_prefix() {
+        let storage = storage_with(&["a-1", "b-1", "b-2", "b2", "c-1"]);
+
+        let keys = listed_keys(&storage, ListQuery::new().prefix("b-"));
+        assert_eq!(keys, ["b-1", "b-2"]);
+    }
+
+    #[test]
+    fn list_starts_at_start_inclusive() {
+        let storage = storage_with(&["b-1", "b-2", "b-3", "c-1"]);
+
+        let keys = listed_keys(&storage, ListQuery::new().prefix("b-").start("b-2"));
+        assert_eq!(keys, ["b-2", "b-3"]);
+
+        // a start before the prefix lists from the prefix
+        let keys = listed_keys(&storage, ListQuery::new().prefix("b-").start("a"));
+        assert_eq!(keys, ["b-1", "b-2", "b-3"]);
+
+        // a start past the prefix lists nothing
+        let keys = listed_keys(&storage, ListQuery::new().prefix("b-").start("c"));
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn list_stops_at_limit() {
+        let storage = storage_with(&["b-1", "b-2", "b-3", "b-4"]);
+
+        let keys = listed_keys(&storage, ListQuery::new().prefix("b-").limit(2));
+        assert_eq!(keys, ["b-1", "b-2"]);
+
+        let keys = listed_keys(
+            &storage,
+            ListQuery::new().prefix("b-").start("b-3").limit(2),
+        );
+        assert_eq!(keys, ["b-3", "b-4"]);
+
+        let keys = listed_keys(&storage, ListQuery::new().prefix("b-").limit(10));
+        assert_eq!(keys.len(), 4);
+    }
+}
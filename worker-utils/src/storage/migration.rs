@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_bytes::ByteBuf;
+use worker::Result;
+
+/// Upgrades a MessagePack payload by one schema version
+pub type Migration = fn(&[u8]) -> Result<Vec<u8>>;
+
+/// Schema of a value stored in durable object storage
+///
+/// Implemented on the stored type itself, or on a marker type
+/// when the stored type is defined in another crate
+pub trait VersionedSchema {
+    type Value: Serialize + DeserializeOwned;
+
+    /// Upgrade functions in order, `MIGRATIONS[i]` upgrades a version `i` payload to `i + 1`
+    /// Payloads stored before the type was versioned are treated as version 0
+    const MIGRATIONS: &'static [Migration];
+
+    const VERSION: u32 = Self::MIGRATIONS.len() as u32;
+}
+
+/// Generic [`Migration`] decoding the old layout and converting it into the new one
+/// e.g `migrate::<GameInfoV0, GameInfoV1>`
+pub fn migrate<Old: DeserializeOwned, New: Serialize + From<Old>>(raw: &[u8]) -> Result<Vec<u8>> {
+    let old: Old =
+        rmp_serde::from_slice(raw).map_err(|e| worker::Error::RustError(e.to_string()))?;
+    rmp_serde::to_vec(&New::from(old)).map_err(|e| worker::Error::RustError(e.to_string()))
+}
+
+const ENVELOPE_TAG: &str = "__yral_v";
+
+#[derive(Serialize, Deserialize)]
+struct Envelope(String, u32, ByteBuf);
+
+pub(crate) struct Decoded<T> {
+    pub value: T,
+    /// the payload was stored with an older schema and should be written back
+    pub upgraded: bool,
+}
+
+/// Version 0 payloads are stored without an envelope, in the same layout as unversioned values
+///
+/// This keeps them readable by builds that predate [`VersionedSchema`], so a rollback is safe
+/// until a schema gains its first migration. Values written after that are wrapped and can
+/// only be read by builds that know the migration, deploy it as a one-way change
+pub(crate) fn encode_versioned<S: VersionedSchema>(v: &S::Value) -> Result<ByteBuf> {
+    let data = rmp_serde::to_vec(v).map_err(|e| worker::Error::RustError(e.to_string()))?;
+    if S::VERSION == 0 {
+        return Ok(ByteBuf::from(data));
+    }
+    let envelope = Envelope(ENVELOPE_TAG.into(), S::VERSION, ByteBuf::from(data));
+    let raw = rmp_serde::to_vec(&envelope).map_err(|e| worker::Error::RustError(e.to_string()))?;
+
+    Ok(ByteBuf::from(raw))
+}
+
+pub(crate) fn decode_versioned<S: VersionedSchema>(
+    key: &str,
+    raw: ByteBuf,
+) -> Result<Decoded<S::Value>> {
+    let (version, wrapped, mut data) = match rmp_serde::from_slice::<Envelope>(&raw) {
+        Ok(Envelope(tag, version, data)) if tag == ENVELOPE_TAG => (version, true, data.into_vec()),
+        // unversioned legacy payload
+        _ => (0, false, raw.into_vec()),
+    };
+
+    if version > S::VERSION {
+        return Err(worker::Error::RustError(format!(
+            "key {key} has schema version {version}, newer than known version {}",
+            S::VERSION
+        )));
+    }
+
+    for (idx, migration) in S::MIGRATIONS.iter().enumerate().skip(version as usize) {
+        log_info!(
+            "migrating key {key} from schema version {idx} to {}",
+            idx + 1
+        );
+        data = migration(&data)?;
+    }
+
+    let value = super::deser_bbuf(key, ByteBuf::from(data))?;
+
+    Ok(Decoded {
+        value,
+        // also rewrites version 0 payloads that were wrapped in an envelope
+        upgraded: version != S::VERSION || wrapped != (S::VERSION > 0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct ScoreV0 {
+        points: u64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct ScoreV1 {
+        points: u64,
+        bonus: u64,
+    }
+
+    impl From<ScoreV0> for ScoreV1 {
+        fn from(v: ScoreV0) -> Self {
+            Self {
+                points: v.points,
+                bonus: 0,
+            }
+        }
+    }
+
+    struct ScoreSchemaV0;
+
+    impl VersionedSchema for ScoreSchemaV0 {
+        type Value = ScoreV0;
+        const MIGRATIONS: &'static [Migration] = &[];
+    }
+
+    struct ScoreSchemaV1;
+
+    impl VersionedSchema for ScoreSchemaV1 {
+        type Value = ScoreV1;
+        const MIGRATIONS: &'static [Migration] = &[migrate::<ScoreV0, ScoreV1>];
+    }
+
+    /// payload written before the type was versioned
+    fn legacy_payload() -> ByteBuf {
+        ByteBuf::from(rmp_serde::to_vec(&ScoreV0 { points: 5 }).unwrap())
+    }
+
+    #[test]
+    fn v0_payload_without_envelope_is_read_as_is() {
+        let decoded = decode_versioned::<ScoreSchemaV0>("score", legacy_payload()).unwrap();
+
+        assert_eq!(decoded.value, ScoreV0 { points: 5 });
+        assert!(!decoded.upgraded);
+    }
+
+    #[test]
+    fn v0_payload_without_envelope_is_migrated() {
+        let decoded = decode_versioned::<ScoreSchemaV1>("score", legacy_payload()).unwrap();
+
+        assert_eq!(
+            decoded.value,
+            ScoreV1 {
+                points: 5,
+                bonus: 0
+            }
+        );
+        assert!(decoded.upgraded);
+    }
+
+    #[test]
+    fn version_0_values_are_written_without_envelope() {
+        let encoded = encode_versioned::<ScoreSchemaV0>(&ScoreV0 { points: 5 }).unwrap();
+
+        assert_eq!(encoded, legacy_payload());
+    }
+
+    #[test]
+    fn current_version_round_trips_without_upgrade() {
+        let value = ScoreV1 {
+            points: 5,
+            bonus: 2,
+        };
+        let encoded = encode_versioned::<ScoreSchemaV1>(&value).unwrap();
+        let decoded = decode_versioned::<ScoreSchemaV1>("score", encoded).unwrap();
+
+        assert_eq!(decoded.value, value);
+        assert!(!decoded.upgraded);
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let encoded = encode_versioned::<ScoreSchemaV1>(&ScoreV1 {
+            points: 5,
+            bonus: 2,
+        })
+        .unwrap();
+
+        assert!(decode_versioned::<ScoreSchemaV0>("score", encoded).is_err());
+    }
+}
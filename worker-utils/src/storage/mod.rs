@@ -0,0 +1,475 @@
+/// `console_error!` inside workerd, stderr on native targets
+/// so the in-memory backend can be used in plain `cargo test`
+macro_rules! log_error {
+    ($($t:tt)*) => {{
+        #[cfg(target_arch = "wasm32")]
+        worker::console_error!($($t)*);
+        #[cfg(not(target_arch = "wasm32"))]
+        eprintln!($($t)*);
+    }};
+}
+
+/// `console_log!` inside workerd, stdout on native targets
+macro_rules! log_info {
+    ($($t:tt)*) => {{
+        #[cfg(target_arch = "wasm32")]
+        worker::console_log!($($t)*);
+        #[cfg(not(target_arch = "wasm32"))]
+        println!($($t)*);
+    }};
+}
+
+mod backend;
+mod key;
+mod list;
+mod map;
+mod memory;
+mod migration;
+
+use std::{fmt::Debug, ops::Deref, rc::Rc};
+
+use backend::{RawStorage, RawStorageImpl, owned_keys};
+use migration::{Decoded, decode_versioned, encode_versioned};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_bytes::ByteBuf;
+use worker::{Result, State, Storage};
+
+pub use backend::ListQuery;
+pub use key::{KeyDecodeError, StorageKey, UnpaddedU64};
+pub use list::{ListEntryError, PrefixStream};
+pub use map::{MapStream, StorageMap};
+pub use memory::MemoryStorage;
+pub use migration::{Migration, VersionedSchema, migrate};
+
+pub struct SafeStorage(RawStorageImpl);
+
+impl From<Storage> for SafeStorage {
+    fn from(value: Storage) -> Self {
+        Self(value.into())
+    }
+}
+
+impl From<MemoryStorage> for SafeStorage {
+    fn from(value: MemoryStorage) -> Self {
+        Self(RawStorageImpl::Memory(value))
+    }
+}
+
+/// Where durable object logic gets its storage from
+///
+/// [`State`] inside workerd, [`MemoryStorage`] in native tests
+pub trait StorageBackend {
+    fn storage(&self) -> SafeStorage;
+}
+
+impl StorageBackend for State {
+    fn storage(&self) -> SafeStorage {
+        State::storage(self).into()
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn storage(&self) -> SafeStorage {
+        self.clone().into()
+    }
+}
+
+/// lets a durable object share its [`State`] with the logic it owns
+impl<T: StorageBackend> StorageBackend for Rc<T> {
+    fn storage(&self) -> SafeStorage {
+        T::storage(self)
+    }
+}
+
+fn deser_bbuf<T: DeserializeOwned>(key: &str, buf: ByteBuf) -> Result<T> {
+    let res = rmp_serde::from_slice(&buf);
+    match res {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            log_error!("failed to get from storage. Key {}, err: {e}", key);
+            Err(worker::Error::RustError(e.to_string()))
+        }
+    }
+}
+
+fn ser_bbuf(v: &impl Serialize) -> Result<ByteBuf> {
+    let v_ser = rmp_serde::to_vec(v).map_err(|e| worker::Error::RustError(e.to_string()))?;
+    Ok(ByteBuf::from(v_ser))
+}
+
+fn decode_plain<T: DeserializeOwned>(key: &str, buf: ByteBuf) -> Result<Decoded<T>> {
+    Ok(Decoded {
+        value: deser_bbuf(key, buf)?,
+        upgraded: false,
+    })
+}
+
+fn encode_plain<T: Serialize>(v: &T) -> Result<ByteBuf> {
+    ser_bbuf(v)
+}
+
+impl SafeStorage {
+    async fn put_bbuf(&mut self, key: &str, v_raw: ByteBuf) -> Result<()> {
+        self.0.put(key, v_raw).await
+    }
+
+    async fn get_bbuf(&self, key: &str) -> Result<Option<ByteBuf>> {
+        self.0.get(key).await
+    }
+
+    /// persist a payload that was upgraded on read
+    ///
+    /// failures are only logged, the payload is upgraded again on the next read
+    pub(crate) async fn write_back(&self, key: &str, v_raw: Result<ByteBuf>) {
+        let res = match v_raw {
+            Ok(v_raw) => self.0.put(key, v_raw).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            log_error!("failed to write back upgraded value. Key {key}, err: {e}");
+        }
+    }
+
+    pub async fn put(&mut self, key: impl AsRef<str>, v: &impl Serialize) -> worker::Result<()> {
+        self.put_bbuf(key.as_ref(), ser_bbuf(v)?).await
+    }
+
+    /// get an unversioned value, values with a [`VersionedSchema`]
+    /// must be read with [`Self::get_versioned`] to be upgraded
+    pub async fn get<T: DeserializeOwned>(
+        &self,
+        key: impl AsRef<str>,
+    ) -> worker::Result<Option<T>> {
+        let key = key.as_ref();
+        let Some(v_raw) = self.get_bbuf(key).await? else {
+            return Ok(None);
+        };
+
+        deser_bbuf(key, v_raw).map(Some)
+    }
+
+    pub async fn put_versioned<S: VersionedSchema>(
+        &mut self,
+        key: impl AsRef<str>,
+        v: &S::Value,
+    ) -> Result<()> {
+        self.put_bbuf(key.as_ref(), encode_versioned::<S>(v)?).await
+    }
+
+    /// get a versioned value, payloads stored with an older schema
+    /// are upgraded and written back
+    pub async fn get_versioned<S: VersionedSchema>(
+        &self,
+        key: impl AsRef<str>,
+    ) -> Result<Option<S::Value>> {
+        let key = key.as_ref();
+        let Some(v_raw) = self.get_bbuf(key).await? else {
+            return Ok(None);
+        };
+
+        let decoded = decode_versioned::<S>(key, v_raw)?;
+        if decoded.upgraded {
+            self.write_back(key, encode_versioned::<S>(&decoded.value))
+                .await;
+        }
+
+        Ok(Some(decoded.value))
+    }
+
+    pub async fn list_with_prefix<T: DeserializeOwned>(
+        &self,
+        prefix: impl AsRef<str>,
+    ) -> impl Iterator<Item = worker::Result<(String, T)>> {
+        self.list_with_options(ListQuery::new().prefix(prefix.as_ref()))
+            .await
+    }
+
+    pub async fn list_with_options<T: DeserializeOwned>(
+        &self,
+        list_query: ListQuery,
+    ) -> impl Iterator<Item = worker::Result<(String, T)>> + use<T> {
+        self.list_raw(list_query).await.map(|entry| {
+            let (key, v_raw) = entry?;
+            let v: T = deser_bbuf(&key, v_raw)?;
+            Ok((key, v))
+        })
+    }
+
+    /// list versioned values, upgraded payloads are written back
+    pub async fn list_versioned_with_options<S: VersionedSchema>(
+        &self,
+        list_query: ListQuery,
+    ) -> impl Iterator<Item = worker::Result<(String, S::Value)>> + use<S> {
+        let mut values = vec![];
+        for entry in self.list_raw(list_query).await {
+            let decoded = entry.and_then(|(key, v_raw)| {
+                let decoded = decode_versioned::<S>(&key, v_raw)?;
+                Ok((key, decoded))
+            });
+            let (key, decoded) = match decoded {
+                Ok(v) => v,
+                Err(e) => {
+                    values.push(Err(e));
+                    continue;
+                }
+            };
+            if decoded.upgraded {
+                self.write_back(&key, encode_versioned::<S>(&decoded.value))
+                    .await;
+            }
+            values.push(Ok((key, decoded.value)));
+        }
+
+        values.into_iter()
+    }
+
+    pub async fn delete(&mut self, key: impl AsRef<str>) -> Result<bool> {
+        self.0.delete(key.as_ref()).await
+    }
+
+    pub async fn delete_multiple(&mut self, keys: Vec<impl Deref<Target = str>>) -> Result<usize> {
+        self.0.delete_multiple(owned_keys(keys)).await
+    }
+
+    pub async fn delete_all(&mut self) -> Result<()> {
+        self.0.delete_all().await
+    }
+
+    /// scheduled alarm time, in milliseconds since epoch
+    pub async fn get_alarm(&self) -> Result<Option<i64>> {
+        self.0.get_alarm().await
+    }
+
+    /// schedule the alarm `offset_ms` milliseconds from now
+    pub async fn set_alarm(&mut self, offset_ms: i64) -> Result<()> {
+        self.0.set_alarm(offset_ms).await
+    }
+
+    pub async fn delete_alarm(&mut self) -> Result<()> {
+        self.0.delete_alarm().await
+    }
+
+    /// Start a transaction, writes are staged in memory
+    /// and only applied on [`StorageTransaction::commit`]
+    pub fn transaction(&mut self) -> StorageTransaction<'_> {
+        StorageTransaction {
+            storage: self,
+            ops: vec![],
+        }
+    }
+}
+
+pub(crate) enum TxnOp {
+    Put(String, ByteBuf),
+    Delete(String),
+}
+
+impl TxnOp {
+    fn key(&self) -> &str {
+        match self {
+            Self::Put(k, _) | Self::Delete(k) => k,
+        }
+    }
+}
+
+/// A batch of puts and deletes that is committed atomically
+/// dropping the transaction without committing discards all staged writes
+pub struct StorageTransaction<'a> {
+    storage: &'a mut SafeStorage,
+    ops: Vec<TxnOp>,
+}
+
+impl StorageTransaction<'_> {
+    async fn get_bbuf(&self, key: &str) -> Result<Option<ByteBuf>> {
+        let staged = self.ops.iter().rev().find(|op| op.key() == key);
+        match staged {
+            Some(TxnOp::Put(_, v)) => Ok(Some(v.clone())),
+            Some(TxnOp::Delete(_)) => Ok(None),
+            None => self.storage.get_bbuf(key).await,
+        }
+    }
+
+    fn put_bbuf(&mut self, key: &str, v_raw: ByteBuf) {
+        self.ops.push(TxnOp::Put(key.to_string(), v_raw));
+    }
+
+    /// read a value, taking writes staged in this transaction into account
+    pub async fn get<T: DeserializeOwned>(&self, key: impl AsRef<str>) -> Result<Option<T>> {
+        let key = key.as_ref();
+        let Some(v_raw) = self.get_bbuf(key).await? else {
+            return Ok(None);
+        };
+
+        deser_bbuf(key, v_raw).map(Some)
+    }
+
+    pub fn put(&mut self, key: impl AsRef<str>, v: &impl Serialize) -> Result<()> {
+        self.put_bbuf(key.as_ref(), ser_bbuf(v)?);
+
+        Ok(())
+    }
+
+    /// read a versioned value, upgraded payloads are staged to be written back
+    pub async fn get_versioned<S: VersionedSchema>(
+        &mut self,
+        key: impl AsRef<str>,
+    ) -> Result<Option<S::Value>> {
+        let key = key.as_ref();
+        let Some(v_raw) = self.get_bbuf(key).await? else {
+            return Ok(None);
+        };
+
+        let decoded = decode_versioned::<S>(key, v_raw)?;
+        if decoded.upgraded {
+            self.put_versioned::<S>(key, &decoded.value)?;
+        }
+
+        Ok(Some(decoded.value))
+    }
+
+    pub fn put_versioned<S: VersionedSchema>(
+        &mut self,
+        key: impl AsRef<str>,
+        v: &S::Value,
+    ) -> Result<()> {
+        self.put_bbuf(key.as_ref(), encode_versioned::<S>(v)?);
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: impl AsRef<str>) {
+        self.ops.push(TxnOp::Delete(key.as_ref().to_string()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// apply all staged writes in a single storage transaction
+    /// either every write is applied or none of them are
+    pub async fn commit(self) -> Result<()> {
+        if self.ops.is_empty() {
+            return Ok(());
+        }
+
+        self.storage.0.commit(self.ops).await
+    }
+}
+
+pub struct StorageCell<T: Serialize + DeserializeOwned + Clone + Debug + 'static> {
+    key: String,
+    hot_cache: Option<T>,
+    initial_value: Box<dyn Fn() -> T>,
+    decode: fn(&str, ByteBuf) -> Result<Decoded<T>>,
+    encode: fn(&T) -> Result<ByteBuf>,
+}
+
+impl<T: Serialize + DeserializeOwned + Clone + Debug + 'static> StorageCell<T> {
+    pub fn new(key: impl AsRef<str>, initial_value: fn() -> T) -> Self {
+        Self {
+            key: key.as_ref().to_string(),
+            hot_cache: None,
+            initial_value: Box::new(initial_value),
+            decode: decode_plain,
+            encode: encode_plain,
+        }
+    }
+
+    /// A cell whose value is stored with schema `S`
+    /// payloads with an older schema are upgraded on read
+    pub fn new_versioned<S: VersionedSchema<Value = T>>(
+        key: impl AsRef<str>,
+        initial_value: fn() -> T,
+    ) -> Self {
+        Self {
+            key: key.as_ref().to_string(),
+            hot_cache: None,
+            initial_value: Box::new(initial_value),
+            decode: decode_versioned::<S>,
+            encode: encode_versioned::<S>,
+        }
+    }
+
+    /// replace the value used when nothing is stored yet
+    /// for initial values that are only known at runtime
+    pub fn set_initial_value(&mut self, initial_value: impl Fn() -> T + 'static) {
+        self.initial_value = Box::new(initial_value);
+    }
+
+    async fn load(&self, storage: &SafeStorage) -> Result<Decoded<T>> {
+        let Some(v_raw) = storage.get_bbuf(&self.key).await? else {
+            return Ok(Decoded {
+                value: (self.initial_value)(),
+                upgraded: false,
+            });
+        };
+
+        (self.decode)(&self.key, v_raw)
+    }
+
+    pub async fn set(&mut self, storage: &mut SafeStorage, v: T) -> worker::Result<()> {
+        let v_raw = (self.encode)(&v)?;
+        self.hot_cache = Some(v);
+        storage.put_bbuf(&self.key, v_raw).await
+    }
+
+    pub async fn update(
+        &mut self,
+        storage: &mut SafeStorage,
+        updater: impl FnOnce(&mut T),
+    ) -> worker::Result<()> {
+        let mutated_val = if let Some(v) = self.hot_cache.as_mut() {
+            v
+        } else {
+            let stored_val = self.load(storage).await?.value;
+            self.hot_cache = Some(stored_val);
+            self.hot_cache.as_mut().unwrap()
+        };
+        updater(mutated_val);
+
+        let v_raw = (self.encode)(mutated_val)?;
+        storage.put_bbuf(&self.key, v_raw).await?;
+
+        Ok(())
+    }
+
+    /// Stage an update in a transaction
+    ///
+    /// The hot cache is dropped and refilled on the next read,
+    /// so the cell stays consistent whether or not the transaction is committed
+    pub async fn update_txn(
+        &mut self,
+        txn: &mut StorageTransaction<'_>,
+        updater: impl FnOnce(&mut T),
+    ) -> worker::Result<()> {
+        let mut val = match self.hot_cache.take() {
+            Some(v) => v,
+            None => match txn.get_bbuf(&self.key).await? {
+                Some(v_raw) => (self.decode)(&self.key, v_raw)?.value,
+                None => (self.initial_value)(),
+            },
+        };
+        updater(&mut val);
+
+        txn.put_bbuf(&self.key, (self.encode)(&val)?);
+
+        Ok(())
+    }
+
+    /// Read the value, payloads with an older schema are upgraded and written back
+    pub async fn read(&mut self, storage: &SafeStorage) -> Result<&T> {
+        if self.hot_cache.is_some() {
+            return Ok(self.hot_cache.as_ref().unwrap());
+        }
+
+        let decoded = self.load(storage).await?;
+        if decoded.upgraded {
+            storage
+                .write_back(&self.key, (self.encode)(&decoded.value))
+                .await;
+        }
+        self.hot_cache = Some(decoded.value);
+
+        Ok(self.hot_cache.as_ref().unwrap())
+    }
+}
@@ -1,9 +1,10 @@
 use std::collections::HashSet;
 
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use jsonwebtoken::{Algorithm, Validation};
 use serde::Deserialize;
 use serde_json::json;
 use worker::*;
+use worker_utils::jwt::JwtKeySet;
 
 mod utils;
 
@@ -65,10 +66,9 @@ struct TokenClaims {
     pub company: String,
 }
 
-fn verify_jwt_token(
-    req: &Request,
-    ctx: &RouteContext<()>,
-) -> std::result::Result<(), (String, u16)> {
+/// `PUBLIC_KEY` verifies tokens without a `kid`, the optional
+/// `JWT_PUBLIC_KEYS` secret (JWKS JSON or PEM) adds keys during a rotation
+fn jwt_keys(ctx: &RouteContext<()>) -> std::result::Result<JwtKeySet, (String, u16)> {
     let public_key = match ctx.secret("PUBLIC_KEY") {
         Err(err) => {
             return Err((format!("unable to fetch public key: {}", err), 500));
@@ -81,11 +81,27 @@ fn verify_jwt_token(
         public_key
     );
 
-    let public_key = match DecodingKey::from_ed_pem(public_key.as_bytes()) {
+    let keys = match JwtKeySet::from_pem(&public_key) {
         Err(err) => return Err((format!("unable to parse public key: {}", err), 500)),
-        Ok(key) => key,
+        Ok(keys) => keys,
     };
 
+    let Ok(extra) = ctx.secret("JWT_PUBLIC_KEYS") else {
+        return Ok(keys);
+    };
+
+    match JwtKeySet::from_secret(&extra.to_string()) {
+        Err(err) => Err((format!("unable to parse public keys: {}", err), 500)),
+        Ok(extra) => Ok(keys.merge(extra)),
+    }
+}
+
+fn verify_jwt_token(
+    req: &Request,
+    ctx: &RouteContext<()>,
+) -> std::result::Result<(), (String, u16)> {
+    let public_keys = jwt_keys(ctx)?;
+
     let mut validation = Validation::new(Algorithm::EdDSA);
     validation.required_spec_claims = HashSet::new();
     validation.validate_exp = false;
@@ -96,7 +112,7 @@ fn verify_jwt_token(
         Ok(Some(key)) => key.replace("Bearer ", ""),
     };
 
-    let req_token = match decode::<TokenClaims>(&req_token, &public_key, &validation) {
+    let req_token = match public_keys.decode::<TokenClaims>(&req_token, &validation) {
         Err(err) => return Err((format!("failed to decode token: {}", err), 401)),
         Ok(req_token) => req_token,
     };
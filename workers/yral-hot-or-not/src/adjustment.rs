@@ -0,0 +1,99 @@
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use worker_utils::storage::{Migration, VersionedSchema};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AdjustmentReason {
+    Airdrop,
+    Compensation,
+    Correction,
+    FraudReversal,
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AirdropReq {
+    pub amount: u128,
+    pub idempotency_key: String,
+}
+
+/// `delta` is credited if positive and debited if negative
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BalanceAdjustmentReq {
+    pub delta: i128,
+    pub reason: AdjustmentReason,
+    #[serde(default)]
+    pub note: Option<String>,
+    pub idempotency_key: String,
+}
+
+/// Sent to the user's durable object along with the admin that made the request
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AdminAdjustmentReq {
+    /// `sub` of the admin JWT
+    pub admin: String,
+    pub adjustment: BalanceAdjustmentReq,
+}
+
+/// An applied adjustment, keyed by `(created_at_ms, idempotency_key)`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BalanceAdjustment {
+    pub delta: i128,
+    pub reason: AdjustmentReason,
+    pub note: Option<String>,
+    pub admin: String,
+    pub balance_after: BigUint,
+}
+
+impl VersionedSchema for BalanceAdjustment {
+    type Value = Self;
+
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+impl BalanceAdjustment {
+    /// whether a retry with the same idempotency key asks for the same adjustment
+    pub fn matches(&self, req: &BalanceAdjustmentReq) -> bool {
+        self.delta == req.delta && self.reason == req.reason && self.note == req.note
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AdjustmentRes {
+    pub idempotency_key: String,
+    /// ms since epoch
+    pub timestamp_ms: u64,
+    pub delta: i128,
+    pub reason: AdjustmentReason,
+    pub note: Option<String>,
+    pub admin: String,
+    pub balance_after: BigUint,
+}
+
+impl AdjustmentRes {
+    pub fn new(idempotency_key: String, timestamp_ms: u64, adjustment: BalanceAdjustment) -> Self {
+        Self {
+            idempotency_key,
+            timestamp_ms,
+            delta: adjustment.delta,
+            reason: adjustment.reason,
+            note: adjustment.note,
+            admin: adjustment.admin,
+            balance_after: adjustment.balance_after,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PaginatedAdjustmentsReq {
+    pub page_size: usize,
+    pub cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PaginatedAdjustmentsRes {
+    /// oldest first
+    pub adjustments: Vec<AdjustmentRes>,
+    pub next: Option<String>,
+}
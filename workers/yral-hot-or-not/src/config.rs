@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+use worker::{console_error, Date, Env, Result};
+
+use crate::consts::{
+    DEFAULT_CREATOR_CUT_PERCENT, DEFAULT_ONBOARDING_REWARD_SATS, DEFAULT_POOLED_WINDOW_MS,
+    DEFAULT_WIN_MULTIPLIER_PERCENT, MAXIMUM_CKBTC_TREASURY_PER_DAY_PER_USER,
+    MAXIMUM_POOLED_WINDOW_MS, MAXIMUM_VOTE_AMOUNT_SATS,
+};
+
+/// optional KV namespace holding the config document under [`CONFIG_KV_KEY`]
+const CONFIG_KV_BINDING: &str = "HON_GAME_CONFIG";
+const CONFIG_KV_KEY: &str = "config";
+/// wrangler var holding the config document, used if the KV namespace is not bound or empty
+const CONFIG_VAR: &str = "HON_GAME_CONFIG";
+/// how long the config is cached in the durable object
+const CONFIG_CACHE_TTL_MS: u64 = 60 * 1000;
+
+/// Game economics, fields missing from the config document use the defaults in `consts`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct HonGameConfig {
+    pub onboarding_reward_sats: u64,
+    pub max_vote_amount_sats: u128,
+    pub max_ckbtc_treasury_per_day_per_user: u64,
+    /// winnings as a percentage of the vote amount
+    pub win_multiplier_percent: u32,
+    /// creator reward as a percentage of the vote amount
+    pub creator_cut_percent: u32,
+    /// votes through `/vote_v2` are pooled per post and settled together once this window closes,
+    /// 0 settles every vote instantly. `/vote` always settles instantly
+    pub pooled_window_ms: u64,
+}
+
+impl Default for HonGameConfig {
+    fn default() -> Self {
+        Self {
+            onboarding_reward_sats: DEFAULT_ONBOARDING_REWARD_SATS,
+            max_vote_amount_sats: MAXIMUM_VOTE_AMOUNT_SATS,
+            max_ckbtc_treasury_per_day_per_user: MAXIMUM_CKBTC_TREASURY_PER_DAY_PER_USER as u64,
+            win_multiplier_percent: DEFAULT_WIN_MULTIPLIER_PERCENT,
+            creator_cut_percent: DEFAULT_CREATOR_CUT_PERCENT,
+            pooled_window_ms: DEFAULT_POOLED_WINDOW_MS,
+        }
+    }
+}
+
+impl HonGameConfig {
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.max_vote_amount_sats == 0 {
+            return Err("max_vote_amount_sats must be positive".into());
+        }
+        if !(1..=1000).contains(&self.win_multiplier_percent) {
+            return Err("win_multiplier_percent must be between 1 and 1000".into());
+        }
+        if self.creator_cut_percent > 100 {
+            return Err("creator_cut_percent must be at most 100".into());
+        }
+        if self.pooled_window_ms > MAXIMUM_POOLED_WINDOW_MS {
+            return Err(format!(
+                "pooled_window_ms must be at most {MAXIMUM_POOLED_WINDOW_MS}"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// A config that replaces the current one from `effective_at_ms` (ms since epoch)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScheduledHonGameConfig {
+    pub effective_at_ms: u64,
+    pub config: HonGameConfig,
+}
+
+/// The config document
+///
+/// `{ "current": { ... }, "scheduled": [{ "effective_at_ms": ..., "config": { ... } }] }`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct HonGameConfigSchedule {
+    pub current: HonGameConfig,
+    pub scheduled: Vec<ScheduledHonGameConfig>,
+}
+
+impl HonGameConfigSchedule {
+    /// invalid configs are logged and replaced with defaults or dropped
+    fn validated(mut self) -> Self {
+        if let Err(e) = self.current.validate() {
+            console_error!("invalid hot or not config, using defaults: {e}");
+            self.current = HonGameConfig::default();
+        }
+        self.scheduled.retain(|scheduled| {
+            let res = scheduled.config.validate();
+            if let Err(e) = &res {
+                console_error!(
+                    "dropping invalid hot or not config scheduled at {}: {e}",
+                    scheduled.effective_at_ms
+                );
+            }
+            res.is_ok()
+        });
+
+        self
+    }
+
+    /// config in effect at `now_ms`
+    pub fn active(&self, now_ms: u64) -> &HonGameConfig {
+        self.scheduled
+            .iter()
+            .filter(|scheduled| scheduled.effective_at_ms <= now_ms)
+            .max_by_key(|scheduled| scheduled.effective_at_ms)
+            .map(|scheduled| &scheduled.config)
+            .unwrap_or(&self.current)
+    }
+
+    async fn load_raw(env: &Env) -> Result<Option<String>> {
+        if let Ok(kv) = env.kv(CONFIG_KV_BINDING) {
+            if let Some(raw) = kv.get(CONFIG_KV_KEY).text().await? {
+                return Ok(Some(raw));
+            }
+        }
+
+        Ok(env.var(CONFIG_VAR).ok().map(|raw| raw.to_string()))
+    }
+
+    pub async fn load(env: &Env) -> Result<Self> {
+        let Some(raw) = Self::load_raw(env).await? else {
+            return Ok(Self::default());
+        };
+        let schedule: Self = serde_json::from_str(&raw)?;
+
+        Ok(schedule.validated())
+    }
+}
+
+/// [`HonGameConfigSchedule`] cached in the durable object
+#[derive(Default)]
+pub struct HonGameConfigCache {
+    schedule: Option<HonGameConfigSchedule>,
+    fetched_at_ms: u64,
+}
+
+impl HonGameConfigCache {
+    /// the active config, the previous schedule is kept if reloading fails
+    pub async fn get(&mut self, env: &Env) -> HonGameConfig {
+        let now = Date::now().as_millis();
+        if self.schedule.is_none() || now - self.fetched_at_ms >= CONFIG_CACHE_TTL_MS {
+            match HonGameConfigSchedule::load(env).await {
+                Ok(schedule) => self.schedule = Some(schedule),
+                Err(e) => console_error!("failed to load hot or not config: {e}"),
+            }
+            self.fetched_at_ms = now;
+        }
+
+        self.schedule
+            .get_or_insert_with(HonGameConfigSchedule::default)
+            .active(now)
+            .clone()
+    }
+}
@@ -3,6 +3,19 @@ use candid::Principal;
 pub const DEFAULT_ONBOARDING_REWARD_SATS: u64 = 1000;
 // mxzaz-hqaaa-aaaar-qaada-cai
 pub const CKBTC_LEDGER: Principal = Principal::from_slice(&[0, 0, 0, 0, 2, 48, 0, 6, 1, 1]);
+// n5wcd-faaaa-aaaar-qaaea-cai
+pub const CKBTC_INDEX: Principal = Principal::from_slice(&[0, 0, 0, 0, 2, 48, 0, 8, 1, 1]);
 // 1000 Satoshis
 pub const MAXIMUM_CKBTC_TREASURY_PER_DAY_PER_USER: u32 = 10000u32;
 pub const MAXIMUM_VOTE_AMOUNT_SATS: u128 = 200;
+// winnings are 80% of the vote amount
+pub const DEFAULT_WIN_MULTIPLIER_PERCENT: u32 = 80;
+// creators get 10% of the vote amount
+pub const DEFAULT_CREATOR_CUT_PERCENT: u32 = 10;
+// votes settle instantly unless a pooled window is configured
+pub const DEFAULT_POOLED_WINDOW_MS: u64 = 0;
+pub const MAXIMUM_POOLED_WINDOW_MS: u64 = 7 * 24 * 3600 * 1000;
+/// signed message of a nonced vote, the un-nonced one is `hon_game_vote_msg`
+pub const HON_GAME_VOTE_NONCED: &str = "hon_worker_game_vote_v2";
+/// signed message of a nonced withdrawal, the un-nonced one is `hon_game_withdraw_msg`
+pub const HON_GAME_WITHDRAW_NONCED: &str = "hon_worker_game_withdraw_v2";
@@ -0,0 +1,84 @@
+use candid::Principal;
+use serde::{Deserialize, Serialize};
+use worker_utils::storage::{Migration, VersionedSchema};
+
+/// first retry delay, doubled on every failed attempt
+const BASE_RETRY_DELAY_MS: u64 = 5 * 1000;
+const MAX_RETRY_DELAY_MS: u64 = 60 * 60 * 1000;
+/// creator rewards are retried for this long, then parked for manual recovery
+pub const CREATOR_REWARD_HORIZON_MS: u64 = 30 * 24 * 3600 * 1000;
+/// credited reward ids are remembered this long, no retry of them arrives later
+pub const RECEIVED_CREATOR_REWARD_TTL_MS: u64 = CREATOR_REWARD_HORIZON_MS + 24 * 3600 * 1000;
+
+fn expired(created_at_ms: Option<u64>, now_ms: u64) -> bool {
+    created_at_ms.is_some_and(|at| at.saturating_add(CREATOR_REWARD_HORIZON_MS) <= now_ms)
+}
+
+/// Sent to the creator's durable object, `id` is used to dedup retries
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreatorRewardReq {
+    pub id: String,
+    pub amount: u128,
+    /// ms since epoch, `None` for rewards queued before this was tracked
+    #[serde(default)]
+    pub created_at_ms: Option<u64>,
+}
+
+impl CreatorRewardReq {
+    /// past [`CREATOR_REWARD_HORIZON_MS`], the id may already be forgotten
+    pub fn expired(&self, now_ms: u64) -> bool {
+        expired(self.created_at_ms, now_ms)
+    }
+}
+
+/// A creator reward waiting to be delivered, keyed by its id
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingCreatorReward {
+    pub creator: Principal,
+    pub amount: u128,
+    pub attempts: u32,
+    /// ms since epoch
+    pub next_attempt_ms: u64,
+    /// ms since epoch, set on the first failed attempt for rewards queued before this was tracked
+    #[serde(default)]
+    pub created_at_ms: Option<u64>,
+}
+
+impl VersionedSchema for PendingCreatorReward {
+    type Value = Self;
+
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+impl PendingCreatorReward {
+    pub fn new(creator: Principal, amount: u128, now_ms: u64) -> Self {
+        Self {
+            creator,
+            amount,
+            attempts: 0,
+            next_attempt_ms: now_ms,
+            created_at_ms: Some(now_ms),
+        }
+    }
+
+    /// past [`CREATOR_REWARD_HORIZON_MS`], no longer retried
+    pub fn expired(&self, now_ms: u64) -> bool {
+        expired(self.created_at_ms, now_ms)
+    }
+
+    /// schedule the next attempt with exponential backoff
+    pub fn failed(&mut self, now_ms: u64) {
+        self.created_at_ms.get_or_insert(now_ms);
+        self.attempts += 1;
+        let delay = BASE_RETRY_DELAY_MS
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(MAX_RETRY_DELAY_MS);
+        self.next_attempt_ms = now_ms + delay;
+    }
+}
+
+pub fn new_reward_id() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("failed to generate reward id");
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
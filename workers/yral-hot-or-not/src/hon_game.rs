@@ -1,44 +1,103 @@
-use std::collections::HashMap;
-
-use candid::Principal;
+use candid::{Nat, Principal};
+use futures::StreamExt;
 use hon_worker_common::{
-    GameInfo, GameInfoReq, GameRes, GameResult, HotOrNot, PaginatedGamesReq, PaginatedGamesRes,
+    GameInfo, GameInfoReq, GameRes, HotOrNot, PaginatedGamesReq, PaginatedGamesRes,
     SatsBalanceInfo, VoteRequest, VoteRes, WithdrawRequest, WorkerError,
 };
 use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
-use std::result::Result as StdResult;
+use std::{rc::Rc, result::Result as StdResult};
 use worker::*;
 use worker_utils::{
-    storage::{SafeStorage, StorageCell},
+    signed::{NonceStore, ReplayKey},
+    storage::{
+        ListEntryError, SafeStorage, StorageCell, StorageMap, StorageTransaction, UnpaddedU64,
+    },
     RequestInitBuilder,
 };
 
 use crate::{
-    consts::{DEFAULT_ONBOARDING_REWARD_SATS, MAXIMUM_VOTE_AMOUNT_SATS},
-    get_hon_game_stub_env,
-    treasury::{CkBtcTreasury, CkBtcTreasuryImpl},
+    adjustment::{
+        AdjustmentReason, AdjustmentRes, AdminAdjustmentReq, BalanceAdjustment,
+        PaginatedAdjustmentsReq, PaginatedAdjustmentsRes,
+    },
+    config::{HonGameConfig, HonGameConfigCache},
+    consts::DEFAULT_ONBOARDING_REWARD_SATS,
+    creator_reward::{CreatorRewardReq, PendingCreatorReward, RECEIVED_CREATOR_REWARD_TTL_MS},
+    get_hon_game_stub_env, get_hon_post_pool_stub_env,
+    hon_pool::{
+        GameInfoRes, PendingGameInfo, PoolJoinReq, PoolJoinRes, PoolSettleReq, PoolVoteStatus,
+        PoolVoteStatusReq, PooledVoteEntry, VoteResV2,
+    },
+    leaderboard::{report_game_result, GameResultDelta},
+    treasury::{PayoutArgs, PayoutError, PayoutService, PayoutServiceImpl, CKBTC},
     treasury_obj::CkBtcTreasuryStore,
     utils::worker_err_to_resp,
+    vote_ledger::{GameKey, InstantVote, VoteLedger, STORAGE_PAGE_SIZE},
+    withdrawal::{
+        PaginatedWithdrawalsReq, PaginatedWithdrawalsRes, WithdrawalEntry, WithdrawalRes,
+        WithdrawalState,
+    },
 };
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct VoteRequestWithSentiment {
+    pub voter: Principal,
     pub request: VoteRequest,
     pub sentiment: HotOrNot,
     pub post_creator: Option<Principal>,
+    pub replay_key: ReplayKey,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WithdrawRequestWithReplayKey {
+    pub request: WithdrawRequest,
+    pub replay_key: ReplayKey,
 }
 
+/// creator rewards delivered per attempt, also used as the listing page size
+const CREATOR_REWARD_BATCH: usize = 32;
+/// pooled votes whose join failed ambiguously are checked against their pool after this
+const POOL_JOIN_RECONCILE_MS: u64 = 60 * 1000;
+/// delay before an alarm task that failed is retried
+const ALARM_RETRY_MS: u64 = 30 * 1000;
+/// withdrawals whose outcome is unknown are retried this often
+const WITHDRAWAL_RETRY_MS: u64 = 5 * 60 * 1000;
+/// pending withdrawals retried per alarm
+const WITHDRAWAL_BATCH: usize = 16;
+
 #[durable_object]
 pub struct UserHonGameState {
-    state: State,
+    state: Rc<State>,
     env: Env,
-    treasury: CkBtcTreasuryImpl,
+    // balance, games and stats
+    ledger: VoteLedger<Rc<State>>,
+    treasury: PayoutServiceImpl,
     treasury_amount: CkBtcTreasuryStore,
-    sats_balance: StorageCell<BigUint>,
     airdrop_amount: StorageCell<BigUint>,
-    // (canister_id, post_id) -> GameInfo
-    games: Option<HashMap<(Principal, u64), GameInfo>>,
+    // `nonce-{timestamp}-{idempotency_key}` and `unnonced-sig-{hash}`, consumed signed messages
+    nonces: NonceStore,
+    // `withdrawal-{idempotency_key}`
+    withdrawals: StorageMap<String, WithdrawalEntry>,
+    // `withdrawals-{timestamp}-{idempotency_key}`, withdrawals in the order they were made
+    withdrawal_history: StorageMap<(u64, String), ()>,
+    // `pending-withdrawal-{idempotency_key}`, withdrawals retried from the alarm until their outcome is known
+    pending_withdrawals: StorageMap<String, ()>,
+    // active config, refreshed on every request
+    config: HonGameConfig,
+    config_cache: HonGameConfigCache,
+    // `undelivered-creator-reward-{id}`, creator rewards given up on after the retry horizon
+    undelivered_creator_rewards: StorageMap<String, PendingCreatorReward>,
+    // `received-creator-reward-{id}`, creator rewards already credited to this user
+    received_creator_rewards: StorageMap<String, ()>,
+    // `received-reward-expiry-{expires_at_ms}-{id}`, when a credited reward id can be forgotten
+    received_creator_reward_expiry: StorageMap<(u64, String), ()>,
+    // `pooled-vote-{post_canister}-{post_id}`, votes waiting for their pooled round to settle
+    pooled_votes: StorageMap<GameKey, PooledVoteEntry>,
+    // `adjustments-{timestamp}-{idempotency_key}`, admin balance adjustments in the order they were made
+    adjustments: StorageMap<(u64, String), BalanceAdjustment>,
+    // `adjustment-id-{idempotency_key}`, timestamp of the adjustment
+    adjustment_ids: StorageMap<String, u64>,
 }
 
 impl UserHonGameState {
@@ -46,28 +105,27 @@ impl UserHonGameState {
         self.state.storage().into()
     }
 
-    async fn games(&mut self) -> Result<&mut HashMap<(Principal, u64), GameInfo>> {
-        if self.games.is_some() {
-            return Ok(self.games.as_mut().unwrap());
-        }
+    async fn refresh_config(&mut self) {
+        self.config = self.config_cache.get(&self.env).await;
+        let onboarding_reward = self.config.onboarding_reward_sats;
+        self.ledger
+            .sats_balance
+            .set_initial_value(move || BigUint::from(onboarding_reward));
+        self.airdrop_amount
+            .set_initial_value(move || BigUint::from(onboarding_reward));
+    }
 
-        let games = self
-            .storage()
-            .list_with_prefix("games-")
-            .await
-            .map(|v| {
-                v.map(|(k, v)| {
-                    let (can_raw, post_raw) =
-                        k.strip_prefix("games-").unwrap().rsplit_once("-").unwrap();
-                    let canister_id = Principal::from_text(can_raw).unwrap();
-                    let post_id = post_raw.parse::<u64>().unwrap();
-                    ((canister_id, post_id), v)
-                })
-            })
-            .collect::<Result<_>>()?;
+    fn daily_treasury_limit(&self) -> BigUint {
+        BigUint::from(self.config.max_ckbtc_treasury_per_day_per_user)
+    }
 
-        self.games = Some(games);
-        Ok(self.games.as_mut().unwrap())
+    /// consume the request's replay key, returns the error response if it's rejected
+    async fn consume_replay_key(&mut self, replay_key: &ReplayKey) -> Result<Option<Response>> {
+        let mut storage = self.storage();
+        match self.nonces.consume(&mut storage, replay_key).await {
+            Ok(()) => Ok(None),
+            Err(e) => e.to_response().map(Some),
+        }
     }
 
     async fn paginated_games_with_cursor(
@@ -77,32 +135,29 @@ impl UserHonGameState {
     ) -> Result<PaginatedGamesRes> {
         let page_size = page_size.clamp(1, 100);
         let to_fetch = page_size + 1;
-        let mut list_options = ListOptions::new().prefix("games-").limit(to_fetch);
-        if let Some(cursor) = cursor.as_ref() {
-            list_options = list_options.start(cursor.as_str());
-        }
+        let start = cursor
+            .map(|cursor| self.ledger.games.decode_storage_key(&cursor))
+            .transpose()?;
 
         let mut games = self
-            .storage()
-            .list_with_options::<GameInfo>(list_options)
-            .await
-            .map(|v| {
-                v.map(|(k, v)| {
-                    let (can_raw, post_raw) =
-                        k.strip_prefix("games-").unwrap().rsplit_once("-").unwrap();
-                    let canister_id = Principal::from_text(can_raw).unwrap();
-                    let post_id = post_raw.parse::<u64>().unwrap();
-                    GameRes {
-                        post_canister: canister_id,
-                        post_id,
-                        game_info: v,
-                    }
-                })
+            .ledger
+            .games
+            .page(&self.storage(), start.as_ref(), to_fetch)
+            .await?
+            .into_iter()
+            .map(|((post_canister, post_id), game_info)| GameRes {
+                post_canister,
+                post_id: post_id.0,
+                game_info,
             })
-            .collect::<Result<Vec<_>>>()?;
+            .collect::<Vec<_>>();
         let next = if games.len() > page_size {
             let info = games.pop().unwrap();
-            Some(format!("games-{}-{}", info.post_canister, info.post_id))
+            Some(
+                self.ledger
+                    .games
+                    .storage_key(&(info.post_canister, UnpaddedU64(info.post_id))),
+            )
         } else {
             None
         };
@@ -110,16 +165,209 @@ impl UserHonGameState {
         Ok(PaginatedGamesRes { games, next })
     }
 
-    async fn redeem_sats_for_ckbtc(
+    async fn paginated_withdrawals_with_cursor(
+        &mut self,
+        page_size: usize,
+        cursor: Option<String>,
+    ) -> Result<PaginatedWithdrawalsRes> {
+        let page_size = page_size.clamp(1, 100);
+        let to_fetch = page_size + 1;
+        let start = cursor
+            .map(|cursor| self.withdrawal_history.decode_storage_key(&cursor))
+            .transpose()?;
+
+        let storage = self.storage();
+        let mut keys = self
+            .withdrawal_history
+            .page(&storage, start.as_ref(), to_fetch)
+            .await?;
+        let next = if keys.len() > page_size {
+            let (key, _) = keys.pop().unwrap();
+            Some(self.withdrawal_history.storage_key(&key))
+        } else {
+            None
+        };
+
+        let mut withdrawals = Vec::with_capacity(keys.len());
+        for ((_, idempotency_key), _) in keys {
+            let Some(entry) = self.withdrawals.get(&storage, &idempotency_key).await? else {
+                continue;
+            };
+            withdrawals.push(WithdrawalRes::new(idempotency_key, entry.clone()));
+        }
+
+        Ok(PaginatedWithdrawalsRes { withdrawals, next })
+    }
+
+    async fn paginated_adjustments_with_cursor(
+        &mut self,
+        page_size: usize,
+        cursor: Option<String>,
+    ) -> Result<PaginatedAdjustmentsRes> {
+        let page_size = page_size.clamp(1, 100);
+        let to_fetch = page_size + 1;
+        let start = cursor
+            .map(|cursor| self.adjustments.decode_storage_key(&cursor))
+            .transpose()?;
+
+        let mut adjustments = self
+            .adjustments
+            .page(&self.storage(), start.as_ref(), to_fetch)
+            .await?;
+        let next = if adjustments.len() > page_size {
+            let (key, _) = adjustments.pop().unwrap();
+            Some(self.adjustments.storage_key(&key))
+        } else {
+            None
+        };
+        let adjustments = adjustments
+            .into_iter()
+            .map(|((timestamp_ms, idempotency_key), adjustment)| {
+                AdjustmentRes::new(idempotency_key, timestamp_ms, adjustment)
+            })
+            .collect();
+
+        Ok(PaginatedAdjustmentsRes { adjustments, next })
+    }
+
+    /// apply an admin balance adjustment, retries with the same idempotency key return the
+    /// original adjustment
+    async fn adjust_balance(
+        &mut self,
+        req: AdminAdjustmentReq,
+    ) -> StdResult<AdjustmentRes, (u16, WorkerError)> {
+        let AdminAdjustmentReq { admin, adjustment } = req;
+        let key = adjustment.idempotency_key.clone();
+        let storage = self.storage();
+        let existing_ts = self
+            .adjustment_ids
+            .get(&storage, &key)
+            .await
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to get adjustment".into()),
+                )
+            })?
+            .copied();
+        if let Some(timestamp_ms) = existing_ts {
+            let existing = self
+                .adjustments
+                .get(&storage, &(timestamp_ms, key.clone()))
+                .await
+                .map_err(|_| {
+                    (
+                        500,
+                        WorkerError::Internal("failed to get adjustment".into()),
+                    )
+                })?
+                .cloned();
+            return match existing {
+                Some(existing) if existing.matches(&adjustment) => {
+                    Ok(AdjustmentRes::new(key, timestamp_ms, existing))
+                }
+                _ => Err((
+                    400,
+                    WorkerError::Internal(
+                        "idempotency key already used for a different adjustment".into(),
+                    ),
+                )),
+            };
+        }
+
+        let is_airdrop = adjustment.reason == AdjustmentReason::Airdrop;
+        if adjustment.delta == 0 || (is_airdrop && adjustment.delta < 0) {
+            return Err((
+                400,
+                WorkerError::Internal("invalid adjustment amount".into()),
+            ));
+        }
+
+        let now = Date::now().as_millis();
+        let mut storage = self.storage();
+        let mut txn = storage.transaction();
+        let delta = adjustment.delta;
+        let mut insufficient_funds = false;
+        let mut balance_after = BigUint::from(0u32);
+        self.ledger
+            .sats_balance
+            .update_txn(&mut txn, |balance| {
+                if delta > 0 {
+                    *balance += delta.unsigned_abs();
+                } else {
+                    let debit = BigUint::from(delta.unsigned_abs());
+                    if *balance < debit {
+                        insufficient_funds = true;
+                        return;
+                    }
+                    *balance -= debit;
+                }
+                balance_after = balance.clone();
+            })
+            .await
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to update balance".into()),
+                )
+            })?;
+        if insufficient_funds {
+            return Err((400, WorkerError::InsufficientFunds));
+        }
+        if is_airdrop {
+            self.airdrop_amount
+                .update_txn(&mut txn, |airdropped| {
+                    *airdropped += delta.unsigned_abs();
+                })
+                .await
+                .map_err(|_| {
+                    (
+                        500,
+                        WorkerError::Internal("failed to update airdrop amount".into()),
+                    )
+                })?;
+        }
+
+        let entry = BalanceAdjustment {
+            delta,
+            reason: adjustment.reason,
+            note: adjustment.note,
+            admin,
+            balance_after,
+        };
+        self.adjustments
+            .insert_txn(&mut txn, &(now, key.clone()), &entry)
+            .and_then(|_| self.adjustment_ids.insert_txn(&mut txn, &key, &now))
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to store adjustment".into()),
+                )
+            })?;
+        txn.commit().await.map_err(|_| {
+            (
+                500,
+                WorkerError::Internal("failed to update balance".into()),
+            )
+        })?;
+
+        Ok(AdjustmentRes::new(key, now, entry))
+    }
+
+    /// debit the balance and record a pending withdrawal under `idempotency_key`
+    async fn begin_withdrawal(
         &mut self,
         user_principal: Principal,
         amount: BigUint,
-    ) -> StdResult<(), (u16, WorkerError)> {
+        idempotency_key: &String,
+    ) -> StdResult<WithdrawalEntry, (u16, WorkerError)> {
         let mut storage = self.storage();
+        let mut txn = storage.transaction();
 
         let mut insufficient_funds = false;
-        self.sats_balance
-            .update(&mut storage, |balance| {
+        self.ledger
+            .sats_balance
+            .update_txn(&mut txn, |balance| {
                 if *balance < amount {
                     insufficient_funds = true;
                     return;
@@ -137,93 +385,365 @@ impl UserHonGameState {
             return Err((400, WorkerError::InsufficientFunds));
         }
 
+        let daily_limit = self.daily_treasury_limit();
         if self
             .treasury_amount
-            .try_consume(&mut storage, amount.clone())
+            .try_consume(&mut txn, amount.clone(), &daily_limit)
             .await
             .inspect_err(|err| {
                 console_error!("withdraw error with treasury: {err:?}");
             })
             .is_err()
         {
-            self.sats_balance
-                .update(&mut storage, |balance| {
-                    *balance += amount.clone();
-                })
-                .await
-                .map_err(|_| {
-                    (
-                        500,
-                        WorkerError::Internal("failed to update balance".into()),
-                    )
-                })?;
             return Err((400, WorkerError::TreasuryLimitReached));
         }
 
+        let now = Date::now().as_millis();
+        let entry = WithdrawalEntry::new(user_principal, amount, now);
+        self.withdrawals
+            .insert_txn(&mut txn, idempotency_key, &entry)
+            .and_then(|_| {
+                self.withdrawal_history
+                    .insert_txn(&mut txn, &(now, idempotency_key.clone()), &())
+            })
+            .and_then(|_| {
+                self.pending_withdrawals
+                    .insert_txn(&mut txn, idempotency_key, &())
+            })
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to store withdrawal".into()),
+                )
+            })?;
+
+        txn.commit().await.map_err(|_| {
+            (
+                500,
+                WorkerError::Internal("failed to update balance".into()),
+            )
+        })?;
+
+        Ok(entry)
+    }
+
+    /// refund a withdrawal rejected by the ledger
+    async fn fail_withdrawal(
+        &mut self,
+        idempotency_key: &String,
+        mut entry: WithdrawalEntry,
+        reason: String,
+    ) -> StdResult<(), (u16, WorkerError)> {
+        let daily_limit = self.daily_treasury_limit();
+        let mut storage = self.storage();
+        let mut txn = storage.transaction();
+        self.treasury_amount
+            .rollback(&mut txn, entry.amount.clone(), &daily_limit)
+            .await
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to rollback treasury".into()),
+                )
+            })?;
+        let amount = entry.amount.clone();
+        self.ledger
+            .sats_balance
+            .update_txn(&mut txn, |balance| {
+                *balance += amount;
+            })
+            .await
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to update balance".into()),
+                )
+            })?;
+        entry.state = WithdrawalState::Failed { reason };
+        self.withdrawals
+            .insert_txn(&mut txn, idempotency_key, &entry)
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to store withdrawal".into()),
+                )
+            })?;
+        self.pending_withdrawals
+            .remove_txn(&mut txn, idempotency_key);
+        txn.commit().await.map_err(|_| {
+            (
+                500,
+                WorkerError::Internal("failed to rollback withdrawal".into()),
+            )
+        })
+    }
+
+    /// record the ledger block of a withdrawal
+    async fn complete_withdrawal(
+        &mut self,
+        idempotency_key: String,
+        mut entry: WithdrawalEntry,
+        block_index: Nat,
+    ) -> StdResult<(), (u16, WorkerError)> {
+        entry.state = WithdrawalState::Submitted { block_index };
+        let mut storage = self.storage();
+        let mut txn = storage.transaction();
+        self.pending_withdrawals
+            .remove_txn(&mut txn, &idempotency_key);
+        self.withdrawals
+            .insert_txn(&mut txn, &idempotency_key, &entry)
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to store withdrawal".into()),
+                )
+            })?;
+        txn.commit().await.map_err(|_| {
+            (
+                500,
+                WorkerError::Internal("failed to store withdrawal".into()),
+            )
+        })
+    }
+
+    /// keep a withdrawal whose outcome is unknown pending, it's retried from the alarm
+    async fn defer_withdrawal(
+        &self,
+        idempotency_key: &str,
+        e: &PayoutError,
+    ) -> StdResult<(), (u16, WorkerError)> {
+        console_error!("withdrawal {idempotency_key} pending: {e:?}");
         if let Err(e) = self
-            .treasury
-            .transfer_ckbtc(user_principal, amount.clone().into())
+            .schedule_alarm(Date::now().as_millis() + WITHDRAWAL_RETRY_MS)
             .await
         {
-            self.treasury_amount
-                .rollback(&mut storage, amount.clone())
-                .await
-                .map_err(|_| {
-                    (
-                        500,
-                        WorkerError::Internal("failed to rollback treasury".into()),
+            console_error!("failed to schedule withdrawal retry: {e}");
+        }
+
+        Err((
+            500,
+            WorkerError::Internal("withdrawal pending, retry with the same idempotency key".into()),
+        ))
+    }
+
+    fn withdrawal_args(idempotency_key: &str, entry: &WithdrawalEntry) -> PayoutArgs {
+        PayoutArgs {
+            to: entry.receiver,
+            amount: entry.amount.clone().into(),
+            memo: WithdrawalEntry::memo(idempotency_key),
+            created_at_time: entry.created_at_time,
+        }
+    }
+
+    /// send a pending withdrawal to the ledger
+    ///
+    /// if the outcome is unknown the entry stays pending, and the transfer is retried
+    /// with the same `created_at_time` and memo from the alarm or when the client retries with the same key.
+    /// once the ledger no longer dedups the transfer it's looked up instead
+    async fn submit_withdrawal(
+        &mut self,
+        idempotency_key: String,
+        entry: WithdrawalEntry,
+    ) -> StdResult<(), (u16, WorkerError)> {
+        let args = Self::withdrawal_args(&idempotency_key, &entry);
+        let res = self.treasury.transfer(CKBTC, args.clone()).await;
+
+        match res {
+            // an earlier attempt went through
+            Ok(block_index)
+            | Err(PayoutError::Duplicate {
+                duplicate_of: block_index,
+            }) => {
+                self.complete_withdrawal(idempotency_key, entry, block_index)
+                    .await
+            }
+            Err(PayoutError::TooOld) => match self.treasury.find_transfer(CKBTC, &args).await {
+                Ok(Some(block_index)) => {
+                    self.complete_withdrawal(idempotency_key, entry, block_index)
+                        .await
+                }
+                Ok(None) => {
+                    self.fail_withdrawal(
+                        &idempotency_key,
+                        entry,
+                        "expired before reaching the ledger".into(),
                     )
-                })?;
-            self.sats_balance
-                .update(&mut storage, |balance| {
-                    *balance += amount.clone();
-                })
-                .await
-                .map_err(|_| {
-                    (
+                    .await?;
+                    Err((
                         500,
-                        WorkerError::Internal("failed to update balance".into()),
-                    )
-                })?;
-            return Err(e);
+                        WorkerError::Internal(
+                            "withdrawal expired, the balance was refunded".into(),
+                        ),
+                    ))
+                }
+                Err(e) => self.defer_withdrawal(&idempotency_key, &e).await,
+            },
+            Err(e) if e.is_retryable() => self.defer_withdrawal(&idempotency_key, &e).await,
+            Err(e) => {
+                self.fail_withdrawal(&idempotency_key, entry, format!("{e:?}"))
+                    .await?;
+                Err(e.to_worker_error())
+            }
+        }
+    }
+
+    /// retry withdrawals whose outcome is unknown, up to [`WITHDRAWAL_BATCH`] at a time
+    async fn reconcile_withdrawals(&mut self) -> Result<()> {
+        let now = Date::now().as_millis();
+        let storage = self.storage();
+        let pending = self
+            .pending_withdrawals
+            .page(&storage, None, WITHDRAWAL_BATCH)
+            .await?;
+        let more = pending.len() >= WITHDRAWAL_BATCH;
+
+        let mut retry = false;
+        for (idempotency_key, _) in pending {
+            let storage = self.storage();
+            let entry = self
+                .withdrawals
+                .get(&storage, &idempotency_key)
+                .await?
+                .cloned();
+            let Some(entry) = entry.filter(|entry| entry.state == WithdrawalState::Pending) else {
+                let mut storage = self.storage();
+                self.pending_withdrawals
+                    .remove(&mut storage, &idempotency_key)
+                    .await?;
+                continue;
+            };
+            // the request that created it may still be submitting it
+            if entry.created_at_time / 1_000_000 + WITHDRAWAL_RETRY_MS > now {
+                retry = true;
+                continue;
+            }
+            if self
+                .submit_withdrawal(idempotency_key, entry)
+                .await
+                .is_err()
+            {
+                retry = true;
+            }
+        }
+
+        if more {
+            self.schedule_alarm(now).await?;
+        } else if retry {
+            self.schedule_alarm(now + WITHDRAWAL_RETRY_MS).await?;
         }
 
         Ok(())
     }
 
-    async fn game_info(
+    async fn redeem_sats_for_ckbtc(
         &mut self,
-        post_canister: Principal,
-        post_id: u64,
-    ) -> Result<Option<GameInfo>> {
-        let games = self.games().await?;
-        Ok(games.get(&(post_canister, post_id)).cloned())
-    }
-
-    async fn add_creator_reward(&mut self, reward: u128) -> StdResult<(), (u16, WorkerError)> {
-        let mut storage = self.storage();
-        self.sats_balance
-            .update(&mut storage, |bal| {
-                *bal += reward;
-            })
+        user_principal: Principal,
+        amount: BigUint,
+        idempotency_key: String,
+    ) -> StdResult<(), (u16, WorkerError)> {
+        let storage = self.storage();
+        let existing = self
+            .withdrawals
+            .get(&storage, &idempotency_key)
             .await
             .map_err(|_| {
                 (
                     500,
-                    WorkerError::Internal("failed to update balance".into()),
+                    WorkerError::Internal("failed to get withdrawal".into()),
                 )
-            })
+            })?
+            .cloned();
+
+        let entry = match existing {
+            None => {
+                self.begin_withdrawal(user_principal, amount, &idempotency_key)
+                    .await?
+            }
+            Some(entry) if entry.receiver != user_principal || entry.amount != amount => {
+                return Err((
+                    400,
+                    WorkerError::Internal(
+                        "idempotency key already used for a different withdrawal".into(),
+                    ),
+                ));
+            }
+            Some(entry) => match entry.state {
+                WithdrawalState::Submitted { .. } => return Ok(()),
+                WithdrawalState::Failed { reason } => {
+                    return Err((400, WorkerError::Internal(reason)));
+                }
+                WithdrawalState::Pending => entry,
+            },
+        };
+
+        self.submit_withdrawal(idempotency_key, entry).await
     }
 
-    async fn vote_on_post(
+    /// settled game or pooled vote on the post
+    async fn game_info(
         &mut self,
         post_canister: Principal,
         post_id: u64,
-        mut vote_amount: u128,
-        direction: HotOrNot,
-        sentiment: HotOrNot,
-        creator_principal: Option<Principal>,
-    ) -> StdResult<VoteRes, (u16, WorkerError)> {
+    ) -> Result<Option<GameInfoRes>> {
+        let storage = self.storage();
+        let key = (post_canister, UnpaddedU64(post_id));
+        if let Some(game_info) = self.ledger.games.get(&storage, &key).await? {
+            return Ok(Some(GameInfoRes::Settled(game_info.clone())));
+        }
+        let pending = self.pooled_votes.get(&storage, &key).await?;
+
+        Ok(pending.map(|entry| GameInfoRes::Pending(entry.clone().into())))
+    }
+
+    async fn send_pool_join(
+        &self,
+        req: &PoolJoinReq,
+    ) -> StdResult<PoolJoinRes, (u16, WorkerError)> {
+        let pool_stub = get_hon_post_pool_stub_env(&self.env, req.post_canister, req.post_id)
+            .map_err(|_| (500, WorkerError::Internal("failed to get pool stub".into())))?;
+        let join_req = Request::new_with_init(
+            "http://fake_url.com/join",
+            RequestInitBuilder::default()
+                .method(Method::Post)
+                .json(req)
+                .map_err(|_| {
+                    (
+                        500,
+                        WorkerError::Internal("failed to build join request".into()),
+                    )
+                })?
+                .build(),
+        )
+        .map_err(|_| {
+            (
+                500,
+                WorkerError::Internal("failed to build join request".into()),
+            )
+        })?;
+        let mut res = pool_stub
+            .fetch_with_request(join_req)
+            .await
+            .map_err(|_| (500, WorkerError::Internal("failed to join pool".into())))?;
+        let status = res.status_code();
+        if !(200..300).contains(&status) {
+            let err = res
+                .json::<WorkerError>()
+                .await
+                .unwrap_or_else(|_| WorkerError::Internal("failed to join pool".into()));
+            return Err((status, err));
+        }
+
+        res.json()
+            .await
+            .map_err(|_| (500, WorkerError::Internal("invalid pool response".into())))
+    }
+
+    /// escrow the stake and add the vote to the post's pooled round
+    async fn join_pool(
+        &mut self,
+        mut join_req: PoolJoinReq,
+    ) -> StdResult<PendingGameInfo, (u16, WorkerError)> {
+        let (post_canister, post_id) = (join_req.post_canister, join_req.post_id);
         let game_info = self
             .game_info(post_canister, post_id)
             .await
@@ -231,29 +751,29 @@ impl UserHonGameState {
         if game_info.is_some() {
             return Err((400, WorkerError::AlreadyVotedOnPost));
         }
+        join_req.vote_amount = join_req.vote_amount.min(self.config.max_vote_amount_sats);
+        let vote_amount = join_req.vote_amount;
 
-        vote_amount = vote_amount.min(MAXIMUM_VOTE_AMOUNT_SATS);
-
+        let key = (post_canister, UnpaddedU64(post_id));
+        let now = Date::now().as_millis();
+        let mut entry = PooledVoteEntry {
+            vote_amount,
+            direction: join_req.direction.clone(),
+            settles_at_ms: None,
+            created_at_ms: now,
+            voter: Some(join_req.voter),
+        };
         let mut storage = self.storage();
-        let mut res = None::<(GameResult, u128)>;
-        self.sats_balance
-            .update(&mut storage, |balance| {
-                let creator_reward = vote_amount / 10;
-                let vote_amount = BigUint::from(vote_amount);
-                if *balance < vote_amount {
+        let mut txn = storage.transaction();
+        let mut insufficient_funds = false;
+        self.ledger
+            .sats_balance
+            .update_txn(&mut txn, |balance| {
+                if *balance < BigUint::from(vote_amount) {
+                    insufficient_funds = true;
                     return;
                 }
-                let game_res = if sentiment == direction {
-                    let win_amt = (vote_amount.clone() * 8u32) / 10u32;
-                    *balance += win_amt.clone();
-                    GameResult::Win { win_amt }
-                } else {
-                    *balance -= vote_amount.clone();
-                    GameResult::Loss {
-                        lose_amt: vote_amount.clone(),
-                    }
-                };
-                res = Some((game_res, creator_reward))
+                *balance -= vote_amount;
             })
             .await
             .map_err(|_| {
@@ -262,46 +782,555 @@ impl UserHonGameState {
                     WorkerError::Internal("failed to update balance".into()),
                 )
             })?;
-
-        let Some((game_result, creator_reward)) = res else {
+        if insufficient_funds {
             return Err((400, WorkerError::InsufficientFunds));
+        }
+        self.pooled_votes
+            .insert_txn(&mut txn, &key, &entry)
+            .map_err(|_| (500, WorkerError::Internal("failed to store vote".into())))?;
+        txn.commit().await.map_err(|_| {
+            (
+                500,
+                WorkerError::Internal("failed to update balance".into()),
+            )
+        })?;
+
+        let joined = match self.send_pool_join(&join_req).await {
+            Ok(joined) => joined,
+            // the pool rejected the vote
+            Err((status, e)) if (400..500).contains(&status) => {
+                self.return_pooled_stake(&key, vote_amount).await?;
+                return Err((status, e));
+            }
+            Err(_) => {
+                // the pool may have accepted the vote, it's reconciled from the alarm
+                console_warn!(
+                    "failed to join pool for {post_canister}/{post_id}, reconciling later"
+                );
+                self.schedule_alarm(now + POOL_JOIN_RECONCILE_MS)
+                    .await
+                    .map_err(|_| {
+                        (
+                            500,
+                            WorkerError::Internal("failed to schedule reconciliation".into()),
+                        )
+                    })?;
+                return Ok(entry.into());
+            }
         };
 
-        if let Some(creator_principal) = creator_principal {
-            let game_stub = get_hon_game_stub_env(&self.env, creator_principal)
-                .map_err(|_| (500, WorkerError::Internal("failed to get game stub".into())))?;
-            let req = Request::new_with_init(
-                "http://fake_url.com/creator_reward",
-                RequestInitBuilder::default()
-                    .method(Method::Post)
-                    .json(&creator_reward)
-                    .unwrap()
-                    .build(),
+        entry.settles_at_ms = Some(joined.settles_at_ms);
+        // the round may have settled while joining
+        let pending = self
+            .pooled_votes
+            .get(&storage, &key)
+            .await
+            .map_err(|_| (500, WorkerError::Internal("failed to get vote".into())))?;
+        if pending.is_some() {
+            self.pooled_votes
+                .insert(&mut storage, key, entry.clone())
+                .await
+                .map_err(|_| (500, WorkerError::Internal("failed to store vote".into())))?;
+        }
+
+        Ok(entry.into())
+    }
+
+    /// return the stake of a pooled vote the pool never accepted
+    async fn return_pooled_stake(
+        &mut self,
+        key: &GameKey,
+        vote_amount: u128,
+    ) -> StdResult<(), (u16, WorkerError)> {
+        let mut storage = self.storage();
+        let mut txn = storage.transaction();
+        self.ledger
+            .sats_balance
+            .update_txn(&mut txn, |balance| {
+                *balance += vote_amount;
+            })
+            .await
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to update balance".into()),
+                )
+            })?;
+        self.pooled_votes.remove_txn(&mut txn, key);
+        txn.commit().await.map_err(|_| {
+            (
+                500,
+                WorkerError::Internal("failed to update balance".into()),
             )
-            .expect("creator reward should build?!");
-            let res = game_stub.fetch_with_request(req).await;
-            if let Err(e) = res {
-                eprintln!("failed to reward creator {e}");
+        })
+    }
+
+    async fn pool_vote_status(
+        &self,
+        voter: Principal,
+        post_canister: Principal,
+        post_id: u64,
+    ) -> Result<PoolVoteStatus> {
+        let pool_stub = get_hon_post_pool_stub_env(&self.env, post_canister, post_id)?;
+        let req = Request::new_with_init(
+            "http://fake_url.com/vote_status",
+            RequestInitBuilder::default()
+                .method(Method::Post)
+                .json(&PoolVoteStatusReq { voter })?
+                .build(),
+        )?;
+        let mut res = pool_stub.fetch_with_request(req).await?;
+        if res.status_code() != 200 {
+            return Err(Error::RustError(format!(
+                "pool returned {}: {}",
+                res.status_code(),
+                res.text().await?
+            )));
+        }
+
+        res.json().await
+    }
+
+    /// resolve pooled votes whose join failed ambiguously against their pool,
+    /// votes the pool never accepted have their stake returned
+    async fn reconcile_pool_joins(&mut self) -> Result<()> {
+        let now = Date::now().as_millis();
+        let storage = self.storage();
+        let mut unjoined = vec![];
+        let mut entries = self.pooled_votes.stream(&storage, STORAGE_PAGE_SIZE);
+        while let Some(entry) = entries.next().await {
+            match entry {
+                Ok((key, entry)) if entry.settles_at_ms.is_none() => match entry.voter {
+                    Some(voter) => unjoined.push((key, voter, entry.created_at_ms)),
+                    None => console_warn!("pooled vote without a voter can't be reconciled"),
+                },
+                Ok(_) => (),
+                Err(ListEntryError::Storage(e)) => return Err(Error::RustError(e)),
+                Err(e) => console_error!("skipping pooled vote: {e}"),
             }
         }
+        drop(entries);
 
-        let game_info = GameInfo::Vote {
-            vote_amount: BigUint::from(vote_amount),
-            game_result: game_result.clone(),
-        };
-        self.games()
+        let mut next_attempt = None::<u64>;
+        for ((post_canister, post_id), voter, created_at_ms) in unjoined {
+            // give joins that are still in flight time to land
+            let due_at = created_at_ms + POOL_JOIN_RECONCILE_MS;
+            if due_at > now {
+                next_attempt = Some(next_attempt.map_or(due_at, |at| at.min(due_at)));
+                continue;
+            }
+            let status = match self.pool_vote_status(voter, post_canister, post_id.0).await {
+                Ok(status) => status,
+                Err(e) => {
+                    console_error!(
+                        "failed to get pool vote status for {post_canister}/{}: {e}",
+                        post_id.0
+                    );
+                    next_attempt = Some(now + POOL_JOIN_RECONCILE_MS);
+                    continue;
+                }
+            };
+
+            // the vote may have settled while querying the pool
+            let key = (post_canister, post_id);
+            let mut storage = self.storage();
+            let Some(mut entry) = self.pooled_votes.get(&storage, &key).await?.cloned() else {
+                continue;
+            };
+            if entry.settles_at_ms.is_some() {
+                continue;
+            }
+            match status {
+                PoolVoteStatus::Open(joined) => {
+                    entry.settles_at_ms = Some(joined.settles_at_ms);
+                    self.pooled_votes.insert(&mut storage, key, entry).await?;
+                }
+                // the payout settles the vote
+                PoolVoteStatus::Settling => next_attempt = Some(now + POOL_JOIN_RECONCILE_MS),
+                PoolVoteStatus::Unknown => {
+                    self.return_pooled_stake(&key, entry.vote_amount)
+                        .await
+                        .map_err(|_| Error::RustError("failed to return pooled stake".into()))?;
+                }
+            }
+        }
+
+        if let Some(at_ms) = next_attempt {
+            self.schedule_alarm(at_ms).await?;
+        }
+
+        Ok(())
+    }
+
+    /// apply a settled pooled round, payouts that were already applied are ignored
+    async fn settle_pool_payout(
+        &mut self,
+        req: PoolSettleReq,
+    ) -> StdResult<(), (u16, WorkerError)> {
+        let storage = self.storage();
+        let key = (req.post_canister, UnpaddedU64(req.post_id));
+        let pending = self
+            .pooled_votes
+            .get(&storage, &key)
             .await
-            .map_err(|_| (500, WorkerError::Internal("failed to get games".into())))?
-            .insert((post_canister, post_id), game_info.clone());
-        self.storage()
-            .put(&format!("games-{}-{}", post_canister, post_id), &game_info)
+            .map_err(|_| (500, WorkerError::Internal("failed to get vote".into())))?
+            .is_some();
+        let creator_reward = req.creator_reward();
+        let creator_reward_due = self.creator_reward_due(&creator_reward).await?;
+
+        self.ledger
+            .stats()
+            .await
+            .map_err(|_| (500, WorkerError::Internal("failed to get stats".into())))?;
+
+        let mut storage = self.storage();
+        let mut txn = storage.transaction();
+        let mut credit = 0u128;
+        if let (true, Some(vote)) = (pending, req.payout.vote) {
+            if !vote.refunded {
+                self.ledger
+                    .update_stats_txn(&mut txn, |stats| stats.record_game(&vote.game_result))
+                    .await?;
+            }
+            let game_info = GameInfo::Vote {
+                vote_amount: BigUint::from(vote.vote_amount),
+                game_result: vote.game_result,
+            };
+            self.ledger
+                .games
+                .insert_txn(&mut txn, &key, &game_info)
+                .map_err(|_| {
+                    (
+                        500,
+                        WorkerError::Internal("failed to store game info".into()),
+                    )
+                })?;
+            self.pooled_votes.remove_txn(&mut txn, &key);
+            credit += vote.credit;
+        }
+        if creator_reward_due {
+            let amount = creator_reward.amount;
+            self.ledger
+                .update_stats_txn(&mut txn, |stats| stats.record_creator_reward(amount))
+                .await?;
+            self.mark_creator_reward_txn(&mut txn, &creator_reward.id)?;
+            credit += amount;
+        }
+        if txn.is_empty() {
+            return Ok(());
+        }
+        if credit > 0 {
+            self.ledger
+                .sats_balance
+                .update_txn(&mut txn, |balance| {
+                    *balance += credit;
+                })
+                .await
+                .map_err(|_| {
+                    (
+                        500,
+                        WorkerError::Internal("failed to update balance".into()),
+                    )
+                })?;
+        }
+
+        txn.commit().await.map_err(|_| {
+            (
+                500,
+                WorkerError::Internal("failed to settle pooled vote".into()),
+            )
+        })?;
+        self.prune_received_creator_rewards().await;
+
+        Ok(())
+    }
+
+    /// credit a creator reward, rewards that were already credited are ignored
+    async fn add_creator_reward(
+        &mut self,
+        reward: CreatorRewardReq,
+    ) -> StdResult<(), (u16, WorkerError)> {
+        if !self.creator_reward_due(&reward).await? {
+            return Ok(());
+        }
+        self.ledger
+            .stats()
+            .await
+            .map_err(|_| (500, WorkerError::Internal("failed to get stats".into())))?;
+
+        let mut storage = self.storage();
+        let mut txn = storage.transaction();
+        self.ledger
+            .update_stats_txn(&mut txn, |stats| stats.record_creator_reward(reward.amount))
+            .await?;
+        self.ledger
+            .sats_balance
+            .update_txn(&mut txn, |bal| {
+                *bal += reward.amount;
+            })
             .await
             .map_err(|_| {
                 (
                     500,
-                    WorkerError::Internal("failed to store game info".into()),
+                    WorkerError::Internal("failed to update balance".into()),
                 )
             })?;
+        self.mark_creator_reward_txn(&mut txn, &reward.id)?;
+        txn.commit().await.map_err(|_| {
+            (
+                500,
+                WorkerError::Internal("failed to update balance".into()),
+            )
+        })?;
+        self.prune_received_creator_rewards().await;
+
+        Ok(())
+    }
+
+    /// whether a creator reward still has to be credited
+    ///
+    /// rewards past the retry horizon are never credited,
+    /// their id may have been forgotten after an earlier credit
+    async fn creator_reward_due(
+        &mut self,
+        reward: &CreatorRewardReq,
+    ) -> StdResult<bool, (u16, WorkerError)> {
+        if reward.amount == 0 {
+            return Ok(false);
+        }
+        let storage = self.storage();
+        let received = self
+            .received_creator_rewards
+            .get(&storage, &reward.id)
+            .await
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to get creator reward".into()),
+                )
+            })?
+            .is_some();
+        if received {
+            return Ok(false);
+        }
+        if reward.expired(Date::now().as_millis()) {
+            console_error!("creator reward {} expired before delivery", reward.id);
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// remember a credited creator reward until no retry of it can arrive
+    fn mark_creator_reward_txn(
+        &mut self,
+        txn: &mut StorageTransaction<'_>,
+        id: &String,
+    ) -> StdResult<(), (u16, WorkerError)> {
+        let expires_at_ms = Date::now().as_millis() + RECEIVED_CREATOR_REWARD_TTL_MS;
+        self.received_creator_rewards
+            .insert_txn(txn, id, &())
+            .and_then(|_| {
+                self.received_creator_reward_expiry.insert_txn(
+                    txn,
+                    &(expires_at_ms, id.clone()),
+                    &(),
+                )
+            })
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to store creator reward".into()),
+                )
+            })
+    }
+
+    /// forget up to [`CREATOR_REWARD_BATCH`] credited creator rewards that expired
+    async fn prune_received_creator_rewards(&mut self) {
+        let now = Date::now().as_millis();
+        let mut storage = self.storage();
+        let expired = match self
+            .received_creator_reward_expiry
+            .page(&storage, None, CREATOR_REWARD_BATCH)
+            .await
+        {
+            Ok(page) => page,
+            Err(e) => {
+                console_error!("failed to list received creator rewards: {e}");
+                return;
+            }
+        };
+
+        let mut txn = storage.transaction();
+        for (key, _) in expired {
+            if key.0 > now {
+                break;
+            }
+            self.received_creator_rewards.remove_txn(&mut txn, &key.1);
+            self.received_creator_reward_expiry
+                .remove_txn(&mut txn, &key);
+        }
+        if let Err(e) = txn.commit().await {
+            console_error!("failed to prune received creator rewards: {e}");
+        }
+    }
+
+    async fn send_creator_reward(&self, id: String, reward: &PendingCreatorReward) -> Result<()> {
+        let game_stub = get_hon_game_stub_env(&self.env, reward.creator)?;
+        let req = Request::new_with_init(
+            "http://fake_url.com/creator_reward",
+            RequestInitBuilder::default()
+                .method(Method::Post)
+                .json(&CreatorRewardReq {
+                    id,
+                    amount: reward.amount,
+                    created_at_ms: reward.created_at_ms,
+                })?
+                .build(),
+        )?;
+        let mut res = game_stub.fetch_with_request(req).await?;
+        if !(200..300).contains(&res.status_code()) {
+            return Err(Error::RustError(format!(
+                "creator reward rejected with {}: {}",
+                res.status_code(),
+                res.text().await?
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// schedule the alarm for `at_ms`, unless it is already scheduled earlier
+    async fn schedule_alarm(&self, at_ms: u64) -> Result<()> {
+        let mut storage = self.storage();
+        if let Some(alarm) = storage.get_alarm().await? {
+            if alarm <= at_ms as i64 {
+                return Ok(());
+            }
+        }
+        let offset = at_ms.saturating_sub(Date::now().as_millis());
+        storage.set_alarm(offset as i64).await
+    }
+
+    /// try to deliver pending creator rewards that are due,
+    /// failed deliveries are retried from the alarm with backoff
+    async fn deliver_creator_rewards(&mut self) -> Result<()> {
+        let now = Date::now().as_millis();
+        let mut storage = self.storage();
+        let mut due = vec![];
+        let mut expired = vec![];
+        let mut next_attempt = None::<u64>;
+        let mut entries = self
+            .ledger
+            .creator_reward_outbox
+            .stream(&storage, CREATOR_REWARD_BATCH);
+        while let Some(entry) = entries.next().await {
+            match entry {
+                Ok((id, reward)) if reward.expired(now) => expired.push((id, reward)),
+                Ok((id, reward)) if reward.next_attempt_ms <= now => due.push((id, reward)),
+                Ok((_, reward)) => {
+                    next_attempt = Some(
+                        next_attempt
+                            .map_or(reward.next_attempt_ms, |at| at.min(reward.next_attempt_ms)),
+                    )
+                }
+                Err(ListEntryError::Storage(e)) => return Err(Error::RustError(e)),
+                Err(e) => console_error!("skipping creator reward: {e}"),
+            }
+        }
+        drop(entries);
+
+        // the creator would no longer credit them, kept for manual recovery
+        for (id, reward) in expired {
+            console_error!(
+                "parking creator reward {id} for {} after {} attempts",
+                reward.creator,
+                reward.attempts
+            );
+            let mut txn = storage.transaction();
+            self.ledger.creator_reward_outbox.remove_txn(&mut txn, &id);
+            self.undelivered_creator_rewards
+                .insert_txn(&mut txn, &id, &reward)?;
+            txn.commit().await?;
+        }
+
+        if due.len() > CREATOR_REWARD_BATCH {
+            due.truncate(CREATOR_REWARD_BATCH);
+            next_attempt = Some(now);
+        }
+        for (id, mut reward) in due {
+            match self.send_creator_reward(id.clone(), &reward).await {
+                Ok(()) => {
+                    self.ledger
+                        .creator_reward_outbox
+                        .remove(&mut storage, &id)
+                        .await?;
+                }
+                Err(e) => {
+                    console_error!("failed to deliver creator reward {id}: {e}");
+                    reward.failed(now);
+                    next_attempt = Some(
+                        next_attempt
+                            .map_or(reward.next_attempt_ms, |at| at.min(reward.next_attempt_ms)),
+                    );
+                    self.ledger
+                        .creator_reward_outbox
+                        .insert(&mut storage, id, reward)
+                        .await?;
+                }
+            }
+        }
+
+        if let Some(at_ms) = next_attempt {
+            self.schedule_alarm(at_ms).await?;
+        }
+
+        Ok(())
+    }
+
+    /// settle a vote instantly and report it to the leaderboard
+    async fn settle_vote(
+        &mut self,
+        req: VoteRequestWithSentiment,
+    ) -> StdResult<VoteRes, (u16, WorkerError)> {
+        let res = self
+            .vote_on_post(InstantVote {
+                post_canister: req.request.post_canister,
+                post_id: req.request.post_id,
+                vote_amount: req.request.vote_amount,
+                direction: req.request.direction,
+                sentiment: req.sentiment,
+                creator: req.post_creator,
+            })
+            .await?;
+        let delta = GameResultDelta::new(req.voter, &res.game_result);
+        report_game_result(&self.env, delta);
+
+        Ok(res)
+    }
+
+    async fn vote_on_post(&mut self, vote: InstantVote) -> StdResult<VoteRes, (u16, WorkerError)> {
+        let storage = self.storage();
+        let pooled = self
+            .pooled_votes
+            .get(&storage, &(vote.post_canister, UnpaddedU64(vote.post_id)))
+            .await
+            .map_err(|_| (500, WorkerError::Internal("failed to get game info".into())))?
+            .is_some();
+        if pooled {
+            return Err((400, WorkerError::AlreadyVotedOnPost));
+        }
+
+        let (game_result, creator_reward) = self
+            .ledger
+            .settle_instant_vote(vote, &self.config, Date::now().as_millis())
+            .await?;
+
+        if creator_reward {
+            if let Err(e) = self.deliver_creator_rewards().await {
+                console_error!("failed to deliver creator rewards: {e}");
+            }
+        }
 
         Ok(VoteRes { game_result })
     }
@@ -312,41 +1341,76 @@ impl DurableObject for UserHonGameState {
     fn new(state: State, env: Env) -> Self {
         console_error_panic_hook::set_once();
 
-        let treasury = CkBtcTreasuryImpl::new(&env).expect("failed to create treasury");
+        let treasury = PayoutServiceImpl::new(&env).expect("failed to create treasury");
 
+        let state = Rc::new(state);
         Self {
+            ledger: VoteLedger::new(state.clone()),
             state,
             env,
             treasury,
             treasury_amount: CkBtcTreasuryStore::default(),
-            sats_balance: StorageCell::new("sats_balance", || {
-                BigUint::from(DEFAULT_ONBOARDING_REWARD_SATS)
-            }),
             airdrop_amount: StorageCell::new("airdrop_amount", || {
                 BigUint::from(DEFAULT_ONBOARDING_REWARD_SATS)
             }),
-            games: None,
+            nonces: NonceStore::default(),
+            withdrawals: StorageMap::new_versioned::<WithdrawalEntry>("withdrawal-"),
+            withdrawal_history: StorageMap::new("withdrawals-"),
+            pending_withdrawals: StorageMap::new("pending-withdrawal-"),
+            config: HonGameConfig::default(),
+            config_cache: HonGameConfigCache::default(),
+            undelivered_creator_rewards: StorageMap::new_versioned::<PendingCreatorReward>(
+                "undelivered-creator-reward-",
+            ),
+            received_creator_rewards: StorageMap::new("received-creator-reward-"),
+            received_creator_reward_expiry: StorageMap::new("received-reward-expiry-"),
+            pooled_votes: StorageMap::new_versioned::<PooledVoteEntry>("pooled-vote-"),
+            adjustments: StorageMap::new_versioned::<BalanceAdjustment>("adjustments-"),
+            adjustment_ids: StorageMap::new("adjustment-id-"),
         }
     }
 
     async fn fetch(&mut self, req: Request) -> Result<Response> {
+        self.refresh_config().await;
         let env = self.env.clone();
         let router = Router::with_data(self);
         router
             .post_async("/vote", async |mut req, ctx| {
                 let req_data: VoteRequestWithSentiment = serde_json::from_str(&req.text().await?)?;
                 let this = ctx.data;
-                match this
-                    .vote_on_post(
-                        req_data.request.post_canister,
-                        req_data.request.post_id,
-                        req_data.request.vote_amount,
-                        req_data.request.direction,
-                        req_data.sentiment,
-                        req_data.post_creator,
-                    )
-                    .await
-                {
+                if let Some(err_res) = this.consume_replay_key(&req_data.replay_key).await? {
+                    return Ok(err_res);
+                }
+                // always settled instantly, pooled votes go through `/vote_v2`
+                match this.settle_vote(req_data).await {
+                    Ok(res) => Response::from_json(&res),
+                    Err((code, msg)) => worker_err_to_resp(code, msg),
+                }
+            })
+            .post_async("/vote_v2", async |mut req, ctx| {
+                let req_data: VoteRequestWithSentiment = serde_json::from_str(&req.text().await?)?;
+                let this = ctx.data;
+                if let Some(err_res) = this.consume_replay_key(&req_data.replay_key).await? {
+                    return Ok(err_res);
+                }
+                let window_ms = this.config.pooled_window_ms;
+                let res = if window_ms > 0 {
+                    // settled by the post's pool
+                    let join_req = PoolJoinReq {
+                        voter: req_data.voter,
+                        post_canister: req_data.request.post_canister,
+                        post_id: req_data.request.post_id,
+                        post_creator: req_data.post_creator,
+                        vote_amount: req_data.request.vote_amount,
+                        direction: req_data.request.direction,
+                        window_ms,
+                        sentiment: Some(req_data.sentiment),
+                    };
+                    this.join_pool(join_req).await.map(VoteResV2::Pending)
+                } else {
+                    this.settle_vote(req_data).await.map(VoteResV2::Settled)
+                };
+                match res {
                     Ok(res) => Response::from_json(&res),
                     Err((code, msg)) => worker_err_to_resp(code, msg),
                 }
@@ -354,7 +1418,7 @@ impl DurableObject for UserHonGameState {
             .get_async("/balance", async |_, ctx| {
                 let this = ctx.data;
                 let storage = this.storage();
-                let balance = this.sats_balance.read(&storage).await?.clone();
+                let balance = this.ledger.sats_balance.read(&storage).await?.clone();
                 let airdropped = this.airdrop_amount.read(&storage).await?.clone();
                 Response::from_json(&SatsBalanceInfo {
                     balance,
@@ -379,11 +1443,63 @@ impl DurableObject for UserHonGameState {
 
                 Response::from_json(&res)
             })
+            .post_async("/withdrawals", async |mut req, ctx| {
+                let req_data: PaginatedWithdrawalsReq = req.json().await?;
+                let this = ctx.data;
+                let res = this
+                    .paginated_withdrawals_with_cursor(req_data.page_size, req_data.cursor)
+                    .await?;
+
+                Response::from_json(&res)
+            })
+            .get_async("/stats", async |_, ctx| {
+                let this = ctx.data;
+                let stats = this.ledger.stats().await?;
+
+                Response::from_json(&stats)
+            })
+            .post_async("/adjust", async |mut req, ctx| {
+                let req_data: AdminAdjustmentReq = serde_json::from_str(&req.text().await?)?;
+                let this = ctx.data;
+                match this.adjust_balance(req_data).await {
+                    Ok(res) => Response::from_json(&res),
+                    Err((code, msg)) => worker_err_to_resp(code, msg),
+                }
+            })
+            .post_async("/adjustments", async |mut req, ctx| {
+                let req_data: PaginatedAdjustmentsReq = req.json().await?;
+                let this = ctx.data;
+                let res = this
+                    .paginated_adjustments_with_cursor(req_data.page_size, req_data.cursor)
+                    .await?;
+
+                Response::from_json(&res)
+            })
+            .get_async("/treasury", async |_, ctx| {
+                let this = ctx.data;
+                let mut storage = this.storage();
+                let daily_limit = this.daily_treasury_limit();
+                let info = this
+                    .treasury_amount
+                    .info(&mut storage, &daily_limit)
+                    .await?;
+
+                Response::from_json(&info)
+            })
             .post_async("/withdraw", async |mut req, ctx| {
-                let req_data: WithdrawRequest = serde_json::from_str(&req.text().await?)?;
+                let req_data: WithdrawRequestWithReplayKey =
+                    serde_json::from_str(&req.text().await?)?;
                 let this = ctx.data;
+                if let Some(err_res) = this.consume_replay_key(&req_data.replay_key).await? {
+                    return Ok(err_res);
+                }
+                let request = req_data.request;
                 let res = this
-                    .redeem_sats_for_ckbtc(req_data.receiver, req_data.amount.into())
+                    .redeem_sats_for_ckbtc(
+                        request.receiver,
+                        request.amount.into(),
+                        req_data.replay_key.idempotency_key(),
+                    )
                     .await;
                 if let Err(e) = res {
                     return worker_err_to_resp(e.0, e.1);
@@ -391,10 +1507,20 @@ impl DurableObject for UserHonGameState {
 
                 Response::ok("done")
             })
+            .post_async("/pool_settle", async |mut req, ctx| {
+                let req_data: PoolSettleReq = serde_json::from_str(&req.text().await?)?;
+                let this = ctx.data;
+                let res = this.settle_pool_payout(req_data).await;
+                if let Err(e) = res {
+                    return worker_err_to_resp(e.0, e.1);
+                }
+
+                Response::ok("done")
+            })
             .post_async("/creator_reward", async |mut req, ctx| {
-                let amount: u128 = serde_json::from_str(&req.text().await?)?;
+                let reward: CreatorRewardReq = serde_json::from_str(&req.text().await?)?;
                 let this = ctx.data;
-                let res = this.add_creator_reward(amount).await;
+                let res = this.add_creator_reward(reward).await;
                 if let Err(e) = res {
                     return worker_err_to_resp(e.0, e.1);
                 }
@@ -404,4 +1530,24 @@ impl DurableObject for UserHonGameState {
             .run(req, env)
             .await
     }
+
+    async fn alarm(&mut self) -> Result<Response> {
+        if let Err(e) = self.deliver_creator_rewards().await {
+            console_error!("failed to deliver creator rewards: {e}");
+            self.schedule_alarm(Date::now().as_millis() + ALARM_RETRY_MS)
+                .await?;
+        }
+        if let Err(e) = self.reconcile_withdrawals().await {
+            console_error!("failed to reconcile withdrawals: {e}");
+            self.schedule_alarm(Date::now().as_millis() + ALARM_RETRY_MS)
+                .await?;
+        }
+        if let Err(e) = self.reconcile_pool_joins().await {
+            console_error!("failed to reconcile pool joins: {e}");
+            self.schedule_alarm(Date::now().as_millis() + ALARM_RETRY_MS)
+                .await?;
+        }
+
+        Response::ok("done")
+    }
 }
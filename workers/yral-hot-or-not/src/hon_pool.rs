@@ -0,0 +1,607 @@
+use candid::Principal;
+use futures::StreamExt;
+use hon_worker_common::{GameInfo, GameResult, HotOrNot, VoteRes, WorkerError};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, result::Result as StdResult};
+use worker::*;
+use worker_utils::{
+    storage::{ListEntryError, Migration, SafeStorage, StorageCell, StorageMap, VersionedSchema},
+    RequestInitBuilder,
+};
+
+use crate::{
+    config::HonGameConfigCache,
+    creator_reward::CreatorRewardReq,
+    get_hon_game_stub_env,
+    leaderboard::{report_game_result, GameResultDelta},
+    sentiment::{SentimentSource, SentimentSourceImpl},
+    utils::worker_err_to_resp,
+};
+
+/// delay before a failed settlement or undelivered payouts are retried
+const RETRY_MS: u64 = 30 * 1000;
+const STORAGE_PAGE_SIZE: usize = 128;
+
+/// A vote waiting for its pooled round to settle, stored in the voter's durable object
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PooledVoteEntry {
+    pub vote_amount: u128,
+    pub direction: HotOrNot,
+    /// `None` until the pool has accepted the vote
+    pub settles_at_ms: Option<u64>,
+    /// ms since epoch, 0 for votes stored before this was tracked
+    #[serde(default)]
+    pub created_at_ms: u64,
+    /// `None` for votes stored before this was tracked
+    #[serde(default)]
+    pub voter: Option<Principal>,
+}
+
+impl VersionedSchema for PooledVoteEntry {
+    type Value = Self;
+
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum PendingGameInfo {
+    PooledVote {
+        vote_amount: u128,
+        direction: HotOrNot,
+        settles_at_ms: Option<u64>,
+    },
+}
+
+impl From<PooledVoteEntry> for PendingGameInfo {
+    fn from(entry: PooledVoteEntry) -> Self {
+        Self::PooledVote {
+            vote_amount: entry.vote_amount,
+            direction: entry.direction,
+            settles_at_ms: entry.settles_at_ms,
+        }
+    }
+}
+
+/// `/vote_v2` response, pooled votes are settled once their round closes
+#[derive(Serialize, Deserialize)]
+pub enum VoteResV2 {
+    Settled(VoteRes),
+    Pending(PendingGameInfo),
+}
+
+/// `/game_info` response, settled games serialize exactly like [`GameInfo`]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum GameInfoRes {
+    Settled(GameInfo),
+    Pending(PendingGameInfo),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PoolJoinReq {
+    pub voter: Principal,
+    pub post_canister: Principal,
+    pub post_id: u64,
+    pub post_creator: Option<Principal>,
+    pub vote_amount: u128,
+    pub direction: HotOrNot,
+    /// used if the vote opens a new round
+    pub window_ms: u64,
+    /// the sentiment the vote would settle against,
+    /// the round settles against the opening vote's while the sentiment API isn't configured
+    #[serde(default)]
+    pub sentiment: Option<HotOrNot>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PoolJoinRes {
+    pub round: u64,
+    pub settles_at_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PoolVoteStatusReq {
+    pub voter: Principal,
+}
+
+/// Where a voter's vote stands in the pool
+#[derive(Serialize, Deserialize, Clone)]
+pub enum PoolVoteStatus {
+    /// the vote is in the open round
+    Open(PoolJoinRes),
+    /// the vote's round settled, its payout is being delivered
+    Settling,
+    /// the pool never accepted the vote
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PoolVoteSettlement {
+    pub vote_amount: u128,
+    pub game_result: GameResult,
+    /// stake returned plus winnings
+    pub credit: u128,
+    /// nobody picked the outcome and the stake was returned, this is neither a win nor a loss
+    #[serde(default)]
+    pub refunded: bool,
+}
+
+/// What a participant receives when a round settles
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PoolPayout {
+    pub vote: Option<PoolVoteSettlement>,
+    pub creator_reward: u128,
+}
+
+/// Sent to the participant's durable object, applying it twice has no effect
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PoolSettleReq {
+    pub post_canister: Principal,
+    pub post_id: u64,
+    pub round: u64,
+    pub payout: PoolPayout,
+    /// ms since epoch, `None` for rounds settled before this was tracked
+    #[serde(default)]
+    pub settled_at_ms: Option<u64>,
+}
+
+impl PoolSettleReq {
+    /// dedup id for the creator reward
+    pub fn creator_reward_id(&self) -> String {
+        format!(
+            "pool-{}-{}-{}",
+            self.post_canister, self.post_id, self.round
+        )
+    }
+
+    /// the creator reward as a [`CreatorRewardReq`]
+    pub fn creator_reward(&self) -> CreatorRewardReq {
+        CreatorRewardReq {
+            id: self.creator_reward_id(),
+            amount: self.payout.creator_reward,
+            created_at_ms: self.settled_at_ms,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct PooledVote {
+    amount: u128,
+    direction: HotOrNot,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct PoolRound {
+    round: u64,
+    post: Option<(Principal, u64)>,
+    creator: Option<Principal>,
+    /// `None` while no round is open
+    settles_at_ms: Option<u64>,
+    /// sentiment of the opening vote, see [`PoolJoinReq::sentiment`]
+    #[serde(default)]
+    sentiment: Option<HotOrNot>,
+}
+
+struct PoolRewardIter {
+    outcome: HotOrNot,
+    winning_stake: u128,
+    reward_pool: u128,
+    remaining: u128,
+    creator: Option<Principal>,
+    creator_reward: u128,
+    inner: std::vec::IntoIter<(Principal, PooledVote)>,
+}
+
+impl Iterator for PoolRewardIter {
+    type Item = (Principal, PoolPayout);
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (start, end) = self.inner.size_hint();
+        let extra = self.creator.as_ref().map(|_| 1).unwrap_or_default();
+
+        (start + extra, end.map(|e| e + extra))
+    }
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some((voter, vote)) = self.inner.next() else {
+            let creator = self.creator.take()?;
+            // there may be something remaining due to rounding errors
+            return Some((
+                creator,
+                PoolPayout {
+                    vote: None,
+                    creator_reward: self.creator_reward + self.remaining,
+                },
+            ));
+        };
+
+        let refunded = self.winning_stake == 0;
+        let (game_result, credit) = if refunded {
+            // nobody picked the outcome, every stake is returned
+            (
+                GameResult::Win {
+                    win_amt: BigUint::from(0u32),
+                },
+                vote.amount,
+            )
+        } else if vote.direction == self.outcome {
+            // the voter's share of the losing stakes, proportional to their stake
+            let win_amt = (vote.amount * self.reward_pool) / self.winning_stake;
+            assert!(self.remaining >= win_amt);
+            self.remaining -= win_amt;
+            (
+                GameResult::Win {
+                    win_amt: BigUint::from(win_amt),
+                },
+                vote.amount + win_amt,
+            )
+        } else {
+            (
+                GameResult::Loss {
+                    lose_amt: BigUint::from(vote.amount),
+                },
+                0,
+            )
+        };
+
+        Some((
+            voter,
+            PoolPayout {
+                vote: Some(PoolVoteSettlement {
+                    vote_amount: vote.amount,
+                    game_result,
+                    credit,
+                    refunded,
+                }),
+                creator_reward: 0,
+            },
+        ))
+    }
+}
+
+impl PoolRewardIter {
+    pub fn new(
+        votes: Vec<(Principal, PooledVote)>,
+        outcome: HotOrNot,
+        creator: Option<Principal>,
+        creator_cut_percent: u32,
+    ) -> Self {
+        let (winning_stake, losing_stake) =
+            votes
+                .iter()
+                .fold((0u128, 0u128), |(winning, losing), (_, vote)| {
+                    if vote.direction == outcome {
+                        (winning + vote.amount, losing)
+                    } else {
+                        (winning, losing + vote.amount)
+                    }
+                });
+
+        // losing stakes fund the creator cut and the winners
+        let creator_reward = match (&creator, winning_stake) {
+            (None, _) | (_, 0) => 0,
+            _ => losing_stake * creator_cut_percent as u128 / 100,
+        };
+        let reward_pool = if winning_stake == 0 {
+            0
+        } else {
+            losing_stake - creator_reward
+        };
+
+        Self {
+            outcome,
+            winning_stake,
+            reward_pool,
+            remaining: reward_pool,
+            creator,
+            creator_reward,
+            inner: votes.into_iter(),
+        }
+    }
+}
+
+/// Votes on a single post, settled together when the round's window closes
+#[durable_object]
+pub struct HonPostPool {
+    state: State,
+    env: Env,
+    round: StorageCell<PoolRound>,
+    // `vote-{voter}`, votes in the open round
+    votes: StorageMap<Principal, PooledVote>,
+    // `payout-{round}-{participant}`, settled payouts waiting to be delivered
+    payouts: StorageMap<(u64, Principal), PoolSettleReq>,
+    config_cache: HonGameConfigCache,
+}
+
+impl HonPostPool {
+    fn storage(&self) -> SafeStorage {
+        self.state.storage().into()
+    }
+
+    /// schedule the alarm for `at_ms`, unless it is already scheduled earlier
+    async fn schedule_alarm(&self, at_ms: u64) -> Result<()> {
+        let mut storage = self.storage();
+        if let Some(alarm) = storage.get_alarm().await? {
+            if alarm <= at_ms as i64 {
+                return Ok(());
+            }
+        }
+        let offset = at_ms.saturating_sub(Date::now().as_millis());
+        storage.set_alarm(offset as i64).await
+    }
+
+    async fn join(&mut self, req: PoolJoinReq) -> StdResult<PoolJoinRes, (u16, WorkerError)> {
+        let mut storage = self.storage();
+        let existing = self
+            .votes
+            .get(&storage, &req.voter)
+            .await
+            .map_err(|_| (500, WorkerError::Internal("failed to get vote".into())))?;
+        if existing.is_some() {
+            return Err((400, WorkerError::AlreadyVotedOnPost));
+        }
+
+        let now = Date::now().as_millis();
+        let mut txn = storage.transaction();
+        let mut res = None::<PoolJoinRes>;
+        let mut opened = false;
+        self.round
+            .update_txn(&mut txn, |round| {
+                if round.settles_at_ms.is_none() {
+                    round.settles_at_ms = Some(now + req.window_ms);
+                    round.post = Some((req.post_canister, req.post_id));
+                    round.creator = req.post_creator;
+                    round.sentiment = req.sentiment.clone();
+                    opened = true;
+                }
+                res = round.settles_at_ms.map(|settles_at_ms| PoolJoinRes {
+                    round: round.round,
+                    settles_at_ms,
+                });
+            })
+            .await
+            .map_err(|_| (500, WorkerError::Internal("failed to update round".into())))?;
+        self.votes
+            .insert_txn(
+                &mut txn,
+                &req.voter,
+                &PooledVote {
+                    amount: req.vote_amount,
+                    direction: req.direction,
+                },
+            )
+            .map_err(|_| (500, WorkerError::Internal("failed to store vote".into())))?;
+        txn.commit()
+            .await
+            .map_err(|_| (500, WorkerError::Internal("failed to store vote".into())))?;
+
+        let res = res.expect("round should be open");
+        if opened {
+            self.schedule_alarm(res.settles_at_ms).await.map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to schedule round".into()),
+                )
+            })?;
+        }
+
+        Ok(res)
+    }
+
+    async fn all_votes(&self) -> Result<Vec<(Principal, PooledVote)>> {
+        let storage = self.storage();
+        let mut votes = vec![];
+        let mut entries = self.votes.stream(&storage, STORAGE_PAGE_SIZE);
+        while let Some(entry) = entries.next().await {
+            match entry {
+                Ok(vote) => votes.push(vote),
+                Err(ListEntryError::Storage(e)) => return Err(Error::RustError(e)),
+                Err(e) => console_error!("skipping vote: {e}"),
+            }
+        }
+
+        Ok(votes)
+    }
+
+    /// close the open round and stage the payouts for delivery
+    async fn settle_round(&mut self, round: PoolRound) -> Result<()> {
+        let Some((post_canister, post_id)) = round.post else {
+            return Err(Error::RustError("open round without a post".into()));
+        };
+        let votes = self.all_votes().await?;
+        let outcome = SentimentSourceImpl::new(&self.env, round.sentiment.clone())?
+            .post_sentiment(post_canister, post_id)
+            .await?;
+        let config = self.config_cache.get(&self.env).await;
+        let rewards =
+            PoolRewardIter::new(votes, outcome, round.creator, config.creator_cut_percent);
+
+        // a creator who also voted gets a single payout with both
+        let mut payouts = HashMap::<Principal, PoolPayout>::new();
+        for (participant, payout) in rewards {
+            let merged = payouts.entry(participant).or_default();
+            merged.creator_reward += payout.creator_reward;
+            if payout.vote.is_some() {
+                merged.vote = payout.vote;
+            }
+        }
+
+        let now = Date::now().as_millis();
+        let mut storage = self.storage();
+        let mut txn = storage.transaction();
+        let mut deltas = vec![];
+        for (participant, payout) in payouts {
+            if let Some(vote) = payout.vote.as_ref().filter(|vote| !vote.refunded) {
+                deltas.push(GameResultDelta::new(participant, &vote.game_result));
+            }
+            self.payouts.insert_txn(
+                &mut txn,
+                &(round.round, participant),
+                &PoolSettleReq {
+                    post_canister,
+                    post_id,
+                    round: round.round,
+                    payout,
+                    settled_at_ms: Some(now),
+                },
+            )?;
+        }
+        self.votes.clear_txn(&mut txn).await?;
+        self.round
+            .update_txn(&mut txn, |round| {
+                round.round += 1;
+                round.creator = None;
+                round.settles_at_ms = None;
+                round.sentiment = None;
+            })
+            .await?;
+        txn.commit().await?;
+
+        for delta in deltas {
+            report_game_result(&self.env, delta);
+        }
+
+        Ok(())
+    }
+
+    async fn vote_status(&mut self, voter: Principal) -> Result<PoolVoteStatus> {
+        let storage = self.storage();
+        if self.votes.get(&storage, &voter).await?.is_some() {
+            let round = self.round.read(&storage).await?;
+            if let Some(settles_at_ms) = round.settles_at_ms {
+                return Ok(PoolVoteStatus::Open(PoolJoinRes {
+                    round: round.round,
+                    settles_at_ms,
+                }));
+            }
+        }
+
+        let mut entries = self.payouts.stream(&storage, STORAGE_PAGE_SIZE);
+        while let Some(entry) = entries.next().await {
+            match entry {
+                Ok(((_, participant), req))
+                    if participant == voter && req.payout.vote.is_some() =>
+                {
+                    return Ok(PoolVoteStatus::Settling);
+                }
+                Ok(_) => (),
+                Err(ListEntryError::Storage(e)) => return Err(Error::RustError(e)),
+                Err(e) => console_error!("skipping payout: {e}"),
+            }
+        }
+
+        Ok(PoolVoteStatus::Unknown)
+    }
+
+    async fn send_payout(&self, participant: Principal, req: &PoolSettleReq) -> Result<()> {
+        let game_stub = get_hon_game_stub_env(&self.env, participant)?;
+        let req = Request::new_with_init(
+            "http://fake_url.com/pool_settle",
+            RequestInitBuilder::default()
+                .method(Method::Post)
+                .json(req)?
+                .build(),
+        )?;
+        let mut res = game_stub.fetch_with_request(req).await?;
+        if !(200..300).contains(&res.status_code()) {
+            return Err(Error::RustError(format!(
+                "payout rejected with {}: {}",
+                res.status_code(),
+                res.text().await?
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// deliver staged payouts, failed deliveries are retried from the alarm
+    async fn deliver_payouts(&mut self) -> Result<()> {
+        let mut storage = self.storage();
+        let mut payouts = vec![];
+        let mut entries = self.payouts.stream(&storage, STORAGE_PAGE_SIZE);
+        while let Some(entry) = entries.next().await {
+            match entry {
+                Ok(payout) => payouts.push(payout),
+                Err(ListEntryError::Storage(e)) => return Err(Error::RustError(e)),
+                Err(e) => console_error!("skipping payout: {e}"),
+            }
+        }
+        drop(entries);
+
+        let mut failed = false;
+        for ((round, participant), req) in payouts {
+            if let Err(e) = self.send_payout(participant, &req).await {
+                console_error!("failed to deliver payout to {participant}: {e}");
+                failed = true;
+                continue;
+            }
+            self.payouts
+                .remove(&mut storage, &(round, participant))
+                .await?;
+        }
+
+        if failed {
+            self.schedule_alarm(Date::now().as_millis() + RETRY_MS)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[durable_object]
+impl DurableObject for HonPostPool {
+    fn new(state: State, env: Env) -> Self {
+        console_error_panic_hook::set_once();
+
+        Self {
+            state,
+            env,
+            round: StorageCell::new("round", PoolRound::default),
+            votes: StorageMap::new("vote-"),
+            payouts: StorageMap::new("payout-"),
+            config_cache: HonGameConfigCache::default(),
+        }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let env = self.env.clone();
+        let router = Router::with_data(self);
+        router
+            .post_async("/join", async |mut req, ctx| {
+                let req_data: PoolJoinReq = req.json().await?;
+                let this = ctx.data;
+                match this.join(req_data).await {
+                    Ok(res) => Response::from_json(&res),
+                    Err((code, msg)) => worker_err_to_resp(code, msg),
+                }
+            })
+            .post_async("/vote_status", async |mut req, ctx| {
+                let req_data: PoolVoteStatusReq = req.json().await?;
+                let this = ctx.data;
+                let status = this.vote_status(req_data.voter).await?;
+                Response::from_json(&status)
+            })
+            .run(req, env)
+            .await
+    }
+
+    async fn alarm(&mut self) -> Result<Response> {
+        let storage = self.storage();
+        let round = self.round.read(&storage).await?.clone();
+        if let Some(settles_at_ms) = round.settles_at_ms {
+            let now = Date::now().as_millis();
+            if settles_at_ms > now {
+                self.schedule_alarm(settles_at_ms).await?;
+            } else if let Err(e) = self.settle_round(round).await {
+                console_error!("failed to settle round: {e}");
+                self.schedule_alarm(now + RETRY_MS).await?;
+            }
+        }
+
+        self.deliver_payouts().await?;
+
+        Response::ok("done")
+    }
+}
@@ -1,4 +1,19 @@
+use worker::Env;
+use worker_utils::jwt::JwtKeySet;
+
 pub const JWT_PUBKEY: &str = "-----BEGIN PUBLIC KEY-----
 MCowBQYDK2VwAyEAn4Vbu7ZX4fDX3SNCiDYMoOs4KITJP1h2dw+MBnu6pPw=
 -----END PUBLIC KEY-----";
 pub const JWT_AUD: &str = "hot-or-not-worker";
+
+pub const SCOPE_VOTE: &str = "hon:vote";
+pub const SCOPE_WITHDRAW: &str = "hon:withdraw";
+/// admin, grant airdrops
+pub const SCOPE_AIRDROP: &str = "hon:airdrop";
+/// admin, adjust balances and read the adjustment log
+pub const SCOPE_ADJUST_BALANCE: &str = "hon:adjust_balance";
+
+/// keys accepted for worker JWTs, `JWT_PUBKEY` verifies tokens minted without a `kid`
+pub fn jwt_keys(env: &Env) -> worker::Result<&'static JwtKeySet> {
+    worker_utils::jwt::jwt_keys(env, JWT_PUBKEY)
+}
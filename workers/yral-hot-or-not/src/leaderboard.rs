@@ -0,0 +1,466 @@
+use std::collections::BTreeSet;
+
+use candid::Principal;
+use hon_worker_common::GameResult;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen_futures::spawn_local;
+use worker::*;
+use worker_utils::{
+    storage::{SafeStorage, StorageCell, StorageMap, StorageTransaction},
+    RequestInitBuilder,
+};
+
+const DAY_MS: u64 = 24 * 3600 * 1000;
+/// the unix epoch was a thursday, weeks start on monday
+const WEEK_OFFSET_DAYS: u64 = 3;
+pub const MAX_LEADERBOARD_SIZE: usize = 100;
+/// players need this many games to be ranked by win rate
+const MIN_GAMES_FOR_WIN_RATE: u64 = 10;
+/// entries deleted from each index of an ended board per transaction
+const ROLLOVER_BATCH_SIZE: usize = 40;
+/// transactions per alarm run, the rest of the ended boards are cleared on the next run
+const ROLLOVER_MAX_BATCHES: usize = 16;
+/// delay before continuing an unfinished rollover
+const ROLLOVER_CONTINUE_MS: i64 = 1000;
+
+/// Outcome of a single game, sent to the leaderboard by the player's durable object
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GameResultDelta {
+    pub user: Principal,
+    /// sats won, negative for a loss
+    pub net_sats: i64,
+    pub won: bool,
+}
+
+impl GameResultDelta {
+    pub fn new(user: Principal, game_result: &GameResult) -> Self {
+        let (net_sats, won) = match game_result {
+            GameResult::Win { win_amt } => (i64::try_from(win_amt).unwrap_or(i64::MAX), true),
+            GameResult::Loss { lose_amt } => (-i64::try_from(lose_amt).unwrap_or(i64::MAX), false),
+        };
+
+        Self {
+            user,
+            net_sats,
+            won,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderboardPeriod {
+    Daily,
+    Weekly,
+    #[default]
+    AllTime,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LeaderboardQuery {
+    #[serde(default)]
+    pub period: LeaderboardPeriod,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LeaderboardEntry {
+    pub rank: u32,
+    pub user: Principal,
+    pub net_sats: i64,
+    pub wins: u64,
+    pub losses: u64,
+    /// basis points
+    pub win_rate_bps: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LeaderboardRes {
+    pub period: LeaderboardPeriod,
+    pub by_net_sats: Vec<LeaderboardEntry>,
+    pub by_win_rate: Vec<LeaderboardEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct PlayerStats {
+    net_sats: i64,
+    wins: u64,
+    losses: u64,
+}
+
+impl PlayerStats {
+    fn games(&self) -> u64 {
+        self.wins + self.losses
+    }
+
+    fn win_rate_bps(&self) -> u64 {
+        if self.games() == 0 {
+            return 0;
+        }
+        self.wins * 10_000 / self.games()
+    }
+
+    /// index key, higher net sats sort first
+    fn net_sats_key(&self) -> u64 {
+        let ordered = (self.net_sats as u64) ^ (1 << 63);
+        u64::MAX - ordered
+    }
+
+    /// index key, higher win rates sort first
+    fn win_rate_key(&self) -> Option<u64> {
+        (self.games() >= MIN_GAMES_FOR_WIN_RATE).then(|| u64::MAX - self.win_rate_bps())
+    }
+
+    fn apply(&mut self, delta: &GameResultDelta) {
+        self.net_sats = self.net_sats.saturating_add(delta.net_sats);
+        if delta.won {
+            self.wins += 1;
+        } else {
+            self.losses += 1;
+        }
+    }
+}
+
+fn current_day(now_ms: u64) -> u64 {
+    now_ms / DAY_MS
+}
+
+fn current_week(now_ms: u64) -> u64 {
+    (current_day(now_ms) + WEEK_OFFSET_DAYS) / 7
+}
+
+/// A board for one period
+///
+/// `{prefix}stats-{user}` holds the player's totals,
+/// `{prefix}net-{key}-{user}` and `{prefix}rate-{key}-{user}` index them by score
+struct Board {
+    stats: StorageMap<Principal, PlayerStats>,
+    by_net_sats: StorageMap<(u64, Principal), ()>,
+    by_win_rate: StorageMap<(u64, Principal), ()>,
+}
+
+impl Board {
+    fn new(prefix: String) -> Self {
+        Self {
+            stats: StorageMap::new(format!("{prefix}stats-")),
+            by_net_sats: StorageMap::new(format!("{prefix}net-")),
+            by_win_rate: StorageMap::new(format!("{prefix}rate-")),
+        }
+    }
+
+    fn for_period(period: LeaderboardPeriod, now_ms: u64) -> Self {
+        match period {
+            LeaderboardPeriod::Daily => Self::daily(current_day(now_ms)),
+            LeaderboardPeriod::Weekly => Self::weekly(current_week(now_ms)),
+            LeaderboardPeriod::AllTime => Self::new("all-time-".into()),
+        }
+    }
+
+    fn daily(day: u64) -> Self {
+        Self::new(format!("daily-{day}-"))
+    }
+
+    fn weekly(week: u64) -> Self {
+        Self::new(format!("weekly-{week}-"))
+    }
+
+    async fn player(&mut self, storage: &SafeStorage, user: Principal) -> Result<PlayerStats> {
+        let stats = self.stats.get(storage, &user).await?;
+
+        Ok(stats.cloned().unwrap_or_default())
+    }
+
+    /// stage the player's new totals, `old` must be the currently stored totals
+    fn stage(
+        &mut self,
+        txn: &mut StorageTransaction<'_>,
+        user: Principal,
+        old: &PlayerStats,
+        new: &PlayerStats,
+    ) -> Result<()> {
+        if old.games() > 0 {
+            self.by_net_sats
+                .remove_txn(txn, &(old.net_sats_key(), user));
+        }
+        if let Some(key) = old.win_rate_key() {
+            self.by_win_rate.remove_txn(txn, &(key, user));
+        }
+        self.by_net_sats
+            .insert_txn(txn, &(new.net_sats_key(), user), &())?;
+        if let Some(key) = new.win_rate_key() {
+            self.by_win_rate.insert_txn(txn, &(key, user), &())?;
+        }
+
+        self.stats.insert_txn(txn, &user, new)
+    }
+
+    /// delete up to `limit` entries from each map, returns `true` once the board is empty
+    async fn clear_batch(&mut self, storage: &mut SafeStorage, limit: usize) -> Result<bool> {
+        let stats = self.stats.page(storage, None, limit).await?;
+        let by_net_sats = self.by_net_sats.page(storage, None, limit).await?;
+        let by_win_rate = self.by_win_rate.page(storage, None, limit).await?;
+        let cleared = stats.len() < limit && by_net_sats.len() < limit && by_win_rate.len() < limit;
+
+        let mut txn = storage.transaction();
+        for (user, _) in stats {
+            self.stats.remove_txn(&mut txn, &user);
+        }
+        for (key, _) in by_net_sats {
+            self.by_net_sats.remove_txn(&mut txn, &key);
+        }
+        for (key, _) in by_win_rate {
+            self.by_win_rate.remove_txn(&mut txn, &key);
+        }
+        txn.commit().await?;
+
+        Ok(cleared)
+    }
+
+    /// clear the board using at most `ROLLOVER_MAX_BATCHES - batches` transactions,
+    /// returns `true` once the board is empty
+    async fn clear(&mut self, storage: &mut SafeStorage, batches: &mut usize) -> Result<bool> {
+        while *batches < ROLLOVER_MAX_BATCHES {
+            *batches += 1;
+            if self.clear_batch(storage, ROLLOVER_BATCH_SIZE).await? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    async fn ranked(
+        &mut self,
+        storage: &SafeStorage,
+        index: Vec<((u64, Principal), ())>,
+    ) -> Result<Vec<LeaderboardEntry>> {
+        let mut entries = Vec::with_capacity(index.len());
+        for (rank, ((_, user), _)) in index.into_iter().enumerate() {
+            let stats = self.player(storage, user).await?;
+            entries.push(LeaderboardEntry {
+                rank: rank as u32 + 1,
+                user,
+                net_sats: stats.net_sats,
+                wins: stats.wins,
+                losses: stats.losses,
+                win_rate_bps: stats.win_rate_bps(),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    async fn top(
+        &mut self,
+        storage: &SafeStorage,
+        limit: usize,
+    ) -> Result<[Vec<LeaderboardEntry>; 2]> {
+        let by_net_sats = self.by_net_sats.page(storage, None, limit).await?;
+        let by_win_rate = self.by_win_rate.page(storage, None, limit).await?;
+
+        Ok([
+            self.ranked(storage, by_net_sats).await?,
+            self.ranked(storage, by_win_rate).await?,
+        ])
+    }
+}
+
+/// daily and weekly boards that have been written to, ended ones are dropped on rollover
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct ActiveBoards {
+    days: BTreeSet<u64>,
+    weeks: BTreeSet<u64>,
+}
+
+/// Daily, weekly and all-time rankings across all players
+#[durable_object]
+pub struct HonLeaderboard {
+    state: State,
+    env: Env,
+    active: StorageCell<ActiveBoards>,
+}
+
+impl HonLeaderboard {
+    fn storage(&self) -> SafeStorage {
+        self.state.storage().into()
+    }
+
+    async fn apply_delta(&mut self, delta: GameResultDelta) -> Result<()> {
+        let now = Date::now().as_millis();
+        let mut storage = self.storage();
+        let mut boards = [
+            Board::for_period(LeaderboardPeriod::Daily, now),
+            Board::for_period(LeaderboardPeriod::Weekly, now),
+            Board::for_period(LeaderboardPeriod::AllTime, now),
+        ];
+        let mut players = Vec::with_capacity(boards.len());
+        for board in boards.iter_mut() {
+            players.push(board.player(&storage, delta.user).await?);
+        }
+
+        let (day, week) = (current_day(now), current_week(now));
+        let active = self.active.read(&storage).await?;
+        let is_new_board = !active.days.contains(&day) || !active.weeks.contains(&week);
+
+        let mut txn = storage.transaction();
+        for (board, old) in boards.iter_mut().zip(players) {
+            let mut new = old.clone();
+            new.apply(&delta);
+            board.stage(&mut txn, delta.user, &old, &new)?;
+        }
+        if is_new_board {
+            self.active
+                .update_txn(&mut txn, |active| {
+                    active.days.insert(day);
+                    active.weeks.insert(week);
+                })
+                .await?;
+        }
+        txn.commit().await?;
+
+        self.schedule_rollover().await
+    }
+
+    async fn leaderboard(&mut self, query: LeaderboardQuery) -> Result<LeaderboardRes> {
+        let limit = query
+            .limit
+            .unwrap_or(MAX_LEADERBOARD_SIZE)
+            .clamp(1, MAX_LEADERBOARD_SIZE);
+        let storage = self.storage();
+        let mut board = Board::for_period(query.period, Date::now().as_millis());
+        let [by_net_sats, by_win_rate] = board.top(&storage, limit).await?;
+
+        Ok(LeaderboardRes {
+            period: query.period,
+            by_net_sats,
+            by_win_rate,
+        })
+    }
+
+    /// schedule the rollover for the next day boundary, unless one is already scheduled
+    async fn schedule_rollover(&self) -> Result<()> {
+        let mut storage = self.storage();
+        if storage.get_alarm().await?.is_some() {
+            return Ok(());
+        }
+        let now = Date::now().as_millis();
+        let next_day = (current_day(now) + 1) * DAY_MS;
+        storage.set_alarm((next_day - now) as i64).await
+    }
+
+    /// drop the boards of periods that have ended, a few batches per run
+    ///
+    /// returns `true` if ended boards remain
+    async fn rollover(&mut self) -> Result<bool> {
+        let now = Date::now().as_millis();
+        let (day, week) = (current_day(now), current_week(now));
+        let mut storage = self.storage();
+        let active = self.active.read(&storage).await?.clone();
+
+        let mut batches = 0;
+        for ended in active.days.into_iter().filter(|d| *d != day) {
+            if !Board::daily(ended)
+                .clear(&mut storage, &mut batches)
+                .await?
+            {
+                return Ok(true);
+            }
+            self.active
+                .update(&mut storage, |active| {
+                    active.days.remove(&ended);
+                })
+                .await?;
+        }
+        for ended in active.weeks.into_iter().filter(|w| *w != week) {
+            if !Board::weekly(ended)
+                .clear(&mut storage, &mut batches)
+                .await?
+            {
+                return Ok(true);
+            }
+            self.active
+                .update(&mut storage, |active| {
+                    active.weeks.remove(&ended);
+                })
+                .await?;
+        }
+
+        Ok(false)
+    }
+}
+
+#[durable_object]
+impl DurableObject for HonLeaderboard {
+    fn new(state: State, env: Env) -> Self {
+        console_error_panic_hook::set_once();
+
+        Self {
+            state,
+            env,
+            active: StorageCell::new("active-boards", ActiveBoards::default),
+        }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let env = self.env.clone();
+        let router = Router::with_data(self);
+        router
+            .post_async("/game_result", async |mut req, ctx| {
+                let delta: GameResultDelta = req.json().await?;
+                let this = ctx.data;
+                this.apply_delta(delta).await?;
+
+                Response::ok("done")
+            })
+            .get_async("/leaderboard", async |req, ctx| {
+                let query: LeaderboardQuery = req.query()?;
+                let this = ctx.data;
+                let res = this.leaderboard(query).await?;
+
+                Response::from_json(&res)
+            })
+            .run(req, env)
+            .await
+    }
+
+    async fn alarm(&mut self) -> Result<Response> {
+        if self.rollover().await? {
+            self.storage().set_alarm(ROLLOVER_CONTINUE_MS).await?;
+        } else {
+            self.schedule_rollover().await?;
+        }
+
+        Response::ok("done")
+    }
+}
+
+pub fn leaderboard_stub(env: &Env) -> Result<Stub> {
+    let ns = env.durable_object("HON_LEADERBOARD")?;
+    let obj = ns.id_from_name("global")?;
+
+    obj.get_stub()
+}
+
+/// report a game result to the leaderboard in the background, failures are only logged
+///
+/// the caller doesn't wait for the leaderboard, so a slow leaderboard can't hold up games
+pub fn report_game_result(env: &Env, delta: GameResultDelta) {
+    let env = env.clone();
+    spawn_local(async move {
+        let res: Result<Response> = async {
+            let req = Request::new_with_init(
+                "http://fake_url.com/game_result",
+                RequestInitBuilder::default()
+                    .method(Method::Post)
+                    .json(&delta)?
+                    .build(),
+            )?;
+            leaderboard_stub(&env)?.fetch_with_request(req).await
+        }
+        .await;
+        if let Err(e) = res {
+            console_error!("failed to report game result to leaderboard: {e}");
+        }
+    });
+}
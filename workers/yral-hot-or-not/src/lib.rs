@@ -1,21 +1,45 @@
+mod adjustment;
+mod config;
 mod consts;
+mod creator_reward;
 mod hon_game;
+mod hon_pool;
 mod jwt;
+mod leaderboard;
+mod sentiment;
+mod stats;
 mod treasury;
 mod treasury_obj;
 mod utils;
+mod vote_ledger;
+mod withdrawal;
 
+use adjustment::{
+    AdjustmentReason, AdminAdjustmentReq, AirdropReq, BalanceAdjustmentReq, PaginatedAdjustmentsReq,
+};
 use candid::Principal;
-use hon_game::VoteRequestWithSentiment;
+use consts::{HON_GAME_VOTE_NONCED, HON_GAME_WITHDRAW_NONCED};
+use hon_game::{VoteRequestWithSentiment, WithdrawRequestWithReplayKey};
 use hon_worker_common::{
     hon_game_vote_msg, hon_game_withdraw_msg, GameInfoReq, HoNGameVoteReq, HoNGameWithdrawReq,
     PaginatedGamesReq, WorkerError,
 };
-use jwt::{JWT_AUD, JWT_PUBKEY};
+use jwt::{jwt_keys, JWT_AUD, SCOPE_ADJUST_BALANCE, SCOPE_AIRDROP, SCOPE_VOTE, SCOPE_WITHDRAW};
+use leaderboard::{leaderboard_stub, LeaderboardQuery};
+use sentiment::{SentimentSource, SentimentSourceImpl};
 use std::result::Result as StdResult;
 use utils::worker_err_to_resp;
+use withdrawal::PaginatedWithdrawalsReq;
 use worker::*;
-use worker_utils::{jwt::verify_jwt_from_header, parse_principal, RequestInitBuilder};
+use worker_utils::{
+    jwt::{handle_revocation_req, verify_jwt_scope_from_header, RevocationList},
+    parse_principal,
+    signed::{
+        nonced_message, unnonced_cutoff_from_env, MaybeNonced, SignatureError, SignedRequest,
+    },
+    RequestInitBuilder,
+};
+use yral_identity::msg_builder::Message;
 
 fn cors_policy() -> Cors {
     Cors::new()
@@ -25,18 +49,16 @@ fn cors_policy() -> Cors {
         .with_max_age(86400)
 }
 
-fn verify_hon_game_req(
-    sender: Principal,
-    req: &HoNGameVoteReq,
-) -> StdResult<(), (u16, WorkerError)> {
-    let msg = hon_game_vote_msg(req.request.clone());
-
-    req.signature
-        .clone()
-        .verify_identity(sender, msg)
-        .map_err(|_| (401, WorkerError::InvalidSignature))?;
-
-    Ok(())
+/// signed message of a nonced vote, `hon_game_vote_msg` for votes signed without a nonce
+fn hon_game_req_msg(req: &MaybeNonced<HoNGameVoteReq>) -> StdResult<Message, SignatureError> {
+    match req {
+        MaybeNonced::Nonced(req) => nonced_message(
+            HON_GAME_VOTE_NONCED,
+            req.request.request.clone(),
+            &req.nonce,
+        ),
+        MaybeNonced::Unnonced(req) => Ok(hon_game_vote_msg(req.request.clone())),
+    }
 }
 
 fn get_hon_game_stub<T>(ctx: &RouteContext<T>, user_principal: Principal) -> Result<Stub> {
@@ -55,28 +77,76 @@ fn get_hon_game_stub_env(env: &Env, user_principal: Principal) -> Result<Stub> {
     Ok(game_stub)
 }
 
-async fn place_hot_or_not_vote(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    if let Err((msg, code)) = verify_jwt_from_header(JWT_PUBKEY, JWT_AUD.into(), &req) {
+fn get_hon_post_pool_stub_env(env: &Env, post_canister: Principal, post_id: u64) -> Result<Stub> {
+    let pool_ns = env.durable_object("HON_POST_POOL")?;
+    let pool_obj = pool_ns.id_from_name(&format!("{post_canister}-{post_id}"))?;
+    let pool_stub = pool_obj.get_stub()?;
+
+    Ok(pool_stub)
+}
+
+/// `vote_path` is the game state route handling the vote
+async fn place_hot_or_not_vote(
+    mut req: Request,
+    ctx: RouteContext<()>,
+    vote_path: &str,
+) -> Result<Response> {
+    let keys = jwt_keys(&ctx.env)?;
+    let revocations = RevocationList::from_env(&ctx.env);
+    if let Err((msg, code)) =
+        verify_jwt_scope_from_header(keys, &revocations, JWT_AUD.into(), SCOPE_VOTE, &req).await
+    {
         return Response::error(msg, code);
     };
 
     let user_principal = parse_principal!(ctx, "user_principal");
+    let unnonced_cutoff = unnonced_cutoff_from_env(&ctx.env);
+
+    let (req, replay_key) = match SignedRequest::from_json_body(
+        &mut req,
+        |req: MaybeNonced<HoNGameVoteReq>| {
+            let signature = req.request().signature.clone();
+            let nonce = req.nonce().cloned();
+            SignedRequest::new(user_principal, signature, req)
+                .maybe_nonce(nonce.as_ref())
+                .unnonced_cutoff(unnonced_cutoff)
+        },
+        hon_game_req_msg,
+    )
+    .await
+    {
+        Ok(verified) => verified.payload.into_replay_parts(|req| &req.signature),
+        Err(e) => return e.to_response(),
+    };
 
-    let req: HoNGameVoteReq = serde_json::from_str(&req.text().await?)?;
-    if let Err((code, err)) = verify_hon_game_req(user_principal, &req) {
-        return worker_err_to_resp(code, err);
+    // the vote is settled against the server's sentiment,
+    // `fetched_sentiment` is only used while the sentiment API isn't configured
+    let sentiment = match SentimentSourceImpl::new(&ctx.env, Some(req.fetched_sentiment.clone()))?
+        .post_sentiment(req.request.post_canister, req.request.post_id)
+        .await
+    {
+        Ok(sentiment) => sentiment,
+        Err(e) => {
+            console_error!("failed to fetch post sentiment: {e}");
+            return worker_err_to_resp(
+                503,
+                WorkerError::Internal("failed to fetch post sentiment".into()),
+            );
+        }
     };
 
     let game_stub = get_hon_game_stub(&ctx, user_principal)?;
 
     let req = VoteRequestWithSentiment {
+        voter: user_principal,
         request: req.request,
-        sentiment: req.fetched_sentiment,
+        sentiment,
         post_creator: req.post_creator,
+        replay_key,
     };
 
     let req = Request::new_with_init(
-        "http://fake_url.com/vote",
+        &format!("http://fake_url.com{vote_path}"),
         RequestInitBuilder::default()
             .method(Method::Post)
             .json(&req)?
@@ -140,33 +210,229 @@ async fn paginated_games(mut req: Request, ctx: RouteContext<()>) -> Result<Resp
     Ok(res)
 }
 
-fn verify_hon_withdraw_req(req: &HoNGameWithdrawReq) -> StdResult<(), (u16, WorkerError)> {
-    let msg = hon_game_withdraw_msg(&req.request);
+async fn paginated_withdrawals(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let user_principal = parse_principal!(ctx, "user_principal");
+
+    let game_stub = get_hon_game_stub(&ctx, user_principal)?;
+
+    let req_data: PaginatedWithdrawalsReq = req.json().await?;
+
+    let req = Request::new_with_init(
+        "http://fake_url.com/withdrawals",
+        RequestInitBuilder::default()
+            .method(Method::Post)
+            .json(&req_data)?
+            .build(),
+    )?;
+
+    let res = game_stub.fetch_with_request(req).await?;
+
+    Ok(res)
+}
+
+async fn user_stats(ctx: RouteContext<()>) -> Result<Response> {
+    let user_principal = parse_principal!(ctx, "user_principal");
 
-    req.signature
-        .clone()
-        .verify_identity(req.request.receiver, msg)
-        .map_err(|_| (401, WorkerError::InvalidSignature))?;
+    let game_stub = get_hon_game_stub(&ctx, user_principal)?;
 
-    Ok(())
+    let res = game_stub
+        .fetch_with_str("http://fake_url.com/stats")
+        .await?;
+
+    Ok(res)
+}
+
+async fn treasury_info(ctx: RouteContext<()>) -> Result<Response> {
+    let user_principal = parse_principal!(ctx, "user_principal");
+
+    let game_stub = get_hon_game_stub(&ctx, user_principal)?;
+
+    let res = game_stub
+        .fetch_with_str("http://fake_url.com/treasury")
+        .await?;
+
+    Ok(res)
+}
+
+fn signed_hon_withdraw_req(
+    req: MaybeNonced<HoNGameWithdrawReq>,
+    unnonced_cutoff: u64,
+) -> SignedRequest<MaybeNonced<HoNGameWithdrawReq>> {
+    let sender = req.request().request.receiver;
+    let signature = req.request().signature.clone();
+    let nonce = req.nonce().cloned();
+    SignedRequest::new(sender, signature, req)
+        .maybe_nonce(nonce.as_ref())
+        .unnonced_cutoff(unnonced_cutoff)
+}
+
+/// signed message of a nonced withdrawal,
+/// `hon_game_withdraw_msg` for withdrawals signed without a nonce
+fn hon_withdraw_req_msg(
+    req: &MaybeNonced<HoNGameWithdrawReq>,
+) -> StdResult<Message, SignatureError> {
+    match req {
+        MaybeNonced::Nonced(req) => {
+            nonced_message(HON_GAME_WITHDRAW_NONCED, &req.request.request, &req.nonce)
+        }
+        MaybeNonced::Unnonced(req) => Ok(hon_game_withdraw_msg(&req.request)),
+    }
 }
 
 async fn withdraw_sats(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    if let Err((msg, code)) = verify_jwt_from_header(JWT_PUBKEY, JWT_AUD.into(), &req) {
+    let keys = jwt_keys(&ctx.env)?;
+    let revocations = RevocationList::from_env(&ctx.env);
+    if let Err((msg, code)) =
+        verify_jwt_scope_from_header(keys, &revocations, JWT_AUD.into(), SCOPE_WITHDRAW, &req).await
+    {
         return Response::error(msg, code);
     };
-    let req: HoNGameWithdrawReq = serde_json::from_str(&req.text().await?)?;
-    if let Err(e) = verify_hon_withdraw_req(&req) {
-        return worker_err_to_resp(e.0, e.1);
-    }
+    let unnonced_cutoff = unnonced_cutoff_from_env(&ctx.env);
+    let (req, replay_key) = match SignedRequest::from_json_body(
+        &mut req,
+        |req| signed_hon_withdraw_req(req, unnonced_cutoff),
+        hon_withdraw_req_msg,
+    )
+    .await
+    {
+        Ok(verified) => verified.payload.into_replay_parts(|req| &req.signature),
+        Err(e) => return e.to_response(),
+    };
 
     let game_stub = get_hon_game_stub(&ctx, req.request.receiver)?;
 
+    let req = WithdrawRequestWithReplayKey {
+        request: req.request,
+        replay_key,
+    };
     let req = Request::new_with_init(
         "http://fake_url.com/withdraw",
         RequestInitBuilder::default()
             .method(Method::Post)
-            .json(&req.request)?
+            .json(&req)?
+            .build(),
+    )?;
+
+    let res = game_stub.fetch_with_request(req).await?;
+
+    Ok(res)
+}
+
+async fn leaderboard(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    if let Err(e) = req.query::<LeaderboardQuery>() {
+        return Response::error(format!("invalid query: {e}"), 400);
+    }
+    let query = req.url()?.query().unwrap_or_default().to_string();
+    let stub = leaderboard_stub(&ctx.env)?;
+
+    stub.fetch_with_str(&format!("http://fake_url.com/leaderboard?{query}"))
+        .await
+}
+
+async fn forward_adjustment(
+    ctx: &RouteContext<()>,
+    user_principal: Principal,
+    req: AdminAdjustmentReq,
+) -> Result<Response> {
+    let game_stub = get_hon_game_stub(ctx, user_principal)?;
+    let req = Request::new_with_init(
+        "http://fake_url.com/adjust",
+        RequestInitBuilder::default()
+            .method(Method::Post)
+            .json(&req)?
+            .build(),
+    )?;
+
+    game_stub.fetch_with_request(req).await
+}
+
+async fn admin_airdrop(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let keys = jwt_keys(&ctx.env)?;
+    let revocations = RevocationList::from_env(&ctx.env);
+    let claims =
+        match verify_jwt_scope_from_header(keys, &revocations, JWT_AUD.into(), SCOPE_AIRDROP, &req)
+            .await
+        {
+            Ok(claims) => claims,
+            Err((msg, code)) => return Response::error(msg, code),
+        };
+    // adjustments are attributed to the admin in the audit log
+    let Some(admin) = claims.sub else {
+        return Response::error("admin token without sub", 403);
+    };
+    let user_principal = parse_principal!(ctx, "user_principal");
+    let req_data: AirdropReq = req.json().await?;
+    let Ok(delta) = i128::try_from(req_data.amount) else {
+        return Response::error("invalid amount", 400);
+    };
+
+    let req = AdminAdjustmentReq {
+        admin,
+        adjustment: BalanceAdjustmentReq {
+            delta,
+            reason: AdjustmentReason::Airdrop,
+            note: None,
+            idempotency_key: req_data.idempotency_key,
+        },
+    };
+    forward_adjustment(&ctx, user_principal, req).await
+}
+
+async fn admin_adjust_balance(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let keys = jwt_keys(&ctx.env)?;
+    let revocations = RevocationList::from_env(&ctx.env);
+    let claims = match verify_jwt_scope_from_header(
+        keys,
+        &revocations,
+        JWT_AUD.into(),
+        SCOPE_ADJUST_BALANCE,
+        &req,
+    )
+    .await
+    {
+        Ok(claims) => claims,
+        Err((msg, code)) => return Response::error(msg, code),
+    };
+    // adjustments are attributed to the admin in the audit log
+    let Some(admin) = claims.sub else {
+        return Response::error("admin token without sub", 403);
+    };
+    let user_principal = parse_principal!(ctx, "user_principal");
+    let adjustment: BalanceAdjustmentReq = req.json().await?;
+
+    forward_adjustment(
+        &ctx,
+        user_principal,
+        AdminAdjustmentReq { admin, adjustment },
+    )
+    .await
+}
+
+async fn admin_adjustments(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let keys = jwt_keys(&ctx.env)?;
+    let revocations = RevocationList::from_env(&ctx.env);
+    if let Err((msg, code)) = verify_jwt_scope_from_header(
+        keys,
+        &revocations,
+        JWT_AUD.into(),
+        SCOPE_ADJUST_BALANCE,
+        &req,
+    )
+    .await
+    {
+        return Response::error(msg, code);
+    };
+    let user_principal = parse_principal!(ctx, "user_principal");
+
+    let game_stub = get_hon_game_stub(&ctx, user_principal)?;
+
+    let req_data: PaginatedAdjustmentsReq = req.json().await?;
+
+    let req = Request::new_with_init(
+        "http://fake_url.com/adjustments",
+        RequestInitBuilder::default()
+            .method(Method::Post)
+            .json(&req_data)?
             .build(),
     )?;
 
@@ -175,6 +441,11 @@ async fn withdraw_sats(mut req: Request, ctx: RouteContext<()>) -> Result<Respon
     Ok(res)
 }
 
+async fn revocations_admin(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let keys = jwt_keys(&ctx.env)?;
+    handle_revocation_req(req, &ctx.env, keys, JWT_AUD.into()).await
+}
+
 #[event(fetch)]
 async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
     console_error_panic_hook::set_once();
@@ -190,9 +461,23 @@ async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
             paginated_games(req, ctx)
         })
         .post_async("/vote/:user_principal", |req, ctx| {
-            place_hot_or_not_vote(req, ctx)
+            place_hot_or_not_vote(req, ctx, "/vote")
+        })
+        .post_async("/vote_v2/:user_principal", |req, ctx| {
+            place_hot_or_not_vote(req, ctx, "/vote_v2")
+        })
+        .post_async("/withdrawals/:user_principal", |req, ctx| {
+            paginated_withdrawals(req, ctx)
         })
+        .get_async("/treasury/:user_principal", |_req, ctx| treasury_info(ctx))
+        .get_async("/stats/:user_principal", |_req, ctx| user_stats(ctx))
         .post_async("/withdraw", withdraw_sats)
+        .get_async("/leaderboard", leaderboard)
+        .post_async("/admin/airdrop/:user_principal", admin_airdrop)
+        .post_async("/admin/adjust/:user_principal", admin_adjust_balance)
+        .post_async("/admin/adjustments/:user_principal", admin_adjustments)
+        .post_async("/admin/revocations", revocations_admin)
+        .delete_async("/admin/revocations", revocations_admin)
         .options("/*catchall", |_, _| Response::empty())
         .run(req, env)
         .await?;
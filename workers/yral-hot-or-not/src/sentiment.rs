@@ -0,0 +1,156 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use candid::Principal;
+use enum_dispatch::enum_dispatch;
+use hon_worker_common::HotOrNot;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use worker::{console_warn, Date, Env, Fetch, Method, Request, Result, Url};
+use worker_utils::environment::{env_kind, RunEnv};
+
+/// wrangler var holding the base url of the sentiment API,
+/// votes settle against the client's `fetched_sentiment` while it's empty
+const SENTIMENT_API_VAR: &str = "SENTIMENT_API_URL";
+/// how long a post's sentiment is cached inside the isolate
+const SENTIMENT_CACHE_TTL_MS: u64 = 5 * 60 * 1000;
+
+type PostKey = (Principal, u64);
+
+thread_local! {
+    /// (post_canister, post_id) -> (sentiment, cached until)
+    static SENTIMENT_CACHE: RefCell<HashMap<PostKey, (HotOrNot, u64)>> = RefCell::new(HashMap::new());
+}
+
+#[enum_dispatch]
+pub(crate) trait SentimentSource {
+    /// aggregate sentiment of a post, votes are settled against this
+    async fn post_sentiment(&self, post_canister: Principal, post_id: u64) -> Result<HotOrNot>;
+}
+
+/// Deterministic sentiment derived from the post id, for local testing
+pub struct MockSentimentSource;
+
+impl SentimentSource for MockSentimentSource {
+    async fn post_sentiment(&self, post_canister: Principal, post_id: u64) -> Result<HotOrNot> {
+        let mut hasher = Sha256::new();
+        hasher.update(post_canister.as_slice());
+        hasher.update(post_id.to_be_bytes());
+        let hash = hasher.finalize();
+
+        Ok(if hash[0] % 2 == 0 {
+            HotOrNot::Hot
+        } else {
+            HotOrNot::Not
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct PostSentimentRes {
+    sentiment: HotOrNot,
+}
+
+/// Sentiment fetched from `GET {SENTIMENT_API_URL}/post_sentiment/{post_canister}/{post_id}`
+///
+/// the API responds with `{ "sentiment": "Hot" | "Not" }`
+pub struct HttpSentimentSource {
+    base_url: Url,
+}
+
+impl HttpSentimentSource {
+    /// `None` if [`SENTIMENT_API_VAR`] is unset or empty
+    pub fn new(env: &Env) -> Result<Option<Self>> {
+        let Ok(base_url) = env.var(SENTIMENT_API_VAR) else {
+            return Ok(None);
+        };
+        let mut base_url = base_url.to_string();
+        if base_url.is_empty() {
+            return Ok(None);
+        }
+        // `Url::join` replaces the last path segment unless the base ends with a slash
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+        let base_url = Url::parse(&base_url)?;
+
+        Ok(Some(Self { base_url }))
+    }
+
+    async fn fetch_sentiment(&self, post_canister: Principal, post_id: u64) -> Result<HotOrNot> {
+        let url = self
+            .base_url
+            .join(&format!("post_sentiment/{post_canister}/{post_id}"))?;
+        let req = Request::new(url.as_str(), Method::Get)?;
+        let mut res = Fetch::Request(req).send().await?;
+        if res.status_code() != 200 {
+            return Err(worker::Error::RustError(format!(
+                "sentiment API returned {}: {}",
+                res.status_code(),
+                res.text().await?
+            )));
+        }
+        let res: PostSentimentRes = res.json().await?;
+
+        Ok(res.sentiment)
+    }
+}
+
+impl SentimentSource for HttpSentimentSource {
+    async fn post_sentiment(&self, post_canister: Principal, post_id: u64) -> Result<HotOrNot> {
+        let key = (post_canister, post_id);
+        let now = Date::now().as_millis();
+        let cached = SENTIMENT_CACHE.with_borrow(|cache| {
+            cache
+                .get(&key)
+                .filter(|(_, until)| *until > now)
+                .map(|(sentiment, _)| sentiment.clone())
+        });
+        if let Some(sentiment) = cached {
+            return Ok(sentiment);
+        }
+
+        let sentiment = self.fetch_sentiment(post_canister, post_id).await?;
+        SENTIMENT_CACHE.with_borrow_mut(|cache| {
+            cache.retain(|_, (_, until)| *until > now);
+            cache.insert(key, (sentiment.clone(), now + SENTIMENT_CACHE_TTL_MS));
+        });
+
+        Ok(sentiment)
+    }
+}
+
+/// The sentiment reported by the client, used while the sentiment API isn't configured
+pub struct ClientSentimentSource(HotOrNot);
+
+impl SentimentSource for ClientSentimentSource {
+    async fn post_sentiment(&self, _post_canister: Principal, _post_id: u64) -> Result<HotOrNot> {
+        Ok(self.0.clone())
+    }
+}
+
+#[enum_dispatch(SentimentSource)]
+pub enum SentimentSourceImpl {
+    Mock(MockSentimentSource),
+    Client(ClientSentimentSource),
+    Real(HttpSentimentSource),
+}
+
+impl SentimentSourceImpl {
+    /// `fetched_sentiment` is used if the sentiment API isn't configured
+    pub fn new(env: &Env, fetched_sentiment: Option<HotOrNot>) -> Result<Self> {
+        if env_kind() != RunEnv::Remote {
+            return Ok(Self::Mock(MockSentimentSource));
+        }
+        if let Some(source) = HttpSentimentSource::new(env)? {
+            return Ok(Self::Real(source));
+        }
+        let Some(fetched_sentiment) = fetched_sentiment else {
+            return Err(worker::Error::RustError(format!(
+                "{SENTIMENT_API_VAR} is not set and no sentiment was fetched"
+            )));
+        };
+        console_warn!("{SENTIMENT_API_VAR} is not set, using the client's sentiment");
+
+        Ok(Self::Client(ClientSentimentSource(fetched_sentiment)))
+    }
+}
@@ -0,0 +1,64 @@
+use hon_worker_common::GameResult;
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use worker_utils::storage::{Migration, VersionedSchema};
+
+/// Running totals for a player, maintained on every game and creator reward
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct HonGameStats {
+    pub total_games: u64,
+    pub wins: u64,
+    pub losses: u64,
+    pub sats_won: BigUint,
+    pub sats_lost: BigUint,
+    pub creator_rewards: BigUint,
+    pub current_win_streak: u64,
+    pub longest_win_streak: u64,
+}
+
+/// Storage schema for the `stats` cell, `None` until the stats have been backfilled
+pub struct HonGameStatsSchema;
+
+impl VersionedSchema for HonGameStatsSchema {
+    type Value = Option<HonGameStats>;
+
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+impl HonGameStats {
+    pub fn record_game(&mut self, game_result: &GameResult) {
+        self.total_games += 1;
+        match game_result {
+            GameResult::Win { win_amt } => {
+                self.wins += 1;
+                self.sats_won += win_amt;
+                self.current_win_streak += 1;
+                self.longest_win_streak = self.longest_win_streak.max(self.current_win_streak);
+            }
+            GameResult::Loss { lose_amt } => {
+                self.losses += 1;
+                self.sats_lost += lose_amt;
+                self.current_win_streak = 0;
+            }
+        }
+    }
+
+    pub fn record_creator_reward(&mut self, amount: u128) {
+        self.creator_rewards += amount;
+    }
+
+    /// totals from existing games
+    ///
+    /// games are not stored in the order they were played, so streaks start at 0,
+    /// creator rewards were never recorded and also start at 0
+    pub fn backfill<'a>(games: impl IntoIterator<Item = &'a GameResult>) -> Self {
+        let mut stats = games.into_iter().fold(Self::default(), |mut stats, game| {
+            stats.record_game(game);
+            stats
+        });
+        stats.current_win_streak = 0;
+        stats.longest_win_streak = 0;
+
+        stats
+    }
+}
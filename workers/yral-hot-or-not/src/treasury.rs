@@ -1,90 +1,578 @@
-use candid::{Nat, Principal};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use candid::{CandidType, Decode, Encode, Nat, Principal};
 use enum_dispatch::enum_dispatch;
 use hon_worker_common::WorkerError;
 use ic_agent::identity::Secp256k1Identity;
+use serde::{Deserialize, Serialize};
 use worker::{console_log, Env};
 use worker_utils::{
     environment::{env_kind, RunEnv},
     icp::agent_wrapper::AgentWrapper,
 };
 use yral_canisters_client::sns_ledger::{
-    Account, SnsLedger, TransferArg, TransferError, TransferResult,
+    Account, Memo, SnsLedger, TransferArg, TransferError, TransferResult,
 };
 
-use crate::consts::CKBTC_LEDGER;
+use crate::consts::{CKBTC_INDEX, CKBTC_LEDGER};
+
+pub const CKBTC: &str = "ckbtc";
+
+// JSON map of token name to [`PayoutTokenConfig`], merged over the defaults
+// only ckBTC is paid out by default, other ICRC-1 tokens are added here
+const PAYOUT_TOKENS_VAR: &str = "PAYOUT_TOKENS";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PayoutTokenConfig {
+    pub ledger: Principal,
+    /// treasury subaccount transfers are sent from, the default subaccount if not set
+    #[serde(default)]
+    pub from_subaccount: Option<[u8; 32]>,
+    /// fee sent with every transfer, the ledger rejects it with `BadFee` if it doesn't match
+    #[serde(default)]
+    pub fee: Option<u64>,
+    /// ICRC index canister, transfers rejected as `TooOld` are looked up here
+    #[serde(default)]
+    pub index: Option<Principal>,
+}
+
+impl PayoutTokenConfig {
+    pub fn new(ledger: Principal, index: Option<Principal>) -> Self {
+        Self {
+            ledger,
+            from_subaccount: None,
+            fee: None,
+            index,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PayoutTokens(HashMap<String, PayoutTokenConfig>);
+
+impl Default for PayoutTokens {
+    fn default() -> Self {
+        Self(HashMap::from([(
+            CKBTC.to_string(),
+            PayoutTokenConfig::new(CKBTC_LEDGER, Some(CKBTC_INDEX)),
+        )]))
+    }
+}
+
+impl PayoutTokens {
+    pub fn load(env: &Env) -> Result<Self, worker::Error> {
+        let mut tokens = Self::default();
+        let Ok(raw) = env.var(PAYOUT_TOKENS_VAR) else {
+            return Ok(tokens);
+        };
+        let overrides: HashMap<String, PayoutTokenConfig> = serde_json::from_str(&raw.to_string())?;
+        tokens.0.extend(overrides);
+
+        Ok(tokens)
+    }
+
+    pub fn get(&self, token: &str) -> Result<&PayoutTokenConfig, PayoutError> {
+        self.0
+            .get(token)
+            .ok_or_else(|| PayoutError::UnknownToken(token.to_string()))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PayoutArgs {
+    pub to: Principal,
+    pub amount: Nat,
+    pub memo: Vec<u8>,
+    /// ns since epoch
+    pub created_at_time: u64,
+}
+
+impl PayoutArgs {
+    fn matches(
+        &self,
+        to: Principal,
+        amount: &Nat,
+        memo: Option<&[u8]>,
+        created_at_time: Option<u64>,
+    ) -> bool {
+        self.to == to
+            && &self.amount == amount
+            && memo == Some(self.memo.as_slice())
+            && created_at_time == Some(self.created_at_time)
+    }
+}
+
+/// mirrors the ICRC-1 `TransferError`, plus failures before the ledger was reached
+#[derive(Clone, Debug)]
+pub enum PayoutError {
+    UnknownToken(String),
+    BadFee {
+        expected_fee: Nat,
+    },
+    BadBurn {
+        min_burn_amount: Nat,
+    },
+    InsufficientFunds {
+        balance: Nat,
+    },
+    /// outside the ledger's dedup window, an earlier attempt may have gone through,
+    /// use [`PayoutService::find_transfer`] to tell
+    TooOld,
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    TemporarilyUnavailable,
+    /// an earlier transfer with the same arguments went through
+    Duplicate {
+        duplicate_of: Nat,
+    },
+    GenericError {
+        error_code: Nat,
+        message: String,
+    },
+    /// the call failed, the transfer may have been applied
+    Unknown(String),
+}
+
+impl From<TransferError> for PayoutError {
+    fn from(e: TransferError) -> Self {
+        match e {
+            TransferError::BadFee { expected_fee } => Self::BadFee { expected_fee },
+            TransferError::BadBurn { min_burn_amount } => Self::BadBurn { min_burn_amount },
+            TransferError::InsufficientFunds { balance } => Self::InsufficientFunds { balance },
+            TransferError::TooOld => Self::TooOld,
+            TransferError::CreatedInFuture { ledger_time } => Self::CreatedInFuture { ledger_time },
+            TransferError::TemporarilyUnavailable => Self::TemporarilyUnavailable,
+            TransferError::Duplicate { duplicate_of } => Self::Duplicate { duplicate_of },
+            TransferError::GenericError {
+                error_code,
+                message,
+            } => Self::GenericError {
+                error_code,
+                message,
+            },
+        }
+    }
+}
+
+impl PayoutError {
+    /// whether retrying with the same arguments may succeed or reveal an earlier success
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::CreatedInFuture { .. } | Self::TemporarilyUnavailable | Self::Unknown(_)
+        )
+    }
+
+    pub fn to_worker_error(&self) -> (u16, WorkerError) {
+        match self {
+            Self::InsufficientFunds { .. } => (500, WorkerError::TreasuryOutOfFunds),
+            Self::TemporarilyUnavailable => (
+                503,
+                WorkerError::Internal("ledger temporarily unavailable".into()),
+            ),
+            e => (500, WorkerError::Internal(format!("{e:?}"))),
+        }
+    }
+}
 
 #[enum_dispatch]
-pub(crate) trait CkBtcTreasury {
-    async fn transfer_ckbtc(&self, to: Principal, amount: Nat) -> Result<(), (u16, WorkerError)>;
+pub(crate) trait PayoutService {
+    /// transfer `args.amount` of `token` to `args.to`, returns the ledger block index
+    ///
+    /// retries with the same `memo` and `created_at_time` are deduplicated by the ledger
+    async fn transfer(&self, token: &str, args: PayoutArgs) -> Result<Nat, PayoutError>;
+
+    /// block index of an earlier transfer made with `args`, `None` if it never reached the ledger
+    async fn find_transfer(
+        &self,
+        token: &str,
+        args: &PayoutArgs,
+    ) -> Result<Option<Nat>, PayoutError>;
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordedTransfer {
+    pub token: String,
+    pub from_subaccount: Option<[u8; 32]>,
+    pub args: PayoutArgs,
+    pub block_index: Nat,
 }
 
-pub struct NoOpCkBtcTreasury;
+/// records transfers in memory and deduplicates them like the ledger does
+#[derive(Clone, Default)]
+pub struct MockPayoutService {
+    tokens: PayoutTokens,
+    transfers: Rc<RefCell<Vec<RecordedTransfer>>>,
+}
+
+impl MockPayoutService {
+    pub fn new(tokens: PayoutTokens) -> Self {
+        Self {
+            tokens,
+            transfers: Rc::default(),
+        }
+    }
 
-impl CkBtcTreasury for NoOpCkBtcTreasury {
-    async fn transfer_ckbtc(&self, _to: Principal, _amount: Nat) -> Result<(), (u16, WorkerError)> {
-        Ok(())
+    /// transfers made so far
+    #[cfg(test)]
+    pub fn transfers(&self) -> Vec<RecordedTransfer> {
+        self.transfers.borrow().clone()
     }
 }
 
-pub struct AdminCkBtcTreasury(AgentWrapper);
+impl PayoutService for MockPayoutService {
+    async fn find_transfer(
+        &self,
+        token: &str,
+        args: &PayoutArgs,
+    ) -> Result<Option<Nat>, PayoutError> {
+        let config = self.tokens.get(token)?;
+        let transfers = self.transfers.borrow();
+        let found = transfers.iter().find(|prev| {
+            prev.token == token
+                && prev.from_subaccount == config.from_subaccount
+                && args.matches(
+                    prev.args.to,
+                    &prev.args.amount,
+                    Some(&prev.args.memo),
+                    Some(prev.args.created_at_time),
+                )
+        });
 
-impl AdminCkBtcTreasury {
-    pub fn new(env: &Env) -> Result<Self, worker::Error> {
+        Ok(found.map(|prev| prev.block_index.clone()))
+    }
+
+    async fn transfer(&self, token: &str, args: PayoutArgs) -> Result<Nat, PayoutError> {
+        let config = self.tokens.get(token)?;
+        let mut transfers = self.transfers.borrow_mut();
+        if let Some(prev) = transfers.iter().find(|prev| {
+            prev.token == token
+                && prev.from_subaccount == config.from_subaccount
+                && prev.args.to == args.to
+                && prev.args.amount == args.amount
+                && prev.args.memo == args.memo
+                && prev.args.created_at_time == args.created_at_time
+        }) {
+            return Err(PayoutError::Duplicate {
+                duplicate_of: prev.block_index.clone(),
+            });
+        }
+
+        let block_index = Nat::from(transfers.len());
+        transfers.push(RecordedTransfer {
+            token: token.to_string(),
+            from_subaccount: config.from_subaccount,
+            args,
+            block_index: block_index.clone(),
+        });
+
+        Ok(block_index)
+    }
+}
+
+/// transactions fetched per index query
+const INDEX_PAGE_SIZE: u64 = 100;
+/// index pages searched before giving up
+const INDEX_MAX_PAGES: usize = 50;
+/// how far before `created_at_time` a block may be timestamped, in ns
+const LEDGER_MAX_DRIFT_NS: u64 = 60 * 1_000_000_000;
+
+// the parts of the ICRC index `get_account_transactions` interface needed to find a transfer
+
+#[derive(CandidType, Deserialize)]
+struct IndexAccount {
+    owner: Principal,
+    subaccount: Option<serde_bytes::ByteBuf>,
+}
+
+#[derive(CandidType)]
+struct GetAccountTransactionsArgs {
+    account: IndexAccount,
+    start: Option<Nat>,
+    max_results: Nat,
+}
+
+#[derive(Deserialize)]
+struct IndexTransfer {
+    to: IndexAccount,
+    amount: Nat,
+    memo: Option<serde_bytes::ByteBuf>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct IndexTransaction {
+    transfer: Option<IndexTransfer>,
+    /// ns since epoch
+    timestamp: u64,
+}
+
+#[derive(Deserialize)]
+struct IndexTransactionWithId {
+    id: Nat,
+    transaction: IndexTransaction,
+}
+
+#[derive(Deserialize)]
+struct IndexTransactions {
+    transactions: Vec<IndexTransactionWithId>,
+}
+
+#[derive(Deserialize)]
+struct IndexError {
+    message: String,
+}
+
+#[derive(Deserialize)]
+enum GetTransactionsResult {
+    Ok(IndexTransactions),
+    Err(IndexError),
+}
+
+pub struct Icrc1PayoutService {
+    agent: AgentWrapper,
+    tokens: PayoutTokens,
+}
+
+impl Icrc1PayoutService {
+    pub fn new(env: &Env, tokens: PayoutTokens) -> Result<Self, worker::Error> {
         let admin_pem = env.secret("BACKEND_ADMIN_KEY")?.to_string();
         let id = Secp256k1Identity::from_pem(admin_pem.as_bytes())
             .map_err(|e| worker::Error::RustError(e.to_string()))?;
         let agent = AgentWrapper::new(id);
 
-        Ok(Self(agent))
+        Ok(Self { agent, tokens })
     }
 }
 
-impl CkBtcTreasury for AdminCkBtcTreasury {
-    async fn transfer_ckbtc(&self, to: Principal, amount: Nat) -> Result<(), (u16, WorkerError)> {
-        console_log!("ledger: {}; to: {}", CKBTC_LEDGER.to_text(), to.to_text());
-        let ledger = SnsLedger(CKBTC_LEDGER, self.0.get().await);
+impl Icrc1PayoutService {
+    /// a page of `owner`'s transactions, newest first, starting at `start`
+    async fn account_transactions(
+        &self,
+        index: Principal,
+        owner: Principal,
+        start: Option<Nat>,
+    ) -> Result<Vec<IndexTransactionWithId>, PayoutError> {
+        let args = GetAccountTransactionsArgs {
+            account: IndexAccount {
+                owner,
+                subaccount: None,
+            },
+            start,
+            max_results: Nat::from(INDEX_PAGE_SIZE),
+        };
+        let args = Encode!(&args).map_err(|e| PayoutError::Unknown(e.to_string()))?;
+        let res = self
+            .agent
+            .get()
+            .await
+            .query(&index, "get_account_transactions")
+            .with_arg(args)
+            .call()
+            .await
+            .map_err(|e| PayoutError::Unknown(e.to_string()))?;
+        match Decode!(&res, GetTransactionsResult)
+            .map_err(|e| PayoutError::Unknown(e.to_string()))?
+        {
+            GetTransactionsResult::Ok(page) => Ok(page.transactions),
+            GetTransactionsResult::Err(e) => Err(PayoutError::Unknown(e.message)),
+        }
+    }
+}
+
+impl PayoutService for Icrc1PayoutService {
+    async fn find_transfer(
+        &self,
+        token: &str,
+        args: &PayoutArgs,
+    ) -> Result<Option<Nat>, PayoutError> {
+        let config = self.tokens.get(token)?;
+        let Some(index) = config.index else {
+            return Err(PayoutError::Unknown(format!(
+                "no index canister configured for {token}"
+            )));
+        };
+
+        let mut start = None::<Nat>;
+        for _ in 0..INDEX_MAX_PAGES {
+            let page = self
+                .account_transactions(index, args.to, start.clone())
+                .await?;
+            let mut oldest = None::<Nat>;
+            for tx in page {
+                // the index may include `start` again
+                if start.as_ref().is_some_and(|start| tx.id >= *start) {
+                    continue;
+                }
+                // the transfer can't be in a block older than its `created_at_time`
+                if tx.transaction.timestamp + LEDGER_MAX_DRIFT_NS < args.created_at_time {
+                    return Ok(None);
+                }
+                if let Some(transfer) = &tx.transaction.transfer {
+                    if args.matches(
+                        transfer.to.owner,
+                        &transfer.amount,
+                        transfer.memo.as_deref().map(|memo| memo.as_slice()),
+                        transfer.created_at_time,
+                    ) {
+                        return Ok(Some(tx.id));
+                    }
+                }
+                oldest = Some(tx.id);
+            }
+            // no older transactions
+            let Some(oldest) = oldest else {
+                return Ok(None);
+            };
+            start = Some(oldest);
+        }
+
+        Err(PayoutError::Unknown(format!(
+            "transfer not found in the latest {} transactions",
+            INDEX_PAGE_SIZE as usize * INDEX_MAX_PAGES
+        )))
+    }
+
+    async fn transfer(&self, token: &str, args: PayoutArgs) -> Result<Nat, PayoutError> {
+        let config = self.tokens.get(token)?;
+        console_log!(
+            "token: {token}; ledger: {}; to: {}",
+            config.ledger.to_text(),
+            args.to.to_text()
+        );
+        let ledger = SnsLedger(config.ledger, self.agent.get().await);
 
         let res = ledger
             .icrc_1_transfer(TransferArg {
                 to: Account {
-                    owner: to,
+                    owner: args.to,
                     subaccount: None,
                 },
-                fee: None,
-                memo: None,
-                from_subaccount: None,
-                created_at_time: None,
-                amount: amount.clone(),
+                fee: config.fee.map(Nat::from),
+                memo: Some(Memo::from(args.memo)),
+                from_subaccount: config
+                    .from_subaccount
+                    .map(|sub| serde_bytes::ByteBuf::from(sub.to_vec())),
+                created_at_time: Some(args.created_at_time),
+                amount: args.amount,
             })
             .await
-            .map_err(|e| (500, WorkerError::Internal(e.to_string())))?;
+            .map_err(|e| PayoutError::Unknown(e.to_string()))?;
         match res {
-            TransferResult::Err(TransferError::InsufficientFunds { .. }) => {
-                return Err((500, WorkerError::TreasuryOutOfFunds))
-            }
-            TransferResult::Err(e) => {
-                return Err((500, WorkerError::Internal(format!("{e:?}"))));
-            }
-            TransferResult::Ok(_) => (),
+            TransferResult::Ok(block_index) => Ok(block_index),
+            TransferResult::Err(e) => Err(e.into()),
         }
-
-        Ok(())
     }
 }
 
-#[enum_dispatch(CkBtcTreasury)]
-pub enum CkBtcTreasuryImpl {
-    Mock(NoOpCkBtcTreasury),
-    Real(AdminCkBtcTreasury),
+#[enum_dispatch(PayoutService)]
+pub enum PayoutServiceImpl {
+    Mock(MockPayoutService),
+    Real(Icrc1PayoutService),
 }
 
-impl CkBtcTreasuryImpl {
+impl PayoutServiceImpl {
     pub fn new(env: &Env) -> Result<Self, worker::Error> {
+        let tokens = PayoutTokens::load(env)?;
         let this = match env_kind() {
-            RunEnv::Remote => Self::Real(AdminCkBtcTreasury::new(env)?),
-            _ => Self::Mock(NoOpCkBtcTreasury),
+            RunEnv::Remote => Self::Real(Icrc1PayoutService::new(env, tokens)?),
+            _ => Self::Mock(MockPayoutService::new(tokens)),
         };
 
         Ok(this)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    const SUBACCOUNT: [u8; 32] = [7; 32];
+
+    fn service() -> MockPayoutService {
+        let mut tokens = PayoutTokens::default();
+        tokens.0.insert(
+            "test".into(),
+            PayoutTokenConfig {
+                from_subaccount: Some(SUBACCOUNT),
+                ..PayoutTokenConfig::new(Principal::anonymous(), None)
+            },
+        );
+
+        MockPayoutService::new(tokens)
+    }
+
+    fn args(memo: &[u8]) -> PayoutArgs {
+        PayoutArgs {
+            to: Principal::management_canister(),
+            amount: Nat::from(100u32),
+            memo: memo.to_vec(),
+            created_at_time: 1_000,
+        }
+    }
+
+    #[test]
+    fn transfer_is_recorded_with_the_token_subaccount() {
+        let service = service();
+        let block_index = block_on(service.transfer("test", args(b"a"))).unwrap();
+
+        let transfers = service.transfers();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].block_index, block_index);
+        assert_eq!(transfers[0].token, "test");
+        assert_eq!(transfers[0].from_subaccount, Some(SUBACCOUNT));
+        assert_eq!(transfers[0].args.amount, Nat::from(100u32));
+    }
+
+    #[test]
+    fn retried_transfer_is_a_duplicate() {
+        let service = service();
+        let block_index = block_on(service.transfer(CKBTC, args(b"a"))).unwrap();
+
+        let res = block_on(service.transfer(CKBTC, args(b"a")));
+        assert!(
+            matches!(res, Err(PayoutError::Duplicate { duplicate_of }) if duplicate_of == block_index)
+        );
+        assert_eq!(service.transfers().len(), 1);
+
+        // a different memo is a different transfer
+        block_on(service.transfer(CKBTC, args(b"b"))).unwrap();
+        assert_eq!(service.transfers().len(), 2);
+    }
+
+    #[test]
+    fn find_transfer_only_matches_transfers_that_went_through() {
+        let service = service();
+        let block_index = block_on(service.transfer(CKBTC, args(b"a"))).unwrap();
+
+        let found = block_on(service.find_transfer(CKBTC, &args(b"a"))).unwrap();
+        assert_eq!(found, Some(block_index));
+        let found = block_on(service.find_transfer(CKBTC, &args(b"b"))).unwrap();
+        assert_eq!(found, None);
+        let found = block_on(service.find_transfer("test", &args(b"a"))).unwrap();
+        assert_eq!(found, None);
+    }
+
+    #[test]
+    fn unknown_token_is_rejected() {
+        let service = service();
+
+        let res = block_on(service.transfer("unknown", args(b"a")));
+        assert!(matches!(res, Err(PayoutError::UnknownToken(token)) if token == "unknown"));
+        assert!(service.transfers().is_empty());
+    }
+
+    #[test]
+    fn only_retryable_errors_are_retried() {
+        assert!(PayoutError::TemporarilyUnavailable.is_retryable());
+        assert!(PayoutError::Unknown("timeout".into()).is_retryable());
+        assert!(!PayoutError::TooOld.is_retryable());
+        assert!(!PayoutError::InsufficientFunds {
+            balance: Nat::from(0u32)
+        }
+        .is_retryable());
+    }
+}
@@ -1,9 +1,18 @@
 use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
 use worker::{console_debug, Date, Result};
-use worker_utils::storage::SafeStorage;
+use worker_utils::storage::{Migration, SafeStorage, StorageTransaction, VersionedSchema};
 
-use crate::consts::MAXIMUM_CKBTC_TREASURY_PER_DAY_PER_USER;
+const TREASURY_KEY: &str = "ckbtc-treasury-limit-v3";
+const TREASURY_RESET_MS: u64 = 24 * 3600 * 1000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TreasuryInfo {
+    /// sats that can still be withdrawn before the next reset
+    pub remaining: BigUint,
+    /// ms since epoch
+    pub next_reset_ms: u64,
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 struct CkBtcTreasuryInner {
@@ -11,64 +20,97 @@ struct CkBtcTreasuryInner {
     last_reset_epoch: u64,
 }
 
-impl Default for CkBtcTreasuryInner {
-    fn default() -> Self {
+impl VersionedSchema for CkBtcTreasuryInner {
+    type Value = Self;
+
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+impl CkBtcTreasuryInner {
+    fn new(daily_limit: &BigUint) -> Self {
         Self {
-            amount: BigUint::from(MAXIMUM_CKBTC_TREASURY_PER_DAY_PER_USER),
+            amount: daily_limit.clone(),
             last_reset_epoch: Date::now().as_millis(),
         }
     }
+
+    fn reset_if_expired(self, daily_limit: &BigUint) -> Self {
+        if Date::now().as_millis() - TREASURY_RESET_MS >= self.last_reset_epoch {
+            return Self::new(daily_limit);
+        }
+
+        self
+    }
 }
 
 #[derive(Default, Clone)]
 pub struct CkBtcTreasuryStore(Option<CkBtcTreasuryInner>);
 
 impl CkBtcTreasuryStore {
-    async fn get_or_init(&mut self, storage: &SafeStorage) -> Result<&mut CkBtcTreasuryInner> {
-        if self.0.is_some() {
-            return Ok(self.0.as_mut().unwrap());
-        }
-
-        let dolr_treasury_limit = storage
-            .get("ckbtc-treasury-limit-v3")
-            .await?
-            .unwrap_or_default();
-        self.0 = Some(dolr_treasury_limit);
-        Ok(self.0.as_mut().unwrap())
-    }
-
-    async fn treasury(&mut self, storage: &mut SafeStorage) -> Result<&mut CkBtcTreasuryInner> {
-        let treasury = self.get_or_init(storage).await?;
-        if Date::now().as_millis() - (24 * 3600 * 1000) >= treasury.last_reset_epoch {
-            *treasury = CkBtcTreasuryInner::default();
-            storage.put("ckbtc-treasury-limit-v3", treasury).await?;
+    /// takes the cached treasury out, it is only written back once the transaction is staged
+    async fn take_treasury(
+        &mut self,
+        txn: &mut StorageTransaction<'_>,
+        daily_limit: &BigUint,
+    ) -> Result<CkBtcTreasuryInner> {
+        let treasury = match self.0.take() {
+            Some(t) => t,
+            None => txn
+                .get_versioned::<CkBtcTreasuryInner>(TREASURY_KEY)
+                .await?
+                .unwrap_or_else(|| CkBtcTreasuryInner::new(daily_limit)),
         };
 
-        Ok(treasury)
+        Ok(treasury.reset_if_expired(daily_limit))
     }
 
-    pub async fn try_consume(&mut self, storage: &mut SafeStorage, amount: BigUint) -> Result<()> {
-        let treasury = self.treasury(storage).await?;
+    pub async fn try_consume(
+        &mut self,
+        txn: &mut StorageTransaction<'_>,
+        amount: BigUint,
+        daily_limit: &BigUint,
+    ) -> Result<()> {
+        let mut treasury = self.take_treasury(txn, daily_limit).await?;
         console_debug!("treasury amount: {}", treasury.amount.clone());
         if treasury.amount.clone() < amount {
             return Err(worker::Error::RustError("daily limit reached".into()));
         }
         treasury.amount -= amount;
-        storage.put("ckbtc-treasury-limit-v3", treasury).await?;
+        txn.put_versioned::<CkBtcTreasuryInner>(TREASURY_KEY, &treasury)?;
 
         Ok(())
     }
 
-    pub async fn rollback(&mut self, storage: &mut SafeStorage, amount: BigUint) -> Result<()> {
-        let treasury = self.treasury(storage).await?;
-        treasury.amount =
-            (treasury.amount.clone() + amount).min(MAXIMUM_CKBTC_TREASURY_PER_DAY_PER_USER.into());
-        storage.put("ckbtc-treasury-limit-v3", treasury).await?;
+    pub async fn rollback(
+        &mut self,
+        txn: &mut StorageTransaction<'_>,
+        amount: BigUint,
+        daily_limit: &BigUint,
+    ) -> Result<()> {
+        let mut treasury = self.take_treasury(txn, daily_limit).await?;
+        treasury.amount = (treasury.amount.clone() + amount).min(daily_limit.clone());
+        txn.put_versioned::<CkBtcTreasuryInner>(TREASURY_KEY, &treasury)?;
 
         Ok(())
     }
 
-    // pub async fn amount(&mut self, storage: &mut SafeStorage) -> Result<BigUint> {
-    //     self.treasury(storage).await.map(|v| v.amount.clone())
-    // }
+    pub async fn info(
+        &mut self,
+        storage: &mut SafeStorage,
+        daily_limit: &BigUint,
+    ) -> Result<TreasuryInfo> {
+        let treasury = match self.0.clone() {
+            Some(t) => t,
+            None => storage
+                .get_versioned::<CkBtcTreasuryInner>(TREASURY_KEY)
+                .await?
+                .unwrap_or_else(|| CkBtcTreasuryInner::new(daily_limit)),
+        };
+        let treasury = treasury.reset_if_expired(daily_limit);
+
+        Ok(TreasuryInfo {
+            remaining: treasury.amount,
+            next_reset_ms: treasury.last_reset_epoch + TREASURY_RESET_MS,
+        })
+    }
 }
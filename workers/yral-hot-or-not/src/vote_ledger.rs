@@ -0,0 +1,320 @@
+use candid::Principal;
+use futures::StreamExt;
+use hon_worker_common::{GameInfo, GameResult, HotOrNot, WorkerError};
+use num_bigint::BigUint;
+use std::result::Result as StdResult;
+use worker::*;
+use worker_utils::storage::{
+    ListEntryError, Migration, StorageBackend, StorageCell, StorageMap, StorageTransaction,
+    UnpaddedU64, VersionedSchema,
+};
+
+use crate::{
+    config::HonGameConfig,
+    consts::DEFAULT_ONBOARDING_REWARD_SATS,
+    creator_reward::{new_reward_id, PendingCreatorReward},
+    stats::{HonGameStats, HonGameStatsSchema},
+};
+
+/// Storage schema for `games-` entries
+pub struct GameInfoSchema;
+
+impl VersionedSchema for GameInfoSchema {
+    type Value = GameInfo;
+
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+pub type GameKey = (Principal, UnpaddedU64);
+
+pub const STORAGE_PAGE_SIZE: usize = 128;
+
+/// A vote settled instantly against the post's sentiment
+pub struct InstantVote {
+    pub post_canister: Principal,
+    pub post_id: u64,
+    pub vote_amount: u128,
+    pub direction: HotOrNot,
+    pub sentiment: HotOrNot,
+    pub creator: Option<Principal>,
+}
+
+/// A player's balance, games and stats
+///
+/// the game logic of [`crate::hon_game::UserHonGameState`], runs on any [`StorageBackend`]
+pub struct VoteLedger<B: StorageBackend> {
+    backend: B,
+    pub sats_balance: StorageCell<BigUint>,
+    // `games-{post_canister}-{post_id}`, post ids are unpadded for compatibility with older entries
+    pub games: StorageMap<GameKey, GameInfo>,
+    pub stats: StorageCell<Option<HonGameStats>>,
+    // `creator-reward-outbox-{id}`, creator rewards waiting to be delivered
+    pub creator_reward_outbox: StorageMap<String, PendingCreatorReward>,
+}
+
+impl<B: StorageBackend> VoteLedger<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            sats_balance: StorageCell::new("sats_balance", || {
+                BigUint::from(DEFAULT_ONBOARDING_REWARD_SATS)
+            }),
+            games: StorageMap::new_versioned::<GameInfoSchema>("games-"),
+            stats: StorageCell::new_versioned::<HonGameStatsSchema>("stats", || None),
+            creator_reward_outbox: StorageMap::new_versioned::<PendingCreatorReward>(
+                "creator-reward-outbox-",
+            ),
+        }
+    }
+
+    /// running stats, computed from the stored games on first access
+    pub async fn stats(&mut self) -> Result<HonGameStats> {
+        let mut storage = self.backend.storage();
+        if let Some(stats) = self.stats.read(&storage).await? {
+            return Ok(stats.clone());
+        }
+
+        let mut game_results = vec![];
+        let mut entries = self.games.stream(&storage, STORAGE_PAGE_SIZE);
+        while let Some(entry) = entries.next().await {
+            match entry {
+                Ok((_, GameInfo::Vote { game_result, .. })) => game_results.push(game_result),
+                Err(ListEntryError::Storage(e)) => return Err(Error::RustError(e)),
+                Err(e) => console_error!("skipping game: {e}"),
+            }
+        }
+        drop(entries);
+
+        let stats = HonGameStats::backfill(&game_results);
+        self.stats.set(&mut storage, Some(stats.clone())).await?;
+
+        Ok(stats)
+    }
+
+    /// stage a stats update, [`Self::stats`] must have been called before
+    pub async fn update_stats_txn(
+        &mut self,
+        txn: &mut StorageTransaction<'_>,
+        updater: impl FnOnce(&mut HonGameStats),
+    ) -> StdResult<(), (u16, WorkerError)> {
+        self.stats
+            .update_txn(txn, |stats| {
+                updater(stats.get_or_insert_with(Default::default))
+            })
+            .await
+            .map_err(|_| (500, WorkerError::Internal("failed to update stats".into())))
+    }
+
+    /// settle the vote, the balance, game, stats and creator reward are committed together
+    ///
+    /// returns the result and whether a creator reward was queued
+    pub async fn settle_instant_vote(
+        &mut self,
+        vote: InstantVote,
+        config: &HonGameConfig,
+        now_ms: u64,
+    ) -> StdResult<(GameResult, bool), (u16, WorkerError)> {
+        let key = (vote.post_canister, UnpaddedU64(vote.post_id));
+        let storage = self.backend.storage();
+        let voted = self
+            .games
+            .get(&storage, &key)
+            .await
+            .map_err(|_| (500, WorkerError::Internal("failed to get game info".into())))?
+            .is_some();
+        if voted {
+            return Err((400, WorkerError::AlreadyVotedOnPost));
+        }
+
+        self.stats()
+            .await
+            .map_err(|_| (500, WorkerError::Internal("failed to get stats".into())))?;
+
+        let vote_amount = vote.vote_amount.min(config.max_vote_amount_sats);
+        let mut storage = self.backend.storage();
+        let mut txn = storage.transaction();
+        let mut res = None::<(GameResult, u128)>;
+        self.sats_balance
+            .update_txn(&mut txn, |balance| {
+                let creator_reward = vote_amount * config.creator_cut_percent as u128 / 100;
+                let vote_amount = BigUint::from(vote_amount);
+                if *balance < vote_amount {
+                    return;
+                }
+                let game_res = if vote.sentiment == vote.direction {
+                    let win_amt = (vote_amount.clone() * config.win_multiplier_percent) / 100u32;
+                    *balance += win_amt.clone();
+                    GameResult::Win { win_amt }
+                } else {
+                    *balance -= vote_amount.clone();
+                    GameResult::Loss {
+                        lose_amt: vote_amount.clone(),
+                    }
+                };
+                res = Some((game_res, creator_reward))
+            })
+            .await
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to update balance".into()),
+                )
+            })?;
+
+        let Some((game_result, creator_reward)) = res else {
+            return Err((400, WorkerError::InsufficientFunds));
+        };
+
+        let game_info = GameInfo::Vote {
+            vote_amount: BigUint::from(vote_amount),
+            game_result: game_result.clone(),
+        };
+        self.update_stats_txn(&mut txn, |stats| stats.record_game(&game_result))
+            .await?;
+        self.games
+            .insert_txn(&mut txn, &key, &game_info)
+            .map_err(|_| {
+                (
+                    500,
+                    WorkerError::Internal("failed to store game info".into()),
+                )
+            })?;
+        let creator_reward = vote
+            .creator
+            .filter(|_| creator_reward > 0)
+            .map(|creator| PendingCreatorReward::new(creator, creator_reward, now_ms));
+        if let Some(reward) = &creator_reward {
+            self.creator_reward_outbox
+                .insert_txn(&mut txn, &new_reward_id(), reward)
+                .map_err(|_| {
+                    (
+                        500,
+                        WorkerError::Internal("failed to store creator reward".into()),
+                    )
+                })?;
+        }
+        txn.commit().await.map_err(|_| {
+            (
+                500,
+                WorkerError::Internal("failed to store game info".into()),
+            )
+        })?;
+
+        Ok((game_result, creator_reward.is_some()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use worker_utils::storage::MemoryStorage;
+
+    use super::*;
+
+    fn vote(post_id: u64, direction: HotOrNot, sentiment: HotOrNot) -> InstantVote {
+        InstantVote {
+            post_canister: Principal::anonymous(),
+            post_id,
+            vote_amount: 100,
+            direction,
+            sentiment,
+            creator: Some(Principal::management_canister()),
+        }
+    }
+
+    fn balance(ledger: &mut VoteLedger<MemoryStorage>) -> BigUint {
+        let storage = ledger.backend.storage();
+        block_on(ledger.sats_balance.read(&storage))
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn win_credits_the_balance_and_queues_the_creator_reward() {
+        let mut ledger = VoteLedger::new(MemoryStorage::new());
+        let config = HonGameConfig::default();
+
+        let (game_result, creator_reward) =
+            block_on(ledger.settle_instant_vote(vote(1, HotOrNot::Hot, HotOrNot::Hot), &config, 0))
+                .unwrap();
+
+        let win_amt = BigUint::from(100u32) * config.win_multiplier_percent / 100u32;
+        assert!(matches!(&game_result, GameResult::Win { win_amt: won } if *won == win_amt));
+        assert_eq!(
+            balance(&mut ledger),
+            BigUint::from(DEFAULT_ONBOARDING_REWARD_SATS) + win_amt
+        );
+        assert_eq!(creator_reward, config.creator_cut_percent > 0);
+
+        let stats = block_on(ledger.stats()).unwrap();
+        assert_eq!((stats.total_games, stats.wins), (1, 1));
+    }
+
+    #[test]
+    fn loss_debits_the_stake() {
+        let mut ledger = VoteLedger::new(MemoryStorage::new());
+
+        let (game_result, _) = block_on(ledger.settle_instant_vote(
+            vote(1, HotOrNot::Hot, HotOrNot::Not),
+            &HonGameConfig::default(),
+            0,
+        ))
+        .unwrap();
+
+        assert!(matches!(game_result, GameResult::Loss { .. }));
+        assert_eq!(
+            balance(&mut ledger),
+            BigUint::from(DEFAULT_ONBOARDING_REWARD_SATS - 100)
+        );
+        let stats = block_on(ledger.stats()).unwrap();
+        assert_eq!((stats.losses, stats.current_win_streak), (1, 0));
+    }
+
+    #[test]
+    fn second_vote_on_a_post_is_rejected() {
+        let mut ledger = VoteLedger::new(MemoryStorage::new());
+        let config = HonGameConfig::default();
+        block_on(ledger.settle_instant_vote(vote(1, HotOrNot::Hot, HotOrNot::Hot), &config, 0))
+            .unwrap();
+        let balance_after_vote = balance(&mut ledger);
+
+        let res =
+            block_on(ledger.settle_instant_vote(vote(1, HotOrNot::Not, HotOrNot::Hot), &config, 0));
+
+        assert!(matches!(res, Err((400, WorkerError::AlreadyVotedOnPost))));
+        assert_eq!(balance(&mut ledger), balance_after_vote);
+    }
+
+    #[test]
+    fn vote_without_enough_balance_changes_nothing() {
+        let storage = MemoryStorage::new();
+        let mut ledger = VoteLedger::new(storage.clone());
+        ledger
+            .sats_balance
+            .set_initial_value(|| BigUint::from(10u32));
+
+        let res = block_on(ledger.settle_instant_vote(
+            vote(1, HotOrNot::Hot, HotOrNot::Hot),
+            &HonGameConfig::default(),
+            0,
+        ));
+
+        assert!(matches!(res, Err((400, WorkerError::InsufficientFunds))));
+        assert_eq!(balance(&mut ledger), BigUint::from(10u32));
+        assert!(!storage.keys().iter().any(|key| key.starts_with("games-")));
+    }
+
+    #[test]
+    fn vote_amount_is_capped() {
+        let mut ledger = VoteLedger::new(MemoryStorage::new());
+        let config = HonGameConfig::default();
+        let mut vote = vote(1, HotOrNot::Hot, HotOrNot::Not);
+        vote.vote_amount = u128::MAX;
+
+        let (game_result, _) = block_on(ledger.settle_instant_vote(vote, &config, 0)).unwrap();
+
+        assert!(
+            matches!(game_result, GameResult::Loss { lose_amt } if lose_amt == BigUint::from(config.max_vote_amount_sats))
+        );
+    }
+}
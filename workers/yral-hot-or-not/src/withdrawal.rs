@@ -0,0 +1,92 @@
+use candid::{Nat, Principal};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker_utils::storage::{Migration, VersionedSchema};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum WithdrawalState {
+    /// balance debited, the transfer may or may not have reached the ledger
+    Pending,
+    Submitted {
+        block_index: Nat,
+    },
+    /// rejected by the ledger, the balance was refunded
+    Failed {
+        reason: String,
+    },
+}
+
+/// A ckBTC withdrawal, keyed by the client supplied idempotency key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WithdrawalEntry {
+    pub receiver: Principal,
+    pub amount: BigUint,
+    /// ICRC-1 `created_at_time` (ns since epoch), reused on retries so the ledger dedups them
+    pub created_at_time: u64,
+    pub state: WithdrawalState,
+}
+
+impl VersionedSchema for WithdrawalEntry {
+    type Value = Self;
+
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+impl WithdrawalEntry {
+    pub fn new(receiver: Principal, amount: BigUint, now_ms: u64) -> Self {
+        Self {
+            receiver,
+            amount,
+            created_at_time: now_ms * 1_000_000,
+            state: WithdrawalState::Pending,
+        }
+    }
+
+    /// ICRC-1 memo, derived from the idempotency key
+    pub fn memo(idempotency_key: &str) -> Vec<u8> {
+        Sha256::digest(idempotency_key.as_bytes()).to_vec()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WithdrawalRes {
+    pub idempotency_key: String,
+    pub receiver: Principal,
+    pub amount: BigUint,
+    /// ms since epoch
+    pub timestamp_ms: u64,
+    pub block_index: Option<Nat>,
+    pub state: WithdrawalState,
+}
+
+impl WithdrawalRes {
+    pub fn new(idempotency_key: String, entry: WithdrawalEntry) -> Self {
+        let block_index = match &entry.state {
+            WithdrawalState::Submitted { block_index } => Some(block_index.clone()),
+            _ => None,
+        };
+
+        Self {
+            idempotency_key,
+            receiver: entry.receiver,
+            amount: entry.amount,
+            timestamp_ms: entry.created_at_time / 1_000_000,
+            block_index,
+            state: entry.state,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PaginatedWithdrawalsReq {
+    pub page_size: usize,
+    pub cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PaginatedWithdrawalsRes {
+    /// oldest first
+    pub withdrawals: Vec<WithdrawalRes>,
+    pub next: Option<String>,
+}
@@ -4,6 +4,26 @@ pub const GDOLLR_TO_DOLLR: u64 = 100;
 pub const DOLLR_TO_E8S: u64 = 1e8 as u64;
 pub const GDOLLR_TO_E8S: u64 = DOLLR_TO_E8S / GDOLLR_TO_DOLLR;
 pub const TIDE_SHIFT_DELTA: u64 = 1;
+/// countdown events are broadcast this often while a round has a deadline
+pub const ROUND_COUNTDOWN_INTERVAL_MS: u64 = 10 * 1000;
+/// undelivered round settlements are retried after 30 seconds
+pub const SETTLEMENT_RETRY_MS: u64 = 30 * 1000;
+/// settlements that fail 60 times in a row, about 30 minutes of retries, are parked
+pub const SETTLEMENT_MAX_ATTEMPTS: u32 = 60;
+/// received settlement ids are kept for 30 days to deduplicate late retries and refunds
+pub const RECEIVED_SETTLEMENT_TTL_MS: u64 = 30 * 24 * 3600 * 1000;
+/// failed alarm tasks are retried after 30 seconds
+pub const ALARM_RETRY_MS: u64 = 30 * 1000;
+/// sockets that haven't sent anything for 90 seconds are closed
+pub const WS_IDLE_TIMEOUT_MS: u64 = 90 * 1000;
+/// idle sockets are swept every 30 seconds
+pub const WS_IDLE_CHECK_INTERVAL_MS: u64 = 30 * 1000;
+/// player count changes are broadcast at most once every 2 seconds
+pub const PLAYER_COUNT_DEBOUNCE_MS: u64 = 2 * 1000;
+/// maximum number of bets in a single `BetMany`
+pub const MAXIMUM_BET_MANY_COUNT: u64 = 100;
+/// number of entries fetched per page when listing storage
+pub const STORAGE_PAGE_SIZE: usize = 128;
 /// sync user state after 60 seconds
 pub const USER_STATE_RECONCILE_TIME_MS: i64 = 60 * 1000;
 pub const ADMIN_LOCAL_SECP_SK: [u8; 32] = [
@@ -17,3 +37,5 @@ pub const DOLR_LEDGER: Principal = Principal::from_slice(&[0, 0, 0, 0, 2, 0, 0,
 pub const MAXIMUM_DOLR_TREASURY_PER_DAY_PER_USER: u64 = 100 * 1e8 as u64;
 // 400 DOLLR
 pub const USER_INDEX_FUND_AMOUNT: u64 = 400 * 1e8 as u64;
+/// signed message of a nonced claim, the un-nonced one is `claim_msg`
+pub const CLAIM_NONCED: &str = "pump_or_dump_worker_claim_v2";
@@ -0,0 +1,824 @@
+use std::collections::HashMap;
+
+use candid::{Nat, Principal};
+use futures::StreamExt;
+use pump_n_dump_common::{ws::GameResult, GameDirection};
+use worker::*;
+use worker_utils::storage::{ListEntryError, StorageBackend, StorageMap, StorageTransaction};
+
+use crate::{
+    consts::{SETTLEMENT_MAX_ATTEMPTS, STORAGE_PAGE_SIZE, TIDE_SHIFT_DELTA},
+    user_reconciler::StateDiff,
+};
+
+use super::{
+    deadline::{RoundEndReason, RoundTiming},
+    history::RoundRecord,
+    settlement::{Settlement, SettlementEntry},
+    RewardIter,
+};
+
+/// refunds replacing the rewards of a settled round are queued from this idx
+///
+/// the settlement id is derived from `(round, idx)`, the refund must not reuse
+/// the id of the reward it replaces or the user state drops it as already received
+pub const REFUND_IDX_OFFSET: u64 = 1 << 32;
+
+/// a round that just ended, its settlements are queued
+pub struct EndedRound {
+    /// the round that started
+    pub new_round: u64,
+    pub pumps: u64,
+    pub dumps: u64,
+    pub total_pumps: u64,
+    pub total_dumps: u64,
+    pub bets: HashMap<Principal, [u64; 2]>,
+    pub result: GameResult,
+    pub lp_reward: Nat,
+}
+
+/// where a settlement ended up after a delivery attempt
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Delivery {
+    Delivered,
+    Retrying,
+    /// stopped retrying after [`SETTLEMENT_MAX_ATTEMPTS`]
+    Parked,
+}
+
+/// Bets, counters and settlements of a game's rounds
+///
+/// the game logic of [`super::GameState`], runs on any [`StorageBackend`]
+pub struct RoundBook<B: StorageBackend> {
+    backend: B,
+    has_tide_shifted: Option<bool>,
+    round_pumps: Option<u64>,
+    round_dumps: Option<u64>,
+    cumulative_pumps: Option<u64>,
+    cumulative_dumps: Option<u64>,
+    // Principal: (pumps, dumps)
+    bets: StorageMap<Principal, [u64; 2]>,
+    // (round, idx): owed until delivered to the participant
+    settlements: StorageMap<(u64, u64), SettlementEntry>,
+    // (round, idx): settlements that kept failing, requeued when the round is refunded
+    parked_settlements: StorageMap<(u64, u64), SettlementEntry>,
+    // round: results of finished rounds
+    pub history: StorageMap<u64, RoundRecord>,
+    round: Option<u64>,
+}
+
+impl<B: StorageBackend> RoundBook<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            has_tide_shifted: None,
+            round_pumps: None,
+            round_dumps: None,
+            cumulative_pumps: None,
+            cumulative_dumps: None,
+            bets: StorageMap::new("bets-"),
+            settlements: StorageMap::new_versioned::<SettlementEntry>("settlement-"),
+            parked_settlements: StorageMap::new_versioned::<SettlementEntry>("parked-settlement-"),
+            history: StorageMap::new_versioned::<RoundRecord>("hist-"),
+            round: None,
+        }
+    }
+
+    pub async fn pumps(&mut self) -> Result<u64> {
+        if let Some(p) = self.round_pumps {
+            return Ok(p);
+        }
+
+        let pumps = self
+            .backend
+            .storage()
+            .get("pumps")
+            .await?
+            .unwrap_or_default();
+        self.round_pumps = Some(pumps);
+        Ok(pumps)
+    }
+
+    pub async fn dumps(&mut self) -> Result<u64> {
+        if let Some(d) = self.round_dumps {
+            return Ok(d);
+        }
+
+        let dumps = self
+            .backend
+            .storage()
+            .get("dumps")
+            .await?
+            .unwrap_or_default();
+        self.round_dumps = Some(dumps);
+        Ok(dumps)
+    }
+
+    pub async fn cumulative_pumps(&mut self) -> Result<u64> {
+        if let Some(tot) = self.cumulative_pumps {
+            return Ok(tot);
+        }
+
+        let pumps = self
+            .backend
+            .storage()
+            .get("total-pumps")
+            .await?
+            .unwrap_or_default();
+        self.cumulative_pumps = Some(pumps);
+        Ok(pumps)
+    }
+
+    pub async fn cumulative_dumps(&mut self) -> Result<u64> {
+        if let Some(tot) = self.cumulative_dumps {
+            return Ok(tot);
+        }
+
+        let dumps = self
+            .backend
+            .storage()
+            .get("total-dumps")
+            .await?
+            .unwrap_or_default();
+        self.cumulative_dumps = Some(dumps);
+        Ok(dumps)
+    }
+
+    async fn increment_pumps_inner(&mut self, count: u64) -> Result<u64> {
+        let total_pumps = self.cumulative_pumps().await? + count;
+        let pumps = self.pumps().await? + count;
+
+        let mut storage = self.backend.storage();
+        storage.put("total-pumps", &total_pumps).await?;
+        storage.put("pumps", &pumps).await?;
+
+        self.cumulative_pumps = Some(total_pumps);
+        self.round_pumps = Some(pumps);
+
+        Ok(pumps)
+    }
+
+    async fn increment_dumps_inner(&mut self, count: u64) -> Result<u64> {
+        let total_dumps = self.cumulative_dumps().await? + count;
+        let dumps = self.dumps().await? + count;
+
+        let mut storage = self.backend.storage();
+        storage.put("total-dumps", &total_dumps).await?;
+        storage.put("dumps", &dumps).await?;
+
+        self.cumulative_dumps = Some(total_dumps);
+        self.round_dumps = Some(dumps);
+
+        Ok(dumps)
+    }
+
+    async fn has_tide_shifted(&mut self) -> Result<bool> {
+        if let Some(shifted) = self.has_tide_shifted {
+            return Ok(shifted);
+        };
+
+        let shifted = self
+            .backend
+            .storage()
+            .get("has_tide_shifted")
+            .await?
+            .unwrap_or_default();
+        self.has_tide_shifted = Some(shifted);
+
+        Ok(shifted)
+    }
+
+    async fn set_tide_shifted(&mut self) -> Result<()> {
+        self.has_tide_shifted = Some(true);
+        self.backend
+            .storage()
+            .put("has_tide_shifted", &true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// whether the last `count` bets on `with` shifted the tide
+    async fn tide_shift_check(&mut self, with: u64, other: u64, count: u64) -> Result<bool> {
+        let prev_delta = (with - count).saturating_sub(other);
+        let new_delta = (with).saturating_sub(other);
+
+        let shifted = prev_delta < TIDE_SHIFT_DELTA && new_delta >= TIDE_SHIFT_DELTA;
+        if !shifted {
+            return Ok(false);
+        }
+
+        if !self.has_tide_shifted().await? {
+            self.set_tide_shifted().await?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// record `count` bets of `sender`, returns whether they shifted the tide
+    ///
+    /// the round must be ended with [`Self::end_round`] if they did
+    pub async fn place_bet(
+        &mut self,
+        sender: Principal,
+        direction: GameDirection,
+        count: u64,
+    ) -> Result<bool> {
+        let mut bets = self.user_bets(sender).await?;
+        match direction {
+            GameDirection::Pump => bets[0] += count,
+            GameDirection::Dump => bets[1] += count,
+        }
+        let mut storage = self.backend.storage();
+        self.bets.insert(&mut storage, sender, bets).await?;
+
+        match direction {
+            GameDirection::Pump => self.increment_pumps_inner(count).await?,
+            GameDirection::Dump => self.increment_dumps_inner(count).await?,
+        };
+
+        let total_pumps = self.cumulative_pumps().await?;
+        let total_dumps = self.cumulative_dumps().await?;
+        let (with, other) = match direction {
+            GameDirection::Pump => (total_pumps, total_dumps),
+            GameDirection::Dump => (total_dumps, total_pumps),
+        };
+
+        self.tide_shift_check(with, other, count).await
+    }
+
+    pub async fn user_bets(&mut self, user: Principal) -> Result<[u64; 2]> {
+        let storage = self.backend.storage();
+        let bets = self
+            .bets
+            .get(&storage, &user)
+            .await?
+            .copied()
+            .unwrap_or_default();
+
+        Ok(bets)
+    }
+
+    /// all bets placed in the current round
+    async fn all_bets(&self) -> Result<HashMap<Principal, [u64; 2]>> {
+        let storage = self.backend.storage();
+        let mut bets = HashMap::new();
+
+        let mut bet_stream = self.bets.stream(&storage, STORAGE_PAGE_SIZE);
+        while let Some(entry) = bet_stream.next().await {
+            match entry {
+                Ok((better, bet)) => {
+                    bets.insert(better, bet);
+                }
+                Err(ListEntryError::Storage(e)) => return Err(Error::RustError(e)),
+                Err(e) => console_error!("skipping bet entry: {e}"),
+            }
+        }
+
+        Ok(bets)
+    }
+
+    pub async fn round(&mut self) -> Result<u64> {
+        if let Some(round) = self.round {
+            return Ok(round);
+        };
+
+        let round = self
+            .backend
+            .storage()
+            .get("current-round")
+            .await?
+            .unwrap_or_default();
+
+        self.round = Some(round);
+        Ok(round)
+    }
+
+    /// stage clearing the bets and counters of the current round
+    async fn clear_round_txn(&mut self, txn: &mut StorageTransaction<'_>) -> Result<()> {
+        self.bets.clear_txn(txn).await?;
+        txn.delete("pumps");
+        txn.delete("dumps");
+        txn.delete("has_tide_shifted");
+        txn.delete("round-timing");
+
+        Ok(())
+    }
+
+    /// `(game_canister, token_root)` of the game, stored on the first websocket connection
+    pub async fn game_ids(&self) -> Result<Option<(Principal, Principal)>> {
+        self.backend.storage().get("game-ids").await
+    }
+
+    pub async fn set_game_ids(
+        &mut self,
+        game_canister: Principal,
+        token_root: Principal,
+    ) -> Result<()> {
+        if self.game_ids().await?.is_some() {
+            return Ok(());
+        }
+
+        self.backend
+            .storage()
+            .put("game-ids", &(game_canister, token_root))
+            .await
+    }
+
+    pub async fn round_timing(&self) -> Result<Option<RoundTiming>> {
+        self.backend.storage().get("round-timing").await
+    }
+
+    /// record a bet placed at `now_ms` for round deadlines
+    pub async fn touch_round_timing(&mut self, now_ms: u64) -> Result<()> {
+        let timing = match self.round_timing().await? {
+            Some(timing) => RoundTiming {
+                last_bet_at_ms: now_ms,
+                ..timing
+            },
+            None => RoundTiming {
+                started_at_ms: now_ms,
+                last_bet_at_ms: now_ms,
+            },
+        };
+
+        self.backend.storage().put("round-timing", &timing).await
+    }
+
+    /// end the current round and queue its rewards
+    ///
+    /// the round is cleaned up along with the settlements so rewards can't be lost
+    pub async fn end_round(
+        &mut self,
+        game_creator: Principal,
+        token_root: Principal,
+        reason: RoundEndReason,
+        now_ms: u64,
+    ) -> Result<EndedRound> {
+        let total_dumps = self.cumulative_dumps().await?;
+        let total_pumps = self.cumulative_pumps().await?;
+        let round = self.round().await? + 1;
+
+        let pumps = self.pumps().await?;
+        let dumps = self.dumps().await?;
+        let bets = self.all_bets().await?;
+        let outcome = reason.outcome(pumps, dumps, total_pumps, total_dumps);
+        let rewards = RewardIter::new(
+            pumps,
+            dumps,
+            outcome,
+            game_creator,
+            token_root,
+            bets.clone(),
+        );
+
+        let result = GameResult {
+            direction: rewards.outcome,
+            reward_pool: rewards.reward_pool.clone(),
+            bet_count: rewards.bet_cnt,
+            new_round: round,
+        };
+
+        let lp_reward = rewards.liquidity_pool.clone();
+        let mut record = RoundRecord {
+            outcome: Some(rewards.outcome),
+            pool: pumps + dumps,
+            pumps,
+            dumps,
+            bet_count: rewards.bet_cnt,
+            reward_pool: rewards.reward_pool.clone(),
+            creator_reward: 0u32.into(),
+            lp_reward: lp_reward.clone(),
+            ended_at_ms: now_ms,
+        };
+
+        let mut storage = self.backend.storage();
+        let mut txn = storage.transaction();
+        self.clear_round_txn(&mut txn).await?;
+        txn.put("current-round", &round)?;
+        for (idx, (participant, reward)) in rewards.enumerate() {
+            if let StateDiff::CreatorReward(creator_reward) = &reward {
+                record.creator_reward = creator_reward.clone();
+            }
+            let entry = SettlementEntry {
+                participant,
+                settlement: Settlement::Reward(reward),
+                attempts: 0,
+            };
+            self.settlements
+                .insert_txn(&mut txn, &(round - 1, idx as u64), &entry)?;
+        }
+        self.history.insert_txn(&mut txn, &(round - 1), &record)?;
+        txn.commit().await?;
+        self.round = Some(round);
+        self.round_pumps = Some(0);
+        self.round_dumps = Some(0);
+
+        Ok(EndedRound {
+            new_round: round,
+            pumps,
+            dumps,
+            total_pumps,
+            total_dumps,
+            bets,
+            result,
+            lp_reward,
+        })
+    }
+
+    /// refund every bet of the current round and start a new one
+    ///
+    /// the refunded bets are taken out of the cumulative counters as well
+    pub async fn refund_current_round(&mut self, now_ms: u64) -> Result<usize> {
+        let round = self.round().await?;
+        let bets = self.all_bets().await?;
+        if bets.is_empty() {
+            return Ok(0);
+        }
+        let pumps = self.pumps().await?;
+        let dumps = self.dumps().await?;
+        let total_pumps = self.cumulative_pumps().await?.saturating_sub(pumps);
+        let total_dumps = self.cumulative_dumps().await?.saturating_sub(dumps);
+        let record = RoundRecord::refunded(pumps, dumps, now_ms);
+
+        let mut storage = self.backend.storage();
+        let mut txn = storage.transaction();
+        self.clear_round_txn(&mut txn).await?;
+        txn.put("current-round", &(round + 1))?;
+        txn.put("total-pumps", &total_pumps)?;
+        txn.put("total-dumps", &total_dumps)?;
+        for (idx, (participant, bet)) in bets.iter().enumerate() {
+            let entry = SettlementEntry {
+                participant: *participant,
+                settlement: Settlement::refund_for(*bet),
+                attempts: 0,
+            };
+            self.settlements
+                .insert_txn(&mut txn, &(round, idx as u64), &entry)?;
+        }
+        self.history.insert_txn(&mut txn, &round, &record)?;
+        txn.commit().await?;
+
+        self.round = Some(round + 1);
+        self.round_pumps = Some(0);
+        self.round_dumps = Some(0);
+        self.cumulative_pumps = Some(total_pumps);
+        self.cumulative_dumps = Some(total_dumps);
+
+        Ok(bets.len())
+    }
+
+    /// replace the undelivered rewards of a finished round with refunds of their stakes
+    ///
+    /// undelivered creator rewards are dropped and parked refunds are requeued.
+    /// rewards already delivered, and the liquidity pool reward paid when the round ended,
+    /// are not clawed back.
+    /// returns the number of settlements refunded, requeued or dropped
+    pub async fn refund_settled_round(&mut self, round: u64) -> Result<usize> {
+        let mut storage = self.backend.storage();
+        let mut requeued = vec![];
+        let mut parked = vec![];
+        for (map, is_parked) in [(&self.settlements, false), (&self.parked_settlements, true)] {
+            let mut entries = map.stream(&storage, STORAGE_PAGE_SIZE);
+            while let Some(entry) = entries.next().await {
+                match entry {
+                    Ok(((r, idx), entry)) if r == round => {
+                        if is_parked {
+                            parked.push(idx);
+                        }
+                        requeued.push((idx, entry));
+                    }
+                    Ok(_) => (),
+                    Err(ListEntryError::Storage(e)) => return Err(Error::RustError(e)),
+                    Err(e) => console_error!("skipping settlement: {e}"),
+                }
+            }
+        }
+
+        let record = self.history.get(&storage, &round).await?.cloned();
+        let mut refunded_rewards = false;
+        let mut dropped_creator_reward = false;
+        let mut updated = 0;
+        let mut txn = storage.transaction();
+        for (idx, mut entry) in requeued {
+            let mut key = (round, idx);
+            match &entry.settlement {
+                Settlement::Reward(StateDiff::CompletedGame(info)) => {
+                    entry.settlement = Settlement::refund_for([info.pumps, info.dumps]);
+                    self.settlements.remove_txn(&mut txn, &key);
+                    key.1 += REFUND_IDX_OFFSET;
+                    refunded_rewards = true;
+                }
+                Settlement::Reward(StateDiff::CreatorReward(_)) => {
+                    self.settlements.remove_txn(&mut txn, &(round, idx));
+                    dropped_creator_reward = true;
+                    updated += 1;
+                    continue;
+                }
+                Settlement::Refund { .. } if !parked.contains(&idx) => continue,
+                Settlement::Refund { .. } => (),
+            }
+            entry.attempts = 0;
+            self.settlements.insert_txn(&mut txn, &key, &entry)?;
+            updated += 1;
+        }
+        for idx in parked {
+            self.parked_settlements.remove_txn(&mut txn, &(round, idx));
+        }
+        if let (true, Some(mut record)) = (refunded_rewards || dropped_creator_reward, record) {
+            record.mark_refunded(dropped_creator_reward);
+            self.history.insert_txn(&mut txn, &round, &record)?;
+        }
+        txn.commit().await?;
+
+        Ok(updated)
+    }
+
+    /// the oldest settlements waiting to be delivered, up to `limit`
+    pub async fn pending_settlements(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<((u64, u64), SettlementEntry)>> {
+        let storage = self.backend.storage();
+        let mut pending = vec![];
+        let mut entries = self.settlements.stream(&storage, STORAGE_PAGE_SIZE);
+        while let Some(entry) = entries.next().await {
+            match entry {
+                Ok(settlement) => pending.push(settlement),
+                Err(ListEntryError::Storage(e)) => return Err(Error::RustError(e)),
+                Err(e) => console_error!("skipping settlement: {e}"),
+            }
+            if pending.len() >= limit {
+                break;
+            }
+        }
+
+        Ok(pending)
+    }
+
+    /// stage the outcome of a delivery attempt
+    ///
+    /// failed settlements are retried, and parked after [`SETTLEMENT_MAX_ATTEMPTS`]
+    /// so they stop holding up the rest, refunding the round requeues them
+    pub fn record_delivery_txn(
+        &mut self,
+        txn: &mut StorageTransaction<'_>,
+        key: &(u64, u64),
+        mut entry: SettlementEntry,
+        delivered: bool,
+    ) -> Result<Delivery> {
+        if delivered {
+            self.settlements.remove_txn(txn, key);
+            return Ok(Delivery::Delivered);
+        }
+
+        entry.attempts += 1;
+        if entry.attempts < SETTLEMENT_MAX_ATTEMPTS {
+            self.settlements.insert_txn(txn, key, &entry)?;
+            return Ok(Delivery::Retrying);
+        }
+
+        self.settlements.remove_txn(txn, key);
+        self.parked_settlements.insert_txn(txn, key, &entry)?;
+        Ok(Delivery::Parked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use worker_utils::storage::MemoryStorage;
+
+    use crate::consts::GDOLLR_TO_E8S;
+
+    use super::*;
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    const CREATOR: Principal = Principal::anonymous();
+    const TOKEN_ROOT: Principal = Principal::management_canister();
+
+    /// a pumps once, b dumps twice, the second dump ends the round
+    fn play_round(book: &mut RoundBook<MemoryStorage>) -> EndedRound {
+        assert!(!block_on(book.place_bet(user(1), GameDirection::Pump, 1)).unwrap());
+        assert!(!block_on(book.place_bet(user(2), GameDirection::Dump, 1)).unwrap());
+        assert!(block_on(book.place_bet(user(2), GameDirection::Dump, 1)).unwrap());
+
+        block_on(book.end_round(CREATOR, TOKEN_ROOT, RoundEndReason::TideShift, 100)).unwrap()
+    }
+
+    fn record(book: &mut RoundBook<MemoryStorage>, round: u64) -> RoundRecord {
+        let storage = book.backend.storage();
+        block_on(book.history.get(&storage, &round))
+            .unwrap()
+            .cloned()
+            .unwrap()
+    }
+
+    #[test]
+    fn bets_accumulate_per_user() {
+        let mut book = RoundBook::new(MemoryStorage::new());
+        block_on(book.place_bet(user(1), GameDirection::Pump, 2)).unwrap();
+        block_on(book.place_bet(user(1), GameDirection::Dump, 1)).unwrap();
+        block_on(book.place_bet(user(2), GameDirection::Dump, 3)).unwrap();
+
+        assert_eq!(block_on(book.user_bets(user(1))).unwrap(), [2, 1]);
+        assert_eq!(block_on(book.user_bets(user(2))).unwrap(), [0, 3]);
+        assert_eq!(block_on(book.pumps()).unwrap(), 2);
+        assert_eq!(block_on(book.dumps()).unwrap(), 4);
+        assert_eq!(block_on(book.cumulative_dumps()).unwrap(), 4);
+    }
+
+    #[test]
+    fn ended_round_queues_rewards_for_the_whole_pool() {
+        let mut book = RoundBook::new(MemoryStorage::new());
+
+        let ended = play_round(&mut book);
+
+        assert_eq!(ended.new_round, 1);
+        assert!(matches!(ended.result.direction, GameDirection::Dump));
+        assert_eq!(ended.result.bet_count, 2);
+        let settlements = block_on(book.pending_settlements(64)).unwrap();
+        assert_eq!(settlements.len(), 3);
+        let paid = settlements
+            .iter()
+            .map(|(_, entry)| match &entry.settlement {
+                Settlement::Reward(reward) => reward.reward(),
+                Settlement::Refund { .. } => panic!("round was not refunded"),
+            })
+            .fold(ended.lp_reward.clone(), |acc, reward| acc + reward);
+        assert_eq!(paid, Nat::from(GDOLLR_TO_E8S * 3));
+
+        let record = record(&mut book, 0);
+        assert!(matches!(record.outcome, Some(GameDirection::Dump)));
+        assert_eq!((record.pool, record.ended_at_ms), (3, 100));
+
+        // the next round starts clean, cumulative counters carry over
+        assert_eq!(block_on(book.round()).unwrap(), 1);
+        assert_eq!(block_on(book.user_bets(user(2))).unwrap(), [0, 0]);
+        let mut reloaded = RoundBook::new(book.backend.clone());
+        assert_eq!(block_on(reloaded.round()).unwrap(), 1);
+        assert_eq!(block_on(reloaded.dumps()).unwrap(), 0);
+        assert_eq!(block_on(reloaded.cumulative_dumps()).unwrap(), 2);
+        assert!(block_on(reloaded.round_timing()).unwrap().is_none());
+    }
+
+    #[test]
+    fn refunding_the_current_round_returns_every_stake() {
+        let mut book = RoundBook::new(MemoryStorage::new());
+        block_on(book.place_bet(user(1), GameDirection::Pump, 2)).unwrap();
+        block_on(book.place_bet(user(2), GameDirection::Pump, 1)).unwrap();
+
+        let refunded = block_on(book.refund_current_round(100)).unwrap();
+
+        assert_eq!(refunded, 2);
+        assert_eq!(block_on(book.round()).unwrap(), 1);
+        let mut refunds = block_on(book.pending_settlements(64))
+            .unwrap()
+            .into_iter()
+            .map(|(_, entry)| match entry.settlement {
+                Settlement::Refund { amount } => (entry.participant, amount),
+                Settlement::Reward(_) => panic!("round was refunded"),
+            })
+            .collect::<Vec<_>>();
+        refunds.sort_by_key(|(participant, _)| *participant);
+        assert_eq!(
+            refunds,
+            vec![
+                (user(1), Nat::from(GDOLLR_TO_E8S * 2)),
+                (user(2), Nat::from(GDOLLR_TO_E8S)),
+            ]
+        );
+        assert!(record(&mut book, 0).outcome.is_none());
+        assert_eq!(block_on(book.refund_current_round(100)).unwrap(), 0);
+    }
+
+    #[test]
+    fn refunded_bets_leave_the_cumulative_counters() {
+        let mut book = RoundBook::new(MemoryStorage::new());
+        play_round(&mut book);
+        block_on(book.place_bet(user(1), GameDirection::Pump, 2)).unwrap();
+
+        block_on(book.refund_current_round(100)).unwrap();
+
+        assert_eq!(block_on(book.cumulative_pumps()).unwrap(), 1);
+        assert_eq!(block_on(book.cumulative_dumps()).unwrap(), 2);
+        let mut reloaded = RoundBook::new(book.backend.clone());
+        assert_eq!(block_on(reloaded.cumulative_pumps()).unwrap(), 1);
+        assert_eq!(block_on(reloaded.cumulative_dumps()).unwrap(), 2);
+    }
+
+    #[test]
+    fn refunding_a_settled_round_drops_the_creator_reward() {
+        let mut book = RoundBook::new(MemoryStorage::new());
+        play_round(&mut book);
+
+        let updated = block_on(book.refund_settled_round(0)).unwrap();
+
+        assert_eq!(updated, 3);
+        let settlements = block_on(book.pending_settlements(64)).unwrap();
+        let refunded = settlements
+            .iter()
+            .map(|(_, entry)| match &entry.settlement {
+                Settlement::Refund { amount } => amount.clone(),
+                Settlement::Reward(_) => panic!("rewards of a refunded round are not paid"),
+            })
+            .fold(Nat::from(0u32), |acc, amount| acc + amount);
+        assert_eq!(settlements.len(), 2);
+        assert_eq!(refunded, Nat::from(GDOLLR_TO_E8S * 3));
+        let record = record(&mut book, 0);
+        assert!(record.outcome.is_none());
+        assert_eq!(record.reward_pool, Nat::from(0u32));
+        assert_eq!(record.creator_reward, Nat::from(0u32));
+    }
+
+    #[test]
+    fn refunds_of_a_settled_round_get_new_settlement_keys() {
+        let mut book = RoundBook::new(MemoryStorage::new());
+        play_round(&mut book);
+        let reward_keys = block_on(book.pending_settlements(64))
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+        block_on(book.refund_settled_round(0)).unwrap();
+
+        let refund_keys = block_on(book.pending_settlements(64))
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(refund_keys.len(), 2);
+        for key in refund_keys {
+            assert_eq!(key.0, 0);
+            assert!(key.1 >= REFUND_IDX_OFFSET);
+            assert!(!reward_keys.contains(&key));
+        }
+
+        // refunding again leaves the queued refunds as they are
+        assert_eq!(block_on(book.refund_settled_round(0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn failing_settlement_is_parked_until_the_round_is_refunded() {
+        let mut book = RoundBook::new(MemoryStorage::new());
+        play_round(&mut book);
+        let (key, _) = block_on(book.pending_settlements(1)).unwrap().remove(0);
+
+        for attempt in 1..=SETTLEMENT_MAX_ATTEMPTS {
+            let (_, entry) = block_on(book.pending_settlements(64))
+                .unwrap()
+                .into_iter()
+                .find(|(k, _)| *k == key)
+                .unwrap();
+            let mut storage = book.backend.storage();
+            let mut txn = storage.transaction();
+            let delivery = book
+                .record_delivery_txn(&mut txn, &key, entry, false)
+                .unwrap();
+            block_on(txn.commit()).unwrap();
+
+            let expected = if attempt < SETTLEMENT_MAX_ATTEMPTS {
+                Delivery::Retrying
+            } else {
+                Delivery::Parked
+            };
+            assert_eq!(delivery, expected);
+        }
+        assert_eq!(block_on(book.pending_settlements(64)).unwrap().len(), 2);
+
+        block_on(book.refund_settled_round(0)).unwrap();
+
+        // the parked reward comes back as a refund, the creator reward is dropped
+        let settlements = block_on(book.pending_settlements(64)).unwrap();
+        assert_eq!(settlements.len(), 2);
+        assert!(settlements.iter().all(|(_, entry)| entry.attempts == 0));
+        assert!(!book
+            .backend
+            .keys()
+            .iter()
+            .any(|key| key.starts_with("parked-settlement-")));
+    }
+
+    #[test]
+    fn delivered_settlement_is_removed() {
+        let mut book = RoundBook::new(MemoryStorage::new());
+        play_round(&mut book);
+        let (key, entry) = block_on(book.pending_settlements(1)).unwrap().remove(0);
+
+        let mut storage = book.backend.storage();
+        let mut txn = storage.transaction();
+        let delivery = book
+            .record_delivery_txn(&mut txn, &key, entry, true)
+            .unwrap();
+        block_on(txn.commit()).unwrap();
+
+        assert_eq!(delivery, Delivery::Delivered);
+        let settlements = block_on(book.pending_settlements(64)).unwrap();
+        assert!(settlements.iter().all(|(k, _)| *k != key));
+    }
+}
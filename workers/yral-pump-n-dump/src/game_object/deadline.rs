@@ -0,0 +1,89 @@
+use pump_n_dump_common::GameDirection;
+use serde::{Deserialize, Serialize};
+use worker::Env;
+
+/// hard limit on how long a round stays open after its first bet, unset to disable
+const ROUND_MAX_DURATION_VAR: &str = "ROUND_MAX_DURATION_MS";
+/// round ends if no bets are placed for this long, unset to disable
+const ROUND_INACTIVITY_TIMEOUT_VAR: &str = "ROUND_INACTIVITY_TIMEOUT_MS";
+
+/// optional deadlines for a round, the round still ends early on a tide shift
+#[derive(Clone, Copy, Default)]
+pub struct RoundLimits {
+    pub max_duration_ms: Option<u64>,
+    pub inactivity_timeout_ms: Option<u64>,
+}
+
+impl RoundLimits {
+    pub fn from_env(env: &Env) -> Self {
+        let var_ms = |name: &str| {
+            env.var(name)
+                .ok()
+                .and_then(|v| v.to_string().parse::<u64>().ok())
+                .filter(|ms| *ms > 0)
+        };
+
+        Self {
+            max_duration_ms: var_ms(ROUND_MAX_DURATION_VAR),
+            inactivity_timeout_ms: var_ms(ROUND_INACTIVITY_TIMEOUT_VAR),
+        }
+    }
+
+    /// ms since epoch at which the round ends, `None` if no limit is configured
+    pub fn deadline(&self, timing: RoundTiming) -> Option<u64> {
+        let by_duration = self
+            .max_duration_ms
+            .map(|ms| timing.started_at_ms.saturating_add(ms));
+        let by_inactivity = self
+            .inactivity_timeout_ms
+            .map(|ms| timing.last_bet_at_ms.saturating_add(ms));
+
+        match (by_duration, by_inactivity) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// rounds only start ticking once the first bet is placed,
+/// a round without bets has no funds to settle
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct RoundTiming {
+    /// ms since epoch
+    pub started_at_ms: u64,
+    /// ms since epoch
+    pub last_bet_at_ms: u64,
+}
+
+#[derive(Clone, Copy)]
+pub enum RoundEndReason {
+    TideShift,
+    Deadline,
+}
+
+impl RoundEndReason {
+    /// the winning direction of a round
+    ///
+    /// on a tide shift the side with more bets this round wins, ties go to dump.
+    /// on a deadline the side with more bets this round wins as well,
+    /// but ties go to the side leading overall and only then to dump
+    pub fn outcome(
+        self,
+        pumps: u64,
+        dumps: u64,
+        total_pumps: u64,
+        total_dumps: u64,
+    ) -> GameDirection {
+        match self {
+            _ if pumps != dumps => {
+                if pumps > dumps {
+                    GameDirection::Pump
+                } else {
+                    GameDirection::Dump
+                }
+            }
+            Self::Deadline if total_pumps > total_dumps => GameDirection::Pump,
+            _ => GameDirection::Dump,
+        }
+    }
+}
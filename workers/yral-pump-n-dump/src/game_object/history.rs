@@ -0,0 +1,125 @@
+use candid::Nat;
+use pump_n_dump_common::GameDirection;
+use serde::{Deserialize, Serialize};
+use worker::Result;
+use worker_utils::storage::{Migration, VersionedSchema};
+
+use super::GameState;
+
+const DEFAULT_ROUNDS_PAGE_SIZE: usize = 20;
+const MAXIMUM_ROUNDS_PAGE_SIZE: usize = 100;
+
+/// a finished round, archived under `hist-{round}`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RoundRecord {
+    /// `None` if the round was refunded
+    pub outcome: Option<GameDirection>,
+    /// pumps + dumps
+    pub pool: u64,
+    pub pumps: u64,
+    pub dumps: u64,
+    /// bets on the winning side
+    pub bet_count: u64,
+    pub reward_pool: Nat,
+    pub creator_reward: Nat,
+    pub lp_reward: Nat,
+    /// ms since epoch
+    pub ended_at_ms: u64,
+}
+
+impl VersionedSchema for RoundRecord {
+    type Value = Self;
+
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+impl RoundRecord {
+    pub fn refunded(pumps: u64, dumps: u64, ended_at_ms: u64) -> Self {
+        Self {
+            outcome: None,
+            pool: pumps + dumps,
+            pumps,
+            dumps,
+            bet_count: 0,
+            reward_pool: 0u32.into(),
+            creator_reward: 0u32.into(),
+            lp_reward: 0u32.into(),
+            ended_at_ms,
+        }
+    }
+
+    /// the winners' rewards were replaced with refunds after the round ended
+    ///
+    /// the liquidity pool reward was paid when the round ended and stays recorded,
+    /// the creator reward too unless it was dropped before delivery
+    pub fn mark_refunded(&mut self, creator_reward_dropped: bool) {
+        self.outcome = None;
+        self.bet_count = 0;
+        self.reward_pool = 0u32.into();
+        if creator_reward_dropped {
+            self.creator_reward = 0u32.into();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RoundInfo {
+    pub round: u64,
+    #[serde(flatten)]
+    pub record: RoundRecord,
+}
+
+impl RoundInfo {
+    pub fn new(round: u64, record: RoundRecord) -> Self {
+        Self { round, record }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RoundsQuery {
+    /// first round to return
+    #[serde(default)]
+    pub cursor: Option<u64>,
+    #[serde(default)]
+    pub page_size: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RoundsRes {
+    /// oldest first
+    pub rounds: Vec<RoundInfo>,
+    pub next: Option<u64>,
+}
+
+impl GameState {
+    pub(super) async fn rounds(&self, query: RoundsQuery) -> Result<RoundsRes> {
+        let page_size = query
+            .page_size
+            .unwrap_or(DEFAULT_ROUNDS_PAGE_SIZE)
+            .clamp(1, MAXIMUM_ROUNDS_PAGE_SIZE);
+        let storage = self.storage();
+        let mut page = self
+            .book
+            .history
+            .page(&storage, query.cursor.as_ref(), page_size + 1)
+            .await?;
+
+        let next = (page.len() > page_size)
+            .then(|| page.pop())
+            .flatten()
+            .map(|(round, _)| round);
+        let rounds = page
+            .into_iter()
+            .map(|(round, record)| RoundInfo::new(round, record))
+            .collect();
+
+        Ok(RoundsRes { rounds, next })
+    }
+
+    pub(super) async fn round_info(&mut self, round: u64) -> Result<Option<RoundInfo>> {
+        let storage = self.storage();
+        let record = self.book.history.get(&storage, &round).await?.cloned();
+
+        Ok(record.map(|record| RoundInfo::new(round, record)))
+    }
+}
@@ -1,27 +1,35 @@
+mod book;
+mod deadline;
+mod history;
+mod settlement;
 mod ws;
 
 use std::{
     collections::{hash_map, HashMap},
-    future::Future,
+    rc::Rc,
 };
 
 use crate::{
     backend_impl::{GameBackend, GameBackendImpl},
-    consts::{GDOLLR_TO_E8S, TIDE_SHIFT_DELTA},
-    user_reconciler::{AddRewardReq, DecrementReq, StateDiff},
+    consts::{
+        ALARM_RETRY_MS, GDOLLR_TO_E8S, ROUND_COUNTDOWN_INTERVAL_MS, WS_IDLE_CHECK_INTERVAL_MS,
+    },
+    user_reconciler::{DecrementReq, StateDiff},
     utils::{metrics, CfMetricTx},
 };
+use book::{EndedRound, RoundBook};
 use candid::{Nat, Principal};
-use futures::{stream::FuturesUnordered, StreamExt};
+use deadline::{RoundEndReason, RoundLimits};
+use history::RoundsQuery;
 use pump_n_dump_common::{
     rest::{CompletedGameInfo, UserBetsResponse},
-    ws::{GameResult, WsResp},
+    ws::WsResp,
     GameDirection,
 };
 use serde::{Deserialize, Serialize};
 use wasm_bindgen_futures::spawn_local;
 use worker::*;
-use worker_utils::{storage::SafeStorage, RequestInitBuilder};
+use worker_utils::{parse_principal, storage::SafeStorage, RequestInitBuilder};
 use yral_metrics::metrics::tides_turned::TidesTurned;
 
 #[derive(Serialize, Deserialize, Clone, Copy)]
@@ -33,18 +41,12 @@ pub struct TotalBetsInfo {
 
 #[durable_object]
 pub struct GameState {
-    state: State,
+    state: Rc<State>,
     env: Env,
-    has_tide_shifted: Option<bool>,
-    round_pumps: Option<u64>,
-    round_dumps: Option<u64>,
-    cumulative_pumps: Option<u64>,
-    cumulative_dumps: Option<u64>,
-    // Principal: (pumps, dumps)
-    bets: Option<HashMap<Principal, [u64; 2]>>,
-    round: Option<u64>,
+    book: RoundBook<Rc<State>>,
     backend: GameBackend,
     metrics: CfMetricTx,
+    round_limits: RoundLimits,
 }
 
 struct GameObjReq {
@@ -53,6 +55,8 @@ struct GameObjReq {
     pub creator: Principal,
     pub token_root: Principal,
     pub round: u64,
+    /// number of bets placed at once
+    pub count: u64,
 }
 
 struct RewardIter {
@@ -114,6 +118,7 @@ impl RewardIter {
     pub fn new(
         pumps: u64,
         dumps: u64,
+        outcome: GameDirection,
         creator: Principal,
         token_root: Principal,
         bets: HashMap<Principal, [u64; 2]>,
@@ -127,10 +132,9 @@ impl RewardIter {
         let liquidity_pool = creator_reward.clone();
 
         let remaining = total - creator_reward.clone() - liquidity_pool.clone();
-        let (outcome, bet_cnt) = if pumps > dumps {
-            (GameDirection::Pump, pumps)
-        } else {
-            (GameDirection::Dump, dumps)
+        let bet_cnt = match outcome {
+            GameDirection::Pump => pumps,
+            GameDirection::Dump => dumps,
         };
 
         Self {
@@ -151,139 +155,68 @@ impl GameState {
         self.state.storage().into()
     }
 
-    async fn pumps(&mut self) -> Result<u64> {
-        if let Some(p) = self.round_pumps {
-            return Ok(p);
-        }
-
-        let pumps = self.storage().get("pumps").await?.unwrap_or_default();
-        self.round_pumps = Some(pumps);
-        Ok(pumps)
-    }
-
-    async fn dumps(&mut self) -> Result<u64> {
-        if let Some(d) = self.round_dumps {
-            return Ok(d);
-        }
-
-        let dumps = self.storage().get("dumps").await?.unwrap_or_default();
-        self.round_dumps = Some(dumps);
-        Ok(dumps)
-    }
-
-    async fn cumulative_pumps(&mut self) -> Result<u64> {
-        if let Some(tot) = self.cumulative_pumps {
-            return Ok(tot);
-        }
-
-        let pumps = self.storage().get("total-pumps").await?.unwrap_or_default();
-        self.cumulative_pumps = Some(pumps);
-        Ok(pumps)
-    }
-
-    async fn cumulative_dumps(&mut self) -> Result<u64> {
-        if let Some(tot) = self.cumulative_dumps {
-            return Ok(tot);
-        }
+    /// ms since epoch at which the current round ends if no tide shift happens before
+    pub async fn round_deadline(&self) -> Result<Option<u64>> {
+        let Some(timing) = self.book.round_timing().await? else {
+            return Ok(None);
+        };
 
-        let dumps = self.storage().get("total-dumps").await?.unwrap_or_default();
-        self.cumulative_dumps = Some(dumps);
-        Ok(dumps)
+        Ok(self.round_limits.deadline(timing))
     }
 
-    async fn increment_pumps_inner(&mut self) -> Result<u64> {
-        let total_pumps = self.cumulative_pumps().await? + 1;
-        let pumps = self.pumps().await? + 1;
-
-        let mut storage = self.storage();
-        storage.put("total-pumps", &total_pumps).await?;
-        storage.put("pumps", &pumps).await?;
-
-        self.cumulative_pumps = Some(total_pumps);
-        self.round_pumps = Some(pumps);
+    /// wake up at the deadline, or earlier to broadcast a countdown if anyone is watching
+    async fn schedule_round_alarm(&mut self, deadline_ms: u64) -> Result<()> {
+        let now = Date::now().as_millis();
+        let wake_at = if self.state.get_websockets().is_empty() {
+            deadline_ms
+        } else {
+            deadline_ms.min(now + ROUND_COUNTDOWN_INTERVAL_MS)
+        };
 
-        Ok(pumps)
+        self.schedule_alarm_at(wake_at).await
     }
 
-    async fn increment_dumps_inner(&mut self) -> Result<u64> {
-        let total_dumps = self.cumulative_dumps().await? + 1;
-        let dumps = self.dumps().await? + 1;
-
+    /// schedule the alarm at `at_ms`, unless it is already scheduled earlier
+    async fn schedule_alarm_at(&mut self, at_ms: u64) -> Result<()> {
         let mut storage = self.storage();
-        storage.put("total-dumps", &total_dumps).await?;
-        storage.put("dumps", &dumps).await?;
-
-        self.cumulative_dumps = Some(total_dumps);
-        self.round_dumps = Some(dumps);
+        let now = Date::now().as_millis();
+        if let Some(alarm) = storage.get_alarm().await? {
+            // an alarm in the past is the one currently running
+            if alarm as u64 > now && alarm as u64 <= at_ms {
+                return Ok(());
+            }
+        }
 
-        Ok(dumps)
+        storage.set_alarm(at_ms.saturating_sub(now) as i64).await
     }
 
-    async fn has_tide_shifted(&mut self) -> Result<bool> {
-        if let Some(shifted) = self.has_tide_shifted {
-            return Ok(shifted);
+    /// end the round if its deadline passed, or announce the countdown
+    async fn handle_round_deadline(&mut self) -> Result<&'static str> {
+        let Some(deadline) = self.round_deadline().await? else {
+            return Ok("no deadline");
         };
-
-        let shifted = self
-            .storage()
-            .get("has_tide_shifted")
-            .await?
-            .unwrap_or_default();
-        self.has_tide_shifted = Some(shifted);
-
-        Ok(shifted)
-    }
-
-    async fn set_tide_shifted(&mut self) -> Result<()> {
-        self.has_tide_shifted = Some(true);
-        self.storage().put("has_tide_shifted", &true).await?;
-
-        Ok(())
-    }
-
-    async fn bets(&mut self) -> Result<&mut HashMap<Principal, [u64; 2]>> {
-        if self.bets.is_some() {
-            return Ok(self.bets.as_mut().unwrap());
+        if deadline > Date::now().as_millis() {
+            self.announce_round_deadline(deadline).await?;
+            return Ok("countdown");
         }
 
-        let bets = self
-            .storage()
-            .list_with_prefix("bets-")
-            .await
-            .map(|v| {
-                v.map(|(k, v)| {
-                    let better = Principal::from_text(k.strip_prefix("bets-").unwrap()).unwrap();
-                    (better, v)
-                })
-            })
-            .collect::<Result<_>>()?;
-
-        self.bets = Some(bets);
-        Ok(self.bets.as_mut().unwrap())
-    }
-
-    pub async fn round(&mut self) -> Result<u64> {
-        if let Some(round) = self.round {
-            return Ok(round);
+        let Some((game_creator, token_root)) = self.book.game_ids().await? else {
+            console_warn!("round deadline set without game ids?!");
+            return Ok("not ready");
         };
+        let responses = self
+            .round_end(game_creator, token_root, RoundEndReason::Deadline)
+            .await?;
+        for resp in responses {
+            if matches!(
+                resp,
+                WsResp::GameResultEvent(_) | WsResp::WinningPoolEvent { .. }
+            ) {
+                self.broadcast_event(resp)?;
+            }
+        }
 
-        let round = self
-            .storage()
-            .get("current-round")
-            .await?
-            .unwrap_or_default();
-
-        self.round = Some(round);
-        Ok(round)
-    }
-
-    /// advance the game round, returning the new round
-    pub async fn advance_round(&mut self) -> Result<u64> {
-        let new_round = self.round().await? + 1;
-        self.round = Some(new_round);
-        self.storage().put("current-round", &new_round).await?;
-
-        Ok(new_round)
+        Ok("round ended")
     }
 
     fn user_state_stub(&self, user: Principal) -> Result<Stub> {
@@ -293,63 +226,27 @@ impl GameState {
         user_state_obj.get_stub()
     }
 
-    fn send_reward_to_user(
-        &self,
-        user: Principal,
-        state_diff: StateDiff,
-    ) -> Result<impl Future<Output = Result<()>> + 'static> {
-        let body = AddRewardReq {
-            state_diff,
-            user_canister: user,
-        };
-        let req = Request::new_with_init(
-            "http://fake_url.com/add_reward",
-            RequestInitBuilder::default()
-                .method(Method::Post)
-                .json(&body)?
-                .build(),
-        )?;
-        let user_state = self.user_state_stub(user)?;
-
-        Ok(async move {
-            user_state.fetch_with_request(req).await?;
-            Ok(())
-        })
-    }
-
     async fn round_end(
         &mut self,
         game_creator: Principal,
         token_root: Principal,
+        reason: RoundEndReason,
     ) -> Result<Vec<WsResp>> {
-        let total_dumps = self.cumulative_dumps().await?;
-        let total_pumps = self.cumulative_pumps().await?;
-        let round = self.advance_round().await?;
-
-        let pumps = self.pumps().await?;
-        let dumps = self.dumps().await?;
-        let bets = std::mem::take(self.bets().await?);
-        let rewards = RewardIter::new(pumps, dumps, game_creator, token_root, bets.clone());
-
-        let winning_pool = pumps + dumps;
-        // cleanup
-        let mut storage = self.storage();
-        storage.delete_all().await?;
-        self.round_pumps = Some(0);
-        self.round_dumps = Some(0);
-
-        storage.put("total-dumps", &total_dumps).await?;
-        storage.put("total-pumps", &total_pumps).await?;
-        storage.put("current-round", &round).await?;
-
-        let game_res = GameResult {
-            direction: rewards.outcome,
-            reward_pool: rewards.reward_pool.clone(),
-            bet_count: rewards.bet_cnt,
+        let EndedRound {
             new_round: round,
-        };
-
-        let lp_reward = rewards.liquidity_pool.clone();
+            pumps,
+            dumps,
+            total_pumps,
+            total_dumps,
+            bets,
+            result: game_res,
+            lp_reward,
+        } = self
+            .book
+            .end_round(game_creator, token_root, reason, Date::now().as_millis())
+            .await?;
+        let winning_pool = pumps + dumps;
+        self.schedule_alarm_at(Date::now().as_millis()).await?;
 
         let metrics = self.metrics.clone();
         let metrics_list = bets
@@ -376,18 +273,6 @@ impl GameState {
             console_warn!("failed to push metrics tides_turned: {e}");
         }
 
-        let mut reward_futs = rewards
-            .map(|(winner, reward)| self.send_reward_to_user(winner, reward))
-            .collect::<Result<FuturesUnordered<_>>>()?;
-
-        spawn_local(async move {
-            while let Some(res) = reward_futs.next().await {
-                if let Err(e) = res {
-                    console_warn!("failed to reward user: {e}")
-                }
-            }
-        });
-
         let backend = self.backend.clone();
 
         spawn_local(async move {
@@ -409,47 +294,24 @@ impl GameState {
         ])
     }
 
-    async fn tide_shift_check(&mut self, with: u64, other: u64) -> Result<bool> {
-        let prev_delta = (with - 1).saturating_sub(other);
-        let new_delta = (with).saturating_sub(other);
-
-        let shifted = prev_delta < TIDE_SHIFT_DELTA && new_delta >= TIDE_SHIFT_DELTA;
-        if !shifted {
-            return Ok(false);
-        }
-
-        if !self.has_tide_shifted().await? {
-            self.set_tide_shifted().await?;
-            return Ok(false);
-        }
-
-        Ok(true)
-    }
-
-    async fn increment_pumps(
+    async fn place_bet(
         &mut self,
         game_creator: Principal,
         token_root: Principal,
         sender: Principal,
+        direction: GameDirection,
+        count: u64,
     ) -> Result<Vec<WsResp>> {
-        let bets = self.bets().await?.entry(sender).or_insert([0, 0]);
-        bets[0] += 1;
-        let bets = *bets;
-
-        self.increment_pumps_inner().await?;
-
-        let total_pumps = self.cumulative_pumps().await?;
-        let total_dumps = self.cumulative_dumps().await?;
-        let tide_shifted = self.tide_shift_check(total_pumps, total_dumps).await?;
+        let tide_shifted = self.book.place_bet(sender, direction, count).await?;
 
         if tide_shifted {
-            return self.round_end(game_creator, token_root).await;
+            return self
+                .round_end(game_creator, token_root, RoundEndReason::TideShift)
+                .await;
         }
 
-        self.storage().put(&format!("bets-{sender}"), &bets).await?;
-
-        let round = self.round().await?;
-        let pool = self.pumps().await? + self.dumps().await?;
+        let round = self.book.round().await?;
+        let pool = self.book.pumps().await? + self.book.dumps().await?;
 
         Ok(vec![
             WsResp::BetSuccesful { round },
@@ -460,42 +322,8 @@ impl GameState {
         ])
     }
 
-    async fn increment_dumps(
-        &mut self,
-        game_creator: Principal,
-        token_root: Principal,
-        sender: Principal,
-    ) -> Result<Vec<WsResp>> {
-        let bets = self.bets().await?.entry(sender).or_insert([0, 0]);
-        bets[1] += 1;
-        let bets = *bets;
-
-        self.increment_dumps_inner().await?;
-
-        let total_pumps = self.cumulative_pumps().await?;
-        let total_dumps = self.cumulative_dumps().await?;
-        let tide_shifted = self.tide_shift_check(total_dumps, total_pumps).await?;
-
-        if tide_shifted {
-            return self.round_end(game_creator, token_root).await;
-        }
-
-        self.storage().put(&format!("bets-{sender}"), &bets).await?;
-
-        let round = self.round().await?;
-        let pool = self.pumps().await? + self.dumps().await?;
-
-        Ok(vec![
-            WsResp::BetSuccesful { round },
-            WsResp::WinningPoolEvent {
-                new_pool: pool,
-                round,
-            },
-        ])
-    }
-
     async fn game_request(&mut self, game_req: GameObjReq) -> Result<Vec<WsResp>> {
-        if self.round().await? != game_req.round {
+        if self.book.round().await? != game_req.round {
             return Err(Error::RustError("round mismatch".into()));
         }
 
@@ -503,6 +331,7 @@ impl GameState {
         let body = DecrementReq {
             user_canister: game_req.sender,
             token_root: game_req.token_root,
+            count: game_req.count,
         };
         let req = Request::new_with_init(
             "http://fake_url.com/decrement",
@@ -517,16 +346,19 @@ impl GameState {
             return Err(worker::Error::RustError(res.text().await.unwrap()));
         }
 
-        match game_req.direction {
-            GameDirection::Pump => {
-                self.increment_pumps(game_req.creator, game_req.token_root, game_req.sender)
-                    .await
-            }
-            GameDirection::Dump => {
-                self.increment_dumps(game_req.creator, game_req.token_root, game_req.sender)
-                    .await
-            }
-        }
+        // a round ending on this bet clears the timing
+        self.book
+            .touch_round_timing(Date::now().as_millis())
+            .await?;
+
+        self.place_bet(
+            game_req.creator,
+            game_req.token_root,
+            game_req.sender,
+            game_req.direction,
+            game_req.count,
+        )
+        .await
     }
 }
 
@@ -540,18 +372,16 @@ impl DurableObject for GameState {
             Err(e) => panic!("Failed to create backend: {e}"),
         };
 
+        let round_limits = RoundLimits::from_env(&env);
+        let state = Rc::new(state);
+
         Self {
+            book: RoundBook::new(state.clone()),
             state,
             env,
-            round_pumps: None,
-            round_dumps: None,
-            bets: None,
             backend,
-            has_tide_shifted: None,
-            cumulative_pumps: None,
-            cumulative_dumps: None,
-            round: None,
             metrics: metrics(),
+            round_limits,
         }
     }
 
@@ -566,12 +396,7 @@ impl DurableObject for GameState {
                 };
 
                 let this = ctx.data;
-                let bets = this
-                    .bets()
-                    .await?
-                    .get(&user_canister)
-                    .copied()
-                    .unwrap_or_default();
+                let bets = this.book.user_bets(user_canister).await?;
 
                 Response::from_json(&UserBetsResponse {
                     pumps: bets[0],
@@ -594,27 +419,70 @@ impl DurableObject for GameState {
 
                     let pair = WebSocketPair::new()?;
                     ctx.data
-                        .handle_ws(pair.server, game_canister, token_root, user_canister)
+                        .handle_ws(pair.server, game_canister, token_root, Some(user_canister))
                         .await?;
 
                     Response::from_websocket(pair.client)
                 },
             )
+            .get_async(
+                "/spectate/:game_canister/:token_root",
+                |req, ctx| async move {
+                    let upgrade = req.headers().get("Upgrade")?;
+                    if upgrade.as_deref() != Some("websocket") {
+                        return Response::error("expected websocket", 400);
+                    }
+                    let game_canister = parse_principal!(ctx, "game_canister");
+                    let token_root = parse_principal!(ctx, "token_root");
+
+                    let pair = WebSocketPair::new()?;
+                    ctx.data
+                        .handle_ws(pair.server, game_canister, token_root, None)
+                        .await?;
+
+                    Response::from_websocket(pair.client)
+                },
+            )
+            .post_async("/refund_round/:round", |_req, ctx| async move {
+                let Ok(round) = ctx.param("round").unwrap().parse::<u64>() else {
+                    return Response::error("Invalid round", 400);
+                };
+
+                ctx.data.refund_round(round).await
+            })
+            .get_async("/rounds", |req, ctx| async move {
+                let Ok(query) = req.query::<RoundsQuery>() else {
+                    return Response::error("Invalid query", 400);
+                };
+
+                let res = ctx.data.rounds(query).await?;
+                Response::from_json(&res)
+            })
+            .get_async("/round/:round", |_req, ctx| async move {
+                let Ok(round) = ctx.param("round").unwrap().parse::<u64>() else {
+                    return Response::error("Invalid round", 400);
+                };
+
+                match ctx.data.round_info(round).await? {
+                    Some(info) => Response::from_json(&info),
+                    None => Response::error("round not found", 404),
+                }
+            })
             .get_async("/game_pool", |_req, ctx| async move {
                 let this = ctx.data;
-                let total = this.dumps().await? + this.pumps().await?;
+                let total = this.book.dumps().await? + this.book.pumps().await?;
                 Response::ok(total.to_string())
             })
             .get("/player_count", |_req, ctx| {
                 let this = ctx.data;
-                let player_cnt = this.state.get_websockets().len();
+                let player_cnt = this.players().len();
                 Response::ok(player_cnt.to_string())
             })
             .get_async("/total_bets_info", |_req, ctx| async move {
                 let this = ctx.data;
-                let pumps = this.cumulative_pumps().await?;
-                let dumps = this.cumulative_dumps().await?;
-                let round = this.round().await?;
+                let pumps = this.book.cumulative_pumps().await?;
+                let dumps = this.book.cumulative_dumps().await?;
+                let round = this.book.round().await?;
 
                 let res = TotalBetsInfo {
                     pumps,
@@ -628,6 +496,45 @@ impl DurableObject for GameState {
             .await
     }
 
+    async fn alarm(&mut self) -> Result<Response> {
+        let mut failed = false;
+        if let Err(e) = self.deliver_settlements().await {
+            console_error!("failed to deliver settlements: {e}");
+            failed = true;
+        }
+        match self.close_idle_sockets() {
+            Ok(false) => (),
+            Ok(true) => {
+                if let Err(e) = self
+                    .schedule_alarm_at(Date::now().as_millis() + WS_IDLE_CHECK_INTERVAL_MS)
+                    .await
+                {
+                    console_error!("failed to schedule idle socket check: {e}");
+                    failed = true;
+                }
+            }
+            Err(e) => {
+                console_error!("failed to close idle sockets: {e}");
+                failed = true;
+            }
+        }
+        if let Err(e) = self.broadcast_player_count().await {
+            console_error!("failed to broadcast player count: {e}");
+        }
+
+        let res = self.handle_round_deadline().await;
+        if let Err(e) = &res {
+            console_error!("failed to handle round deadline: {e}");
+            failed = true;
+        }
+        if failed {
+            self.schedule_alarm_at(Date::now().as_millis() + ALARM_RETRY_MS)
+                .await?;
+        }
+
+        Response::ok(res.unwrap_or("retrying"))
+    }
+
     async fn websocket_message(
         &mut self,
         ws: WebSocket,
@@ -639,7 +546,7 @@ impl DurableObject for GameState {
     }
 
     async fn websocket_error(&mut self, ws: WebSocket, error: worker::Error) -> Result<()> {
-        ws.close(Some(500), Some(error.to_string()))
+        self.handle_ws_close(&ws, 500, &error.to_string()).await
     }
 
     async fn websocket_close(
@@ -649,6 +556,6 @@ impl DurableObject for GameState {
         reason: String,
         _was_clean: bool,
     ) -> Result<()> {
-        ws.close(Some(code as u16), Some(reason))
+        self.handle_ws_close(&ws, code as u16, &reason).await
     }
 }
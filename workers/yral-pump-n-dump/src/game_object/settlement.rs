@@ -0,0 +1,196 @@
+use std::future::Future;
+
+use candid::{Nat, Principal};
+use futures::future::join_all;
+use pump_n_dump_common::ws::WsResp;
+use serde::{Deserialize, Serialize};
+use worker::*;
+use worker_utils::{
+    storage::{Migration, VersionedSchema},
+    RequestInitBuilder,
+};
+
+use crate::{
+    consts::{GDOLLR_TO_E8S, SETTLEMENT_RETRY_MS},
+    user_reconciler::{AddRewardReq, RefundReq, StateDiff},
+};
+
+use super::{book::Delivery, GameState};
+
+// settlements delivered per alarm
+const SETTLEMENT_BATCH: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Settlement {
+    Reward(StateDiff),
+    /// the stake is returned without settling the round
+    Refund {
+        amount: Nat,
+    },
+}
+
+/// what a participant is owed for a round, kept until their user state acks it
+///
+/// keyed by `(round, idx)` as the creator can be owed both a reward and a creator reward
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SettlementEntry {
+    pub participant: Principal,
+    pub settlement: Settlement,
+    /// failed deliveries so far, the entry is parked after [`crate::consts::SETTLEMENT_MAX_ATTEMPTS`]
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+impl VersionedSchema for SettlementEntry {
+    type Value = Self;
+
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
+impl Settlement {
+    pub fn refund_for(bets: [u64; 2]) -> Self {
+        Self::Refund {
+            amount: Nat::from(GDOLLR_TO_E8S) * (bets[0] + bets[1]),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RefundRoundRes {
+    pub round: u64,
+    pub refunded: usize,
+}
+
+impl GameState {
+    fn send_settlement(
+        &self,
+        user: Principal,
+        token_root: Principal,
+        settlement_id: String,
+        settlement: Settlement,
+    ) -> Result<impl Future<Output = Result<()>> + 'static> {
+        let req = match settlement {
+            Settlement::Reward(state_diff) => Request::new_with_init(
+                "http://fake_url.com/add_reward",
+                RequestInitBuilder::default()
+                    .method(Method::Post)
+                    .json(&AddRewardReq {
+                        state_diff,
+                        user_canister: user,
+                        settlement_id: Some(settlement_id),
+                    })?
+                    .build(),
+            )?,
+            Settlement::Refund { amount } => Request::new_with_init(
+                "http://fake_url.com/refund",
+                RequestInitBuilder::default()
+                    .method(Method::Post)
+                    .json(&RefundReq {
+                        user_canister: user,
+                        token_root,
+                        amount,
+                        settlement_id,
+                    })?
+                    .build(),
+            )?,
+        };
+        let user_state = self.user_state_stub(user)?;
+
+        Ok(async move {
+            let mut res = user_state.fetch_with_request(req).await?;
+            if res.status_code() != 200 {
+                return Err(Error::RustError(res.text().await?));
+            }
+            Ok(())
+        })
+    }
+
+    /// deliver up to [`SETTLEMENT_BATCH`] settlements, retrying later if any are left
+    pub(super) async fn deliver_settlements(&mut self) -> Result<()> {
+        let pending = self.book.pending_settlements(SETTLEMENT_BATCH).await?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let Some((game_canister, token_root)) = self.book.game_ids().await? else {
+            console_warn!("settlements queued without game ids?!");
+            return Ok(());
+        };
+
+        let batch_len = pending.len();
+        let sends = pending
+            .iter()
+            .map(|((round, idx), entry)| {
+                let settlement_id = format!("{game_canister}-{token_root}-{round}-{idx}");
+                self.send_settlement(
+                    entry.participant,
+                    token_root,
+                    settlement_id,
+                    entry.settlement.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let results = join_all(sends).await;
+
+        let mut storage = self.storage();
+        let mut txn = storage.transaction();
+        let mut failed = false;
+        for ((key, entry), res) in pending.into_iter().zip(results) {
+            let attempts = entry.attempts + 1;
+            let delivery = self
+                .book
+                .record_delivery_txn(&mut txn, &key, entry, res.is_ok())?;
+            let Err(e) = res else {
+                continue;
+            };
+            if delivery == Delivery::Retrying {
+                console_warn!("failed to deliver settlement {}-{}: {e}", key.0, key.1);
+                failed = true;
+            } else {
+                console_error!(
+                    "parking settlement {}-{} after {attempts} attempts: {e}",
+                    key.0,
+                    key.1,
+                );
+            }
+        }
+        txn.commit().await?;
+
+        if failed {
+            self.schedule_alarm_at(Date::now().as_millis() + SETTLEMENT_RETRY_MS)
+                .await?;
+        } else if batch_len >= SETTLEMENT_BATCH {
+            self.schedule_alarm_at(Date::now().as_millis()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// force a refund of a round that is stuck open or whose rewards can't be delivered
+    pub(super) async fn refund_round(&mut self, round: u64) -> Result<Response> {
+        let current_round = self.book.round().await?;
+        let refunded = if round == current_round {
+            let refunded = self
+                .book
+                .refund_current_round(Date::now().as_millis())
+                .await?;
+            if refunded > 0 {
+                self.broadcast_event(WsResp::WinningPoolEvent {
+                    new_pool: 0,
+                    round: round + 1,
+                })?;
+            }
+            refunded
+        } else if round < current_round {
+            self.book.refund_settled_round(round).await?
+        } else {
+            return Response::error("round not started", 400);
+        };
+        if refunded == 0 {
+            return Response::error("nothing to refund", 404);
+        }
+
+        self.schedule_alarm_at(Date::now().as_millis()).await?;
+
+        Response::from_json(&RefundRoundRes { round, refunded })
+    }
+}
@@ -1,13 +1,22 @@
+use std::collections::HashSet;
+
 use candid::Principal;
 use pump_n_dump_common::{
     rest::UserBetsResponse,
-    ws::{WsMessage, WsRequest, WsResp, WsResponse},
+    ws::{WsMessage, WsResp, WsResponse},
+    GameDirection,
 };
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use worker::{Result, WebSocket, WebSocketIncomingMessage};
+use worker::{console_warn, Date, Result, WebSocket, WebSocketIncomingMessage};
 
-use crate::game_object::GameObjReq;
+use crate::{
+    consts::{
+        MAXIMUM_BET_MANY_COUNT, PLAYER_COUNT_DEBOUNCE_MS, WS_IDLE_CHECK_INTERVAL_MS,
+        WS_IDLE_TIMEOUT_MS,
+    },
+    game_object::GameObjReq,
+};
 
 use super::GameState;
 
@@ -15,37 +24,134 @@ use super::GameState;
 struct WsState {
     game_canister: Principal,
     token_root: Principal,
-    user_canister: Principal,
+    /// `None` for read-only spectators
+    #[serde(default)]
+    user_canister: Option<Principal>,
+    /// ms since epoch, 0 for sockets accepted before this was tracked
+    #[serde(default)]
+    last_seen_ms: u64,
+    /// set before closing so the socket stops counting as a player
+    #[serde(default)]
+    closing: bool,
+}
+
+/// last [`WsRespExt::PlayerCountEvent`] sent
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+struct PlayerCountBroadcast {
+    player_count: u64,
+    /// ms since epoch
+    at_ms: u64,
+}
+
+fn close_socket(ws: &WebSocket, code: u16, reason: &str) {
+    if let Ok(Some(mut state)) = ws.deserialize_attachment::<WsState>() {
+        state.closing = true;
+        if let Err(e) = ws.serialize_attachment(state) {
+            console_warn!("failed to mark socket as closing: {e}");
+        }
+    }
+    if let Err(e) = ws.close(Some(code), Some(reason)) {
+        console_warn!("failed to close socket: {e}");
+    }
+}
+
+/// requests not part of [`WsMessage`], sent in the same envelope
+#[derive(Serialize, Deserialize, Clone)]
+pub enum WsMessageExt {
+    Ping,
+    /// place `count` bets at once, debited atomically
+    BetMany {
+        direction: GameDirection,
+        count: u64,
+        round: u64,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum GameWsMessage {
+    Common(WsMessage),
+    Ext(WsMessageExt),
+}
+
+#[derive(Deserialize)]
+struct GameWsRequest {
+    request_id: Uuid,
+    msg: GameWsMessage,
+}
+
+/// events not part of [`WsResp`], sent in the same envelope
+#[derive(Serialize, Clone)]
+pub enum WsRespExt {
+    /// the current round ends at `deadline_ms` unless the tide shifts before
+    RoundCountdownEvent {
+        round: u64,
+        /// ms since epoch
+        deadline_ms: u64,
+        remaining_ms: u64,
+    },
+    Pong,
+    /// unique players connected, spectators are not counted
+    PlayerCountEvent {
+        player_count: u64,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum GameWsResp {
+    Common(WsResp),
+    Ext(WsRespExt),
+}
+
+impl From<WsResp> for GameWsResp {
+    fn from(value: WsResp) -> Self {
+        Self::Common(value)
+    }
+}
+
+impl From<WsRespExt> for GameWsResp {
+    fn from(value: WsRespExt) -> Self {
+        Self::Ext(value)
+    }
+}
+
+#[derive(Serialize)]
+struct GameWsResponse {
+    request_id: Uuid,
+    response: GameWsResp,
 }
 
 impl GameState {
+    /// accept a socket, `user_canister` is `None` for spectators
     pub async fn handle_ws(
         &mut self,
         ws: WebSocket,
         game_canister: Principal,
         token_root: Principal,
-        user_canister: Principal,
+        user_canister: Option<Principal>,
     ) -> Result<()> {
         self.state.accept_web_socket(&ws);
         ws.serialize_attachment(WsState {
             game_canister,
             token_root,
             user_canister,
+            last_seen_ms: Date::now().as_millis(),
+            closing: false,
         })?;
+        self.book.set_game_ids(game_canister, token_root).await?;
 
-        let user_bets = self
-            .bets()
-            .await?
-            .get(&user_canister)
-            .copied()
-            .unwrap_or_default();
+        let user_bets = match user_canister {
+            Some(user_canister) => self.book.user_bets(user_canister).await?,
+            None => [0, 0],
+        };
 
         ws.send(&WsResponse {
             request_id: Uuid::max(),
             response: WsResp::WelcomeEvent {
-                round: self.round().await?,
-                pool: self.pumps().await? + self.dumps().await?,
-                player_count: self.state.get_websockets().len() as u64,
+                round: self.book.round().await?,
+                pool: self.book.pumps().await? + self.book.dumps().await?,
+                player_count: self.players().len() as u64,
                 user_bets: UserBetsResponse {
                     pumps: user_bets[0],
                     dumps: user_bets[1],
@@ -53,48 +159,170 @@ impl GameState {
             },
         })?;
 
-        Ok(())
+        if let Some(deadline_ms) = self.round_deadline().await? {
+            ws.send(&GameWsResponse {
+                request_id: Uuid::max(),
+                response: self.countdown_event(deadline_ms).await?.into(),
+            })?;
+        }
+
+        self.schedule_alarm_at(Date::now().as_millis() + WS_IDLE_CHECK_INTERVAL_MS)
+            .await?;
+
+        self.broadcast_player_count().await
+    }
+
+    pub(super) async fn handle_ws_close(
+        &mut self,
+        ws: &WebSocket,
+        code: u16,
+        reason: &str,
+    ) -> Result<()> {
+        close_socket(ws, code, reason);
+
+        self.broadcast_player_count().await
+    }
+
+    /// unique players with an open socket
+    ///
+    /// sockets without a valid attachment are skipped, [`Self::close_idle_sockets`] closes them
+    pub(super) fn players(&self) -> HashSet<Principal> {
+        let mut players = HashSet::new();
+        for ws in self.state.get_websockets() {
+            if let Ok(Some(state)) = ws.deserialize_attachment::<WsState>() {
+                if !state.closing {
+                    players.extend(state.user_canister);
+                }
+            }
+        }
+
+        players
+    }
+
+    /// broadcast the player count if it changed,
+    /// at most once every [`PLAYER_COUNT_DEBOUNCE_MS`]
+    pub(super) async fn broadcast_player_count(&mut self) -> Result<()> {
+        let player_count = self.players().len() as u64;
+        let mut storage = self.storage();
+        let last: PlayerCountBroadcast = storage
+            .get("player-count-broadcast")
+            .await?
+            .unwrap_or_default();
+        if last.player_count == player_count {
+            return Ok(());
+        }
+
+        let now = Date::now().as_millis();
+        let next_at = last.at_ms + PLAYER_COUNT_DEBOUNCE_MS;
+        if now < next_at {
+            return self.schedule_alarm_at(next_at).await;
+        }
+
+        self.broadcast_event(WsRespExt::PlayerCountEvent { player_count })?;
+        storage
+            .put(
+                "player-count-broadcast",
+                &PlayerCountBroadcast {
+                    player_count,
+                    at_ms: now,
+                },
+            )
+            .await
+    }
+
+    async fn countdown_event(&mut self, deadline_ms: u64) -> Result<WsRespExt> {
+        Ok(WsRespExt::RoundCountdownEvent {
+            round: self.book.round().await?,
+            deadline_ms,
+            remaining_ms: deadline_ms.saturating_sub(Date::now().as_millis()),
+        })
+    }
+
+    /// broadcast the countdown and schedule the next tick
+    pub(super) async fn announce_round_deadline(&mut self, deadline_ms: u64) -> Result<()> {
+        let event = self.countdown_event(deadline_ms).await?;
+        self.broadcast_event(event)?;
+        self.schedule_round_alarm(deadline_ms).await
     }
 
-    fn broadcast_event(&self, resp: WsResp) -> Result<()> {
-        let resp = WsResponse {
+    pub(super) fn broadcast_event(&self, resp: impl Into<GameWsResp>) -> Result<()> {
+        let resp = GameWsResponse {
             request_id: Uuid::max(),
-            response: resp,
+            response: resp.into(),
         };
         for ws in self.state.get_websockets() {
-            ws.send(&resp)?;
+            // the peer is gone
+            if let Err(e) = ws.send(&resp) {
+                console_warn!("failed to send event: {e}");
+                close_socket(&ws, 1011, "send failed");
+            }
         }
 
         Ok(())
     }
 
-    pub async fn handle_ws_message(
+    /// close sockets that have been quiet for longer than [`WS_IDLE_TIMEOUT_MS`],
+    /// along with sockets without a valid attachment
+    ///
+    /// returns whether any sockets are left open
+    pub(super) fn close_idle_sockets(&self) -> Result<bool> {
+        let now = Date::now().as_millis();
+        let mut open = false;
+        for ws in self.state.get_websockets() {
+            let Ok(Some(mut state)) = ws.deserialize_attachment::<WsState>() else {
+                close_socket(&ws, 1011, "invalid socket state");
+                continue;
+            };
+            if state.closing {
+                continue;
+            }
+            if state.last_seen_ms == 0 {
+                state.last_seen_ms = now;
+                ws.serialize_attachment(state)?;
+            }
+            if now.saturating_sub(state.last_seen_ms) < WS_IDLE_TIMEOUT_MS {
+                open = true;
+                continue;
+            }
+            close_socket(&ws, 1000, "idle");
+        }
+
+        Ok(open)
+    }
+
+    async fn place_bets(
         &mut self,
         ws: &WebSocket,
-        msg: WebSocketIncomingMessage,
+        request_id: Uuid,
+        state: WsState,
+        direction: GameDirection,
+        count: u64,
+        round: u64,
     ) -> Result<()> {
-        let WebSocketIncomingMessage::String(raw_msg) = msg else {
+        let Some(user_canister) = state.user_canister else {
             return ws.send(&WsResponse {
-                request_id: Uuid::nil(),
-                response: WsResp::error("unknown request"),
+                request_id,
+                response: WsResp::bet_failure("spectators can't bet".into(), direction),
             });
         };
-        let Ok(ws_req) = serde_json::from_str::<WsRequest>(&raw_msg) else {
+        if count == 0 || count > MAXIMUM_BET_MANY_COUNT {
             return ws.send(&WsResponse {
-                request_id: Uuid::nil(),
-                response: WsResp::error("unknown request"),
+                request_id,
+                response: WsResp::bet_failure(
+                    format!("bet count must be between 1 and {MAXIMUM_BET_MANY_COUNT}"),
+                    direction,
+                ),
             });
-        };
-        let state: WsState = ws.deserialize_attachment()?.unwrap();
-        let WsMessage::Bet { direction, round } = ws_req.msg;
+        }
 
         let res = self
             .game_request(GameObjReq {
-                sender: state.user_canister,
+                sender: user_canister,
                 direction,
                 creator: state.game_canister,
                 token_root: state.token_root,
                 round,
+                count,
             })
             .await;
 
@@ -102,7 +330,7 @@ impl GameState {
             Ok(r) => r,
             Err(e) => {
                 return ws.send(&WsResponse {
-                    request_id: ws_req.request_id,
+                    request_id,
                     response: WsResp::bet_failure(e.to_string(), direction),
                 })
             }
@@ -115,13 +343,62 @@ impl GameState {
                 }
                 _ => {
                     ws.send(&WsResponse {
-                        request_id: ws_req.request_id,
+                        request_id,
                         response: resp,
                     })?;
                 }
             };
         }
 
+        // every bet pushes the inactivity deadline back
+        if let Some(deadline_ms) = self.round_deadline().await? {
+            self.announce_round_deadline(deadline_ms).await?;
+        }
+
         Ok(())
     }
+
+    pub async fn handle_ws_message(
+        &mut self,
+        ws: &WebSocket,
+        msg: WebSocketIncomingMessage,
+    ) -> Result<()> {
+        let WebSocketIncomingMessage::String(raw_msg) = msg else {
+            return ws.send(&WsResponse {
+                request_id: Uuid::nil(),
+                response: WsResp::error("unknown request"),
+            });
+        };
+        let Ok(ws_req) = serde_json::from_str::<GameWsRequest>(&raw_msg) else {
+            return ws.send(&WsResponse {
+                request_id: Uuid::nil(),
+                response: WsResp::error("unknown request"),
+            });
+        };
+        let Ok(Some(mut state)) = ws.deserialize_attachment::<WsState>() else {
+            close_socket(ws, 1011, "invalid socket state");
+            return Ok(());
+        };
+        state.last_seen_ms = Date::now().as_millis();
+        ws.serialize_attachment(state)?;
+
+        match ws_req.msg {
+            GameWsMessage::Common(WsMessage::Bet { direction, round }) => {
+                self.place_bets(ws, ws_req.request_id, state, direction, 1, round)
+                    .await
+            }
+            GameWsMessage::Ext(WsMessageExt::BetMany {
+                direction,
+                count,
+                round,
+            }) => {
+                self.place_bets(ws, ws_req.request_id, state, direction, count, round)
+                    .await
+            }
+            GameWsMessage::Ext(WsMessageExt::Ping) => ws.send(&GameWsResponse {
+                request_id: ws_req.request_id,
+                response: WsRespExt::Pong.into(),
+            }),
+        }
+    }
 }
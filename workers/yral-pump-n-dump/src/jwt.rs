@@ -1,4 +1,16 @@
+use worker::Env;
+use worker_utils::jwt::JwtKeySet;
+
 pub const JWT_PUBKEY: &str = "-----BEGIN PUBLIC KEY-----
 MCowBQYDK2VwAyEAV+DJfztWOovpmCUcZ5Fram2BLOt2B4LIlzw2vogIqK4=
 -----END PUBLIC KEY-----";
 pub const JWT_AUD: &str = "pump-n-dump-worker";
+
+pub const SCOPE_CLAIM: &str = "pnd:claim";
+pub const SCOPE_BETS_INFO: &str = "pnd:bets_info";
+pub const SCOPE_REFUND_ROUND: &str = "pnd:refund_round";
+
+/// keys accepted for worker JWTs, `JWT_PUBKEY` verifies tokens minted without a `kid`
+pub fn jwt_keys(env: &Env) -> worker::Result<&'static JwtKeySet> {
+    worker_utils::jwt::jwt_keys(env, JWT_PUBKEY)
+}
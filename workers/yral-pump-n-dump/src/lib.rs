@@ -8,7 +8,8 @@ mod utils;
 
 use backend_impl::{WsBackend, WsBackendImpl};
 use candid::Principal;
-use jwt::{JWT_AUD, JWT_PUBKEY};
+use consts::CLAIM_NONCED;
+use jwt::{jwt_keys, JWT_AUD, SCOPE_BETS_INFO, SCOPE_CLAIM, SCOPE_REFUND_ROUND};
 use pump_n_dump_common::{
     rest::{claim_msg, ClaimReq},
     ws::identify_message,
@@ -18,9 +19,16 @@ use std::result::Result as StdResult;
 use user_reconciler::{ClaimGdollrReq, HotOrNotBetRequest};
 use utils::{game_state_stub, user_state_stub};
 use worker::*;
-use worker_utils::{jwt::verify_jwt_from_header, parse_principal, RequestInitBuilder};
+use worker_utils::{
+    jwt::{handle_revocation_req, verify_jwt_scope_from_header, RevocationList},
+    parse_principal,
+    signed::{
+        nonced_message, unnonced_cutoff_from_env, MaybeNonced, SignatureError, SignedRequest,
+    },
+    RequestInitBuilder,
+};
 use yral_canisters_common::utils::vote::{verifiable_hon_bet_message, VerifiableHonBetReq};
-use yral_identity::Signature;
+use yral_identity::{msg_builder::Message, Signature};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GameWsQuery {
@@ -28,34 +36,47 @@ pub struct GameWsQuery {
     signature: String,
 }
 
-fn verify_claim_req(req: &ClaimReq) -> StdResult<(), (String, u16)> {
-    let msg = claim_msg(req.amount.clone());
+fn signed_claim_req(
+    req: MaybeNonced<ClaimReq>,
+    unnonced_cutoff: u64,
+) -> SignedRequest<MaybeNonced<ClaimReq>> {
+    let sender = req.request().sender;
+    let signature = req.request().signature.clone();
+    let nonce = req.nonce().cloned();
+    SignedRequest::new(sender, signature, req)
+        .maybe_nonce(nonce.as_ref())
+        .unnonced_cutoff(unnonced_cutoff)
+}
 
-    let verify_res = req.signature.clone().verify_identity(req.sender, msg);
-    if verify_res.is_err() {
-        return Err(("invalid signature".into(), 401));
+/// signed message of a nonced claim, `claim_msg` for claims signed without a nonce
+fn claim_req_msg(req: &MaybeNonced<ClaimReq>) -> StdResult<Message, SignatureError> {
+    match req {
+        MaybeNonced::Nonced(req) => {
+            nonced_message(CLAIM_NONCED, req.request.amount.clone(), &req.nonce)
+        }
+        MaybeNonced::Unnonced(req) => Ok(claim_msg(req.amount.clone())),
     }
-
-    Ok(())
 }
 
-// TODO write an abstraction around verification
-fn verify_hot_or_not_bet_req(req: &VerifiableHonBetReq) -> StdResult<(), (String, u16)> {
-    let msg = verifiable_hon_bet_message(req.args);
-
-    let verify_res = req.signature.clone().verify_identity(req.sender, msg);
-    if verify_res.is_err() {
-        return Err(("invalid signature".into(), 401));
-    }
+fn signed_hot_or_not_bet_req(req: VerifiableHonBetReq) -> SignedRequest<VerifiableHonBetReq> {
+    SignedRequest::new(req.sender, req.signature.clone(), req)
+}
 
-    Ok(())
+fn hot_or_not_bet_req_msg(req: &VerifiableHonBetReq) -> StdResult<Message, SignatureError> {
+    Ok(verifiable_hon_bet_message(req.args))
 }
 
 async fn place_hot_or_not_bet(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let req: VerifiableHonBetReq = serde_json::from_str(&req.text().await?)?;
-    if let Err((msg, status)) = verify_hot_or_not_bet_req(&req) {
-        return Response::error(msg, status);
-    }
+    let req = match SignedRequest::from_json_body(
+        &mut req,
+        signed_hot_or_not_bet_req,
+        hot_or_not_bet_req_msg,
+    )
+    .await
+    {
+        Ok(verified) => verified.payload,
+        Err(e) => return e.to_response(),
+    };
     let backend = WsBackend::new(&ctx.env)?;
 
     let Some(user_canister) = backend.user_principal_to_user_canister(req.sender).await? else {
@@ -81,12 +102,20 @@ async fn place_hot_or_not_bet(mut req: Request, ctx: RouteContext<()>) -> Result
 }
 
 async fn claim_gdollr(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let req: ClaimReq = serde_json::from_str(&req.text().await?)?;
-    if let Err((msg, status)) = verify_claim_req(&req) {
-        return Response::error(msg, status);
-    }
+    let unnonced_cutoff = unnonced_cutoff_from_env(&ctx.env);
+    let req = match SignedRequest::from_json_body(
+        &mut req,
+        |req| signed_claim_req(req, unnonced_cutoff),
+        claim_req_msg,
+    )
+    .await
+    {
+        Ok(verified) => verified.payload.into_replay_parts(|req| &req.signature),
+        Err(e) => return e.to_response(),
+    };
     let backend = WsBackend::new(&ctx.env)?;
 
+    let (req, replay_key) = req;
     let Some(user_canister) = backend.user_principal_to_user_canister(req.sender).await? else {
         return Response::error("user not found", 404);
     };
@@ -95,6 +124,7 @@ async fn claim_gdollr(mut req: Request, ctx: RouteContext<()>) -> Result<Respons
     let body = ClaimGdollrReq {
         user_canister,
         amount: req.amount,
+        replay_key,
     };
 
     let req = Request::new_with_init(
@@ -109,16 +139,28 @@ async fn claim_gdollr(mut req: Request, ctx: RouteContext<()>) -> Result<Respons
 }
 
 async fn claim_gdolr_v2(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    if let Err((msg, code)) = verify_jwt_from_header(JWT_PUBKEY, JWT_AUD.into(), &req) {
+    let keys = jwt_keys(&ctx.env)?;
+    let revocations = RevocationList::from_env(&ctx.env);
+    if let Err((msg, code)) =
+        verify_jwt_scope_from_header(keys, &revocations, JWT_AUD.into(), SCOPE_CLAIM, &req).await
+    {
         return Response::error(msg, code);
     }
 
-    let req: ClaimReq = serde_json::from_str(&req.text().await?)?;
-    if let Err((msg, status)) = verify_claim_req(&req) {
-        return Response::error(msg, status);
-    }
+    let unnonced_cutoff = unnonced_cutoff_from_env(&ctx.env);
+    let req = match SignedRequest::from_json_body(
+        &mut req,
+        |req| signed_claim_req(req, unnonced_cutoff),
+        claim_req_msg,
+    )
+    .await
+    {
+        Ok(verified) => verified.payload.into_replay_parts(|req| &req.signature),
+        Err(e) => return e.to_response(),
+    };
     let backend = WsBackend::new(&ctx.env)?;
 
+    let (req, replay_key) = req;
     let Some(user_canister) = backend.user_principal_to_user_canister(req.sender).await? else {
         return Response::error("user not found", 404);
     };
@@ -127,6 +169,7 @@ async fn claim_gdolr_v2(mut req: Request, ctx: RouteContext<()>) -> Result<Respo
     let body = ClaimGdollrReq {
         user_canister,
         amount: req.amount,
+        replay_key,
     };
 
     let req = Request::new_with_init(
@@ -193,13 +236,9 @@ fn verify_identify_req(
     token_root: Principal,
     sender: Principal,
     signature: Signature,
-) -> StdResult<(), String> {
-    let msg = identify_message(game_canister, token_root);
-
-    let verify_res = signature.clone().verify_identity(sender, msg);
-    if verify_res.is_err() {
-        return Err("invalid signature".into());
-    }
+) -> StdResult<(), SignatureError> {
+    SignedRequest::new(sender, signature, (game_canister, token_root))
+        .verify(|&(game_canister, token_root)| Ok(identify_message(game_canister, token_root)))?;
 
     Ok(())
 }
@@ -217,7 +256,7 @@ async fn estabilish_game_ws(req: Request, ctx: RouteContext<()>) -> Result<Respo
     };
 
     if let Err(e) = verify_identify_req(game_canister, token_root, sender, signature) {
-        return Response::error(e, 403);
+        return e.to_response();
     }
 
     let ws_backend = WsBackend::new(&ctx.env)?;
@@ -253,6 +292,31 @@ async fn estabilish_game_ws(req: Request, ctx: RouteContext<()>) -> Result<Respo
     })
 }
 
+/// read-only socket for pool and result events, no identity required
+async fn spectate_game_ws(ctx: RouteContext<()>) -> Result<Response> {
+    let game_canister = parse_principal!(ctx, "game_canister");
+    let token_root = parse_principal!(ctx, "token_root");
+
+    let ws_backend = WsBackend::new(&ctx.env)?;
+    let token_valid = ws_backend.validate_token(token_root, game_canister).await?;
+    if !token_valid {
+        return Response::error("invalid token", 400);
+    }
+    let game_stub = game_state_stub(&ctx, game_canister, token_root)?;
+
+    let mut headers = Headers::new();
+    headers.set("Upgrade", "websocket")?;
+    let new_req = Request::new_with_init(
+        &format!("http://fakeurl.com/spectate/{game_canister}/{token_root}"),
+        RequestInitBuilder::default()
+            .method(Method::Get)
+            .replace_headers(headers)
+            .build(),
+    )?;
+
+    game_stub.fetch_with_request(new_req).await
+}
+
 async fn player_count(ctx: RouteContext<()>) -> Result<Response> {
     let game_canister = parse_principal!(ctx, "game_canister");
     let token_root = parse_principal!(ctx, "token_root");
@@ -287,7 +351,12 @@ async fn uncommitted_games(ctx: RouteContext<()>) -> Result<Response> {
 }
 
 async fn total_bets_info(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    if let Err((msg, code)) = verify_jwt_from_header(JWT_PUBKEY, JWT_AUD.into(), &req) {
+    let keys = jwt_keys(&ctx.env)?;
+    let revocations = RevocationList::from_env(&ctx.env);
+    if let Err((msg, code)) =
+        verify_jwt_scope_from_header(keys, &revocations, JWT_AUD.into(), SCOPE_BETS_INFO, &req)
+            .await
+    {
         return Response::error(msg, code);
     }
 
@@ -301,6 +370,58 @@ async fn total_bets_info(req: Request, ctx: RouteContext<()>) -> Result<Response
         .await
 }
 
+async fn game_rounds(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let game_canister = parse_principal!(ctx, "game_canister");
+    let token_root = parse_principal!(ctx, "token_root");
+    let query = req.url()?.query().unwrap_or_default().to_string();
+
+    let game_stub = game_state_stub(&ctx, game_canister, token_root)?;
+
+    game_stub
+        .fetch_with_str(&format!("http://fake_url.com/rounds?{query}"))
+        .await
+}
+
+async fn game_round(ctx: RouteContext<()>) -> Result<Response> {
+    let game_canister = parse_principal!(ctx, "game_canister");
+    let token_root = parse_principal!(ctx, "token_root");
+    let Ok(round) = ctx.param("round").unwrap().parse::<u64>() else {
+        return Response::error("Invalid round", 400);
+    };
+
+    let game_stub = game_state_stub(&ctx, game_canister, token_root)?;
+
+    game_stub
+        .fetch_with_str(&format!("http://fake_url.com/round/{round}"))
+        .await
+}
+
+async fn refund_round(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let keys = jwt_keys(&ctx.env)?;
+    let revocations = RevocationList::from_env(&ctx.env);
+    if let Err((msg, code)) =
+        verify_jwt_scope_from_header(keys, &revocations, JWT_AUD.into(), SCOPE_REFUND_ROUND, &req)
+            .await
+    {
+        return Response::error(msg, code);
+    }
+
+    let game_canister = parse_principal!(ctx, "game_canister");
+    let token_root = parse_principal!(ctx, "token_root");
+    let Ok(round) = ctx.param("round").unwrap().parse::<u64>() else {
+        return Response::error("Invalid round", 400);
+    };
+
+    let game_stub = game_state_stub(&ctx, game_canister, token_root)?;
+
+    let req = Request::new_with_init(
+        &format!("http://fake_url.com/refund_round/{round}"),
+        RequestInitBuilder::default().method(Method::Post).build(),
+    )?;
+
+    game_stub.fetch_with_request(req).await
+}
+
 fn cors_policy() -> Cors {
     Cors::new()
         .with_origins(["*"])
@@ -309,6 +430,11 @@ fn cors_policy() -> Cors {
         .with_max_age(86400)
 }
 
+async fn revocations_admin(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    let keys = jwt_keys(&ctx.env)?;
+    handle_revocation_req(req, &ctx.env, keys, JWT_AUD.into()).await
+}
+
 #[event(fetch)]
 async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
     console_error_panic_hook::set_once();
@@ -319,6 +445,12 @@ async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
         .post_async("/claim_gdollr", claim_gdollr)
         .post_async("/claim_gdolr_v2", claim_gdolr_v2)
         .post_async("/place_hot_or_not_bet", place_hot_or_not_bet)
+        .post_async("/admin/revocations", revocations_admin)
+        .delete_async("/admin/revocations", revocations_admin)
+        .post_async(
+            "/admin/refund_round/:game_canister/:token_root/:round",
+            refund_round,
+        )
         .get_async("/balance/:user_canister", |_req, ctx| user_balance(ctx))
         .get_async("/balance_v2/:user_canister", |_req, ctx| {
             user_balance_v2(ctx)
@@ -333,6 +465,9 @@ async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
         .get_async("/ws/:game_canister/:token_root", |req, ctx| {
             estabilish_game_ws(req, ctx)
         })
+        .get_async("/spectate/:game_canister/:token_root", |_req, ctx| {
+            spectate_game_ws(ctx)
+        })
         .get_async("/player_count/:game_canister/:token_root", |_req, ctx| {
             player_count(ctx)
         })
@@ -344,6 +479,10 @@ async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
             "/total_bets_info/:game_canister/:token_root",
             total_bets_info,
         )
+        .get_async("/rounds/:game_canister/:token_root", game_rounds)
+        .get_async("/round/:game_canister/:token_root/:round", |_req, ctx| {
+            game_round(ctx)
+        })
         .options("/*catchall", |_, _| Response::empty())
         .run(req, env)
         .await?;
@@ -0,0 +1,431 @@
+use std::collections::{BTreeMap, HashSet};
+
+use candid::{Nat, Principal};
+use futures::StreamExt;
+use num_bigint::{BigInt, ToBigInt};
+use worker::*;
+use worker_utils::storage::{
+    ListEntryError, StorageBackend, StorageCell, StorageMap, StorageTransaction, UnpaddedU64,
+};
+
+use crate::consts::{GDOLLR_TO_E8S, RECEIVED_SETTLEMENT_TTL_MS, STORAGE_PAGE_SIZE};
+
+use super::StateDiff;
+
+/// Off-chain state taken out of the ledger while it's reconciled with the user's canister
+pub struct Settlement {
+    to_settle: BigInt,
+    earnings: Nat,
+    pub state_diffs: Vec<StateDiff>,
+}
+
+// received settlement ids pruned per alarm
+const SETTLEMENT_PRUNE_BATCH: usize = 64;
+
+/// Off-chain balance, earnings and games of a user, settled to their canister later
+///
+/// the game logic of [`super::UserEphemeralState`], runs on any [`StorageBackend`]
+pub struct OffChainLedger<B: StorageBackend> {
+    backend: B,
+    // effective balance = on_chain_balance + off_chain_balance_delta
+    pub off_chain_balance_delta: StorageCell<BigInt>,
+    // effective earnings = on_chain_earnings + off_chain_earnings
+    off_chain_earning_delta: Option<Nat>,
+    state_diffs: Option<Vec<StateDiff>>,
+    // keys predate `StorageKey` and are not zero padded
+    state_diff_map: StorageMap<UnpaddedU64, StateDiff>,
+    pending_games: Option<HashSet<Principal>>,
+    // token_root -> token_root
+    pending_game_map: StorageMap<Principal, Principal>,
+    // settlement ids of round rewards and refunds already applied
+    received_settlements: StorageMap<String, ()>,
+    // (expires_at_ms, settlement_id), received settlement ids are dropped once expired
+    received_settlement_expiry: StorageMap<(u64, String), ()>,
+}
+
+impl<B: StorageBackend> OffChainLedger<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            off_chain_balance_delta: StorageCell::new("off_chain_balance_delta", || {
+                BigInt::from(0)
+            }),
+            off_chain_earning_delta: None,
+            state_diffs: None,
+            state_diff_map: StorageMap::new_versioned::<StateDiff>("state-diff-"),
+            pending_games: None,
+            pending_game_map: StorageMap::new("pending-game-"),
+            received_settlements: StorageMap::new("received-settlement-"),
+            received_settlement_expiry: StorageMap::new("settlement-expiry-"),
+        }
+    }
+
+    pub async fn off_chain_earning_delta(&mut self) -> Result<&mut Nat> {
+        if self.off_chain_earning_delta.is_some() {
+            return Ok(self.off_chain_earning_delta.as_mut().unwrap());
+        }
+
+        let off_chain_earning_delta = self
+            .backend
+            .storage()
+            .get::<Nat>("off_chain_earning_delta")
+            .await?
+            .unwrap_or_default();
+        self.off_chain_earning_delta = Some(off_chain_earning_delta);
+        Ok(self.off_chain_earning_delta.as_mut().unwrap())
+    }
+
+    pub async fn pending_games(&mut self) -> Result<&mut HashSet<Principal>> {
+        if self.pending_games.is_some() {
+            return Ok(self.pending_games.as_mut().unwrap());
+        }
+
+        let storage = self.backend.storage();
+        let mut pending_games = HashSet::new();
+        let mut entries = self.pending_game_map.stream(&storage, STORAGE_PAGE_SIZE);
+        while let Some(entry) = entries.next().await {
+            match entry {
+                Ok((token_root, _)) => {
+                    pending_games.insert(token_root);
+                }
+                Err(ListEntryError::Storage(e)) => return Err(Error::RustError(e)),
+                Err(e) => console_error!("skipping pending game: {e}"),
+            }
+        }
+        drop(entries);
+
+        self.pending_games = Some(pending_games);
+        Ok(self.pending_games.as_mut().unwrap())
+    }
+
+    pub async fn state_diffs(&mut self) -> Result<&mut Vec<StateDiff>> {
+        if self.state_diffs.is_some() {
+            return Ok(self.state_diffs.as_mut().unwrap());
+        }
+
+        let storage = self.backend.storage();
+        let mut state_diffs = BTreeMap::new();
+        let mut entries = self.state_diff_map.stream(&storage, STORAGE_PAGE_SIZE);
+        while let Some(entry) = entries.next().await {
+            match entry {
+                Ok((idx, state_diff)) => {
+                    state_diffs.insert(idx, state_diff);
+                }
+                Err(ListEntryError::Storage(e)) => return Err(Error::RustError(e)),
+                Err(e) => console_error!("skipping state diff: {e}"),
+            }
+        }
+        drop(entries);
+
+        self.state_diffs = Some(state_diffs.into_values().collect());
+        Ok(self.state_diffs.as_mut().unwrap())
+    }
+
+    pub async fn balance_delta(&mut self) -> Result<BigInt> {
+        let storage = self.backend.storage();
+        let delta = self.off_chain_balance_delta.read(&storage).await?.clone();
+
+        Ok(delta)
+    }
+
+    pub async fn decrement(&mut self, pending_game_root: Principal, count: u64) -> Result<()> {
+        let mut storage = self.backend.storage();
+        self.off_chain_balance_delta
+            .update(&mut storage, |delta| {
+                *delta -= BigInt::from(GDOLLR_TO_E8S) * count
+            })
+            .await?;
+
+        let inserted = self.pending_games().await?.insert(pending_game_root);
+        if !inserted {
+            return Ok(());
+        }
+
+        self.pending_game_map
+            .insert(&mut storage, pending_game_root, pending_game_root)
+            .await?;
+
+        Ok(())
+    }
+
+    /// record the state diff, and the settlement it was delivered in, in a single transaction
+    ///
+    /// returns when the settlement id can be forgotten
+    pub async fn add_state_diff(
+        &mut self,
+        state_diff: StateDiff,
+        settlement_id: Option<String>,
+        now_ms: u64,
+    ) -> Result<Option<u64>> {
+        let reward = state_diff.reward();
+        let earnings = self.off_chain_earning_delta().await?.clone() + reward.clone();
+        let next_idx = self.state_diffs().await?.len();
+        let completed_game = match &state_diff {
+            StateDiff::CompletedGame(ginfo) => Some(ginfo.token_root),
+            StateDiff::CreatorReward(_) => None,
+        };
+
+        let mut storage = self.backend.storage();
+        let mut txn = storage.transaction();
+        self.off_chain_balance_delta
+            .update_txn(&mut txn, |delta| *delta += BigInt::from(reward))
+            .await?;
+        txn.put("off_chain_earning_delta", &earnings)?;
+        if let Some(token_root) = &completed_game {
+            self.pending_game_map.remove_txn(&mut txn, token_root);
+        }
+        self.state_diff_map
+            .insert_txn(&mut txn, &UnpaddedU64(next_idx as u64), &state_diff)?;
+        let expires_at = settlement_id
+            .map(|settlement_id| self.mark_settlement_received_txn(&mut txn, settlement_id, now_ms))
+            .transpose()?;
+        txn.commit().await?;
+
+        self.off_chain_earning_delta = Some(earnings);
+        self.state_diffs().await?.push(state_diff);
+        if let Some(token_root) = completed_game {
+            self.pending_games().await?.remove(&token_root);
+        }
+
+        Ok(expires_at)
+    }
+
+    /// return the stake to the off-chain balance, the game never reaches the canister
+    ///
+    /// returns when the settlement id can be forgotten
+    pub async fn refund(
+        &mut self,
+        token_root: Principal,
+        amount: Nat,
+        settlement_id: String,
+        now_ms: u64,
+    ) -> Result<u64> {
+        let mut storage = self.backend.storage();
+        let mut txn = storage.transaction();
+        self.off_chain_balance_delta
+            .update_txn(&mut txn, |delta| *delta += BigInt::from(amount))
+            .await?;
+        self.pending_game_map.remove_txn(&mut txn, &token_root);
+        let expires_at = self.mark_settlement_received_txn(&mut txn, settlement_id, now_ms)?;
+        txn.commit().await?;
+
+        self.pending_games().await?.remove(&token_root);
+
+        Ok(expires_at)
+    }
+
+    /// clear the state diffs, their balance change moves on-chain with them
+    ///
+    /// [`Self::restore_settlement`] must be called if the canister rejects them
+    pub async fn take_settlement(&mut self) -> Result<Settlement> {
+        let mut storage = self.backend.storage();
+        let to_settle = self.off_chain_balance_delta.read(&storage).await?.clone();
+        let earnings = self.off_chain_earning_delta().await?.clone();
+        let state_diffs = self.state_diffs().await?.clone();
+
+        let mut delta_delta = BigInt::from(0u32);
+        for diff in &state_diffs {
+            match diff {
+                StateDiff::CompletedGame(info) => {
+                    delta_delta += BigInt::from(info.pumps + info.dumps) * GDOLLR_TO_E8S;
+                    delta_delta -= info.reward.clone().0.to_bigint().unwrap();
+                }
+                StateDiff::CreatorReward(rew) => {
+                    delta_delta -= rew.clone().0.to_bigint().unwrap();
+                }
+            }
+        }
+
+        let mut txn = storage.transaction();
+        txn.delete("off_chain_earning_delta");
+        self.state_diff_map.clear_txn(&mut txn).await?;
+        self.off_chain_balance_delta
+            .update_txn(&mut txn, |delta| *delta += delta_delta)
+            .await?;
+        txn.commit().await?;
+
+        self.off_chain_earning_delta = Some(0u32.into());
+        self.state_diffs = Some(vec![]);
+
+        Ok(Settlement {
+            to_settle,
+            earnings,
+            state_diffs,
+        })
+    }
+
+    /// put back a settlement the canister did not accept
+    pub async fn restore_settlement(&mut self, settlement: Settlement) -> Result<()> {
+        let Settlement {
+            to_settle,
+            earnings,
+            state_diffs,
+        } = settlement;
+
+        let mut storage = self.backend.storage();
+        let mut txn = storage.transaction();
+        self.off_chain_balance_delta
+            .update_txn(&mut txn, |delta| *delta = to_settle)
+            .await?;
+        txn.put("off_chain_earning_delta", &earnings)?;
+        for (i, state_diff) in state_diffs.iter().enumerate() {
+            self.state_diff_map
+                .insert_txn(&mut txn, &UnpaddedU64(i as u64), state_diff)?;
+        }
+        txn.commit().await?;
+
+        self.state_diffs = Some(state_diffs);
+        self.off_chain_earning_delta = Some(earnings);
+
+        Ok(())
+    }
+
+    pub async fn settlement_received(&mut self, settlement_id: &String) -> Result<bool> {
+        let storage = self.backend.storage();
+        let received = self
+            .received_settlements
+            .get(&storage, settlement_id)
+            .await?
+            .is_some();
+
+        Ok(received)
+    }
+
+    /// stage the settlement as received, returns when it can be forgotten
+    fn mark_settlement_received_txn(
+        &mut self,
+        txn: &mut StorageTransaction<'_>,
+        settlement_id: String,
+        now_ms: u64,
+    ) -> Result<u64> {
+        let expires_at = now_ms + RECEIVED_SETTLEMENT_TTL_MS;
+        self.received_settlements
+            .insert_txn(txn, &settlement_id, &())?;
+        self.received_settlement_expiry
+            .insert_txn(txn, &(expires_at, settlement_id), &())?;
+
+        Ok(expires_at)
+    }
+
+    /// drop received settlement ids past their ttl, returns when the next one expires
+    pub async fn prune_received_settlements(&mut self, now_ms: u64) -> Result<Option<u64>> {
+        let mut storage = self.backend.storage();
+        let entries = self
+            .received_settlement_expiry
+            .page(&storage, None, SETTLEMENT_PRUNE_BATCH + 1)
+            .await?;
+
+        let mut txn = storage.transaction();
+        let mut next_expiry = None;
+        for (idx, ((expires_at, settlement_id), _)) in entries.into_iter().enumerate() {
+            if expires_at > now_ms || idx == SETTLEMENT_PRUNE_BATCH {
+                next_expiry = Some(expires_at.max(now_ms));
+                break;
+            }
+            self.received_settlements
+                .remove_txn(&mut txn, &settlement_id);
+            self.received_settlement_expiry
+                .remove_txn(&mut txn, &(expires_at, settlement_id));
+        }
+        txn.commit().await?;
+
+        Ok(next_expiry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use worker_utils::storage::MemoryStorage;
+
+    use super::*;
+
+    const TOKEN_ROOT: Principal = Principal::anonymous();
+
+    #[test]
+    fn reward_is_credited_and_its_settlement_recorded() {
+        let storage = MemoryStorage::new();
+        let mut ledger = OffChainLedger::new(storage.clone());
+        let reward = StateDiff::CreatorReward(Nat::from(500u32));
+
+        let expires_at =
+            block_on(ledger.add_state_diff(reward, Some("round-1".into()), 1_000)).unwrap();
+
+        assert_eq!(expires_at, Some(1_000 + RECEIVED_SETTLEMENT_TTL_MS));
+        assert_eq!(block_on(ledger.balance_delta()).unwrap(), BigInt::from(500));
+        assert_eq!(
+            *block_on(ledger.off_chain_earning_delta()).unwrap(),
+            Nat::from(500u32)
+        );
+        assert!(block_on(ledger.settlement_received(&"round-1".into())).unwrap());
+
+        // a fresh ledger reads everything back from storage
+        let mut reloaded = OffChainLedger::new(storage);
+        assert_eq!(block_on(reloaded.state_diffs()).unwrap().len(), 1);
+        assert!(block_on(reloaded.settlement_received(&"round-1".into())).unwrap());
+    }
+
+    #[test]
+    fn refund_returns_the_stake_and_clears_the_pending_game() {
+        let mut ledger = OffChainLedger::new(MemoryStorage::new());
+        block_on(ledger.decrement(TOKEN_ROOT, 2)).unwrap();
+        assert_eq!(
+            block_on(ledger.balance_delta()).unwrap(),
+            BigInt::from(-2 * GDOLLR_TO_E8S as i64)
+        );
+
+        block_on(ledger.refund(
+            TOKEN_ROOT,
+            Nat::from(2 * GDOLLR_TO_E8S),
+            "round-1".into(),
+            0,
+        ))
+        .unwrap();
+
+        assert_eq!(block_on(ledger.balance_delta()).unwrap(), BigInt::from(0));
+        assert!(block_on(ledger.pending_games()).unwrap().is_empty());
+        assert!(block_on(ledger.settlement_received(&"round-1".into())).unwrap());
+    }
+
+    #[test]
+    fn expired_settlement_ids_are_pruned() {
+        let storage = MemoryStorage::new();
+        let mut ledger = OffChainLedger::new(storage.clone());
+        block_on(ledger.refund(TOKEN_ROOT, Nat::from(1u32), "old".into(), 0)).unwrap();
+        block_on(ledger.refund(TOKEN_ROOT, Nat::from(1u32), "new".into(), 10)).unwrap();
+
+        let next_expiry =
+            block_on(ledger.prune_received_settlements(RECEIVED_SETTLEMENT_TTL_MS)).unwrap();
+
+        assert_eq!(next_expiry, Some(10 + RECEIVED_SETTLEMENT_TTL_MS));
+        assert!(!block_on(ledger.settlement_received(&"old".into())).unwrap());
+        assert!(block_on(ledger.settlement_received(&"new".into())).unwrap());
+        assert!(!storage.keys().iter().any(|key| key.contains("old")));
+    }
+
+    #[test]
+    fn rejected_settlement_is_restored() {
+        let storage = MemoryStorage::new();
+        let mut ledger = OffChainLedger::new(storage.clone());
+        block_on(ledger.add_state_diff(StateDiff::CreatorReward(Nat::from(500u32)), None, 0))
+            .unwrap();
+
+        let settlement = block_on(ledger.take_settlement()).unwrap();
+        assert_eq!(settlement.state_diffs.len(), 1);
+        assert_eq!(block_on(ledger.balance_delta()).unwrap(), BigInt::from(0));
+        assert!(!storage
+            .keys()
+            .iter()
+            .any(|key| key.starts_with("state-diff-")));
+
+        block_on(ledger.restore_settlement(settlement)).unwrap();
+
+        assert_eq!(block_on(ledger.balance_delta()).unwrap(), BigInt::from(500));
+        let mut reloaded = OffChainLedger::new(storage);
+        assert_eq!(block_on(reloaded.state_diffs()).unwrap().len(), 1);
+        assert_eq!(
+            *block_on(reloaded.off_chain_earning_delta()).unwrap(),
+            Nat::from(500u32)
+        );
+    }
+}
@@ -1,16 +1,19 @@
+mod ledger;
 mod treasury;
 
-use std::collections::HashSet;
+use std::rc::Rc;
 
 use candid::{Nat, Principal};
-use num_bigint::{BigInt, BigUint, ToBigInt};
+use ledger::OffChainLedger;
+use num_bigint::{BigInt, BigUint};
 use pump_n_dump_common::rest::{BalanceInfoResponse, CompletedGameInfo, UncommittedGameInfo};
 use serde::{Deserialize, Serialize};
 use treasury::DolrTreasury;
 use worker::*;
 use worker_utils::{
     parse_principal,
-    storage::{SafeStorage, StorageCell},
+    signed::{NonceStore, ReplayKey},
+    storage::{Migration, SafeStorage, VersionedSchema},
 };
 use yral_canisters_client::individual_user_template::{
     BalanceInfo, BetOnCurrentlyViewingPostError, BettingStatus, PlaceBetArg, PumpNDumpStateDiff,
@@ -29,18 +32,38 @@ use crate::{
 pub struct AddRewardReq {
     pub state_diff: StateDiff,
     pub user_canister: Principal,
+    /// deduplicates retried deliveries of a round's settlement
+    #[serde(default)]
+    pub settlement_id: Option<String>,
+}
+
+/// return a stake placed in a round that could not be settled
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RefundReq {
+    pub user_canister: Principal,
+    pub token_root: Principal,
+    pub amount: Nat,
+    pub settlement_id: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct DecrementReq {
     pub user_canister: Principal,
     pub token_root: Principal,
+    /// number of bets to debit at once
+    #[serde(default = "single_bet")]
+    pub count: u64,
+}
+
+fn single_bet() -> u64 {
+    1
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ClaimGdollrReq {
     pub user_canister: Principal,
     pub amount: Nat,
+    pub replay_key: ReplayKey,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -64,6 +87,12 @@ impl From<StateDiff> for PumpNDumpStateDiff {
     }
 }
 
+impl VersionedSchema for StateDiff {
+    type Value = Self;
+
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
 impl StateDiff {
     pub fn reward(&self) -> Nat {
         match self {
@@ -75,18 +104,15 @@ impl StateDiff {
 
 #[durable_object]
 pub struct UserEphemeralState {
-    state: State,
+    state: Rc<State>,
     env: Env,
-    // effective balance = on_chain_balance + off_chain_balance_delta
-    off_chain_balance_delta: StorageCell<BigInt>,
-    // effective earnings = on_chain_earnings + off_chain_earnings
-    off_chain_earning_delta: Option<Nat>,
+    ledger: OffChainLedger<Rc<State>>,
     user_canister: Option<Principal>,
-    state_diffs: Option<Vec<StateDiff>>,
-    pending_games: Option<HashSet<Principal>>,
     backend: StateBackend,
     dolr_treasury: DolrTreasury,
     metrics: CfMetricTx,
+    // `nonce-{timestamp}-{idempotency_key}` and `unnonced-sig-{hash}`, consumed claim messages
+    nonces: NonceStore,
 }
 
 /// An intermediary struct that exists simply to allow serializing `SystemTime`
@@ -149,6 +175,15 @@ impl UserEphemeralState {
         self.state.storage().into()
     }
 
+    /// consume the claim's replay key, returns the error response if it's rejected
+    async fn consume_replay_key(&mut self, replay_key: &ReplayKey) -> Result<Option<Response>> {
+        let mut storage = self.storage();
+        match self.nonces.consume(&mut storage, replay_key).await {
+            Ok(()) => Ok(None),
+            Err(e) => e.to_response().map(Some),
+        }
+    }
+
     /// wraps canister call to get clean worker::Response
     async fn send_bet_to_canister(
         &self,
@@ -197,7 +232,8 @@ impl UserEphemeralState {
         // fast case, avoids settling balance
         // https://github.com/dolr-ai/yral-backend-cloudflare-workers/issues/24#issuecomment-2820265311
         let mut storage = self.storage();
-        self.off_chain_balance_delta
+        self.ledger
+            .off_chain_balance_delta
             .update(&mut storage, |delta| *delta -= bet_amount_bigint.clone())
             .await?;
 
@@ -205,7 +241,8 @@ impl UserEphemeralState {
 
         // failure on this call will cause a double negation because of the last two steps
         // however, that seems unlikely
-        self.off_chain_balance_delta
+        self.ledger
+            .off_chain_balance_delta
             .update(&mut storage, |delta| *delta += bet_amount_bigint)
             .await?;
 
@@ -235,16 +272,25 @@ impl UserEphemeralState {
     }
 
     async fn queue_settle_balance_inner(&self) -> Result<()> {
-        self.state
-            .storage()
-            .set_alarm(USER_STATE_RECONCILE_TIME_MS)
-            .await?;
+        self.storage().set_alarm(USER_STATE_RECONCILE_TIME_MS).await
+    }
 
-        Ok(())
+    /// make sure the alarm runs by `at_ms`
+    async fn schedule_alarm_by(&self, at_ms: u64) -> Result<()> {
+        let mut storage = self.storage();
+        let now = Date::now().as_millis();
+        if let Some(alarm) = storage.get_alarm().await? {
+            // an alarm in the past is the one currently running
+            if alarm as u64 > now && alarm as u64 <= at_ms {
+                return Ok(());
+            }
+        }
+
+        storage.set_alarm(at_ms.saturating_sub(now) as i64).await
     }
 
     async fn queue_settle_balance(&self) -> Result<()> {
-        let Some(alarm) = self.state.storage().get_alarm().await? else {
+        let Some(alarm) = self.storage().get_alarm().await? else {
             return self.queue_settle_balance_inner().await;
         };
         let new_time = Date::now().as_millis() as i64 + USER_STATE_RECONCILE_TIME_MS;
@@ -256,59 +302,9 @@ impl UserEphemeralState {
         Ok(())
     }
 
-    async fn off_chain_earning_delta(&mut self) -> Result<&mut Nat> {
-        if self.off_chain_earning_delta.is_some() {
-            return Ok(self.off_chain_earning_delta.as_mut().unwrap());
-        }
-
-        let off_chain_earning_delta = self
-            .storage()
-            .get::<Nat>("off_chain_earning_delta")
-            .await?
-            .unwrap_or_default();
-        self.off_chain_earning_delta = Some(off_chain_earning_delta);
-        Ok(self.off_chain_earning_delta.as_mut().unwrap())
-    }
-
-    async fn pending_games(&mut self) -> Result<&mut HashSet<Principal>> {
-        if self.pending_games.is_some() {
-            return Ok(self.pending_games.as_mut().unwrap());
-        }
-
-        let pending_games = self
-            .storage()
-            .list_with_prefix("pending-game-")
-            .await
-            .map(|v| v.map(|v| v.1))
-            .collect::<Result<_>>()?;
-
-        self.pending_games = Some(pending_games);
-        Ok(self.pending_games.as_mut().unwrap())
-    }
-
-    async fn state_diffs(&mut self) -> Result<&mut Vec<StateDiff>> {
-        if self.state_diffs.is_some() {
-            return Ok(self.state_diffs.as_mut().unwrap());
-        }
-
-        let state_diffs = self
-            .storage()
-            .list_with_prefix("state-diff-")
-            .await
-            .map(|v| v.map(|v| v.1))
-            .collect::<Result<_>>()?;
-
-        self.state_diffs = Some(state_diffs);
-        Ok(self.state_diffs.as_mut().unwrap())
-    }
-
     async fn effective_balance_inner(&mut self, on_chain_balance: Nat) -> Result<Nat> {
         let mut effective_balance = on_chain_balance;
-        let off_chain_delta = self
-            .off_chain_balance_delta
-            .read(&self.storage())
-            .await?
-            .clone();
+        let off_chain_delta = self.ledger.balance_delta().await?;
 
         let off_chain_delta_to_subtract_biguint: BigUint;
         if off_chain_delta < 0u32.into() {
@@ -389,123 +385,67 @@ impl UserEphemeralState {
         })
     }
 
-    async fn decrement(&mut self, pending_game_root: Principal) -> Result<()> {
-        let mut storage = self.storage();
-        self.off_chain_balance_delta
-            .update(&mut storage, |delta| *delta -= GDOLLR_TO_E8S)
+    async fn add_state_diff(
+        &mut self,
+        state_diff: StateDiff,
+        settlement_id: Option<String>,
+    ) -> Result<()> {
+        let expires_at = self
+            .ledger
+            .add_state_diff(state_diff, settlement_id, Date::now().as_millis())
             .await?;
-
-        let inserted = self.pending_games().await?.insert(pending_game_root);
-        if !inserted {
-            return Ok(());
+        if let Some(expires_at) = expires_at {
+            self.schedule_alarm_by(expires_at).await?;
         }
-
-        storage
-            .put(
-                &format!("pending-game-{pending_game_root}"),
-                &pending_game_root,
-            )
-            .await?;
+        self.queue_settle_balance().await?;
 
         Ok(())
     }
 
-    async fn add_state_diff_inner(&mut self, state_diff: StateDiff) -> Result<()> {
-        let reward = state_diff.reward();
-        let mut storage = self.storage();
-        self.off_chain_balance_delta
-            .update(&mut storage, |delta| *delta += BigInt::from(reward.clone()))
-            .await?;
-
-        *self.off_chain_earning_delta().await? += reward;
-        storage
-            .put(
-                "off_chain_earning_delta",
-                self.off_chain_earning_delta().await?,
-            )
-            .await?;
-
-        let state_diffs = self.state_diffs().await?;
-        state_diffs.push(state_diff.clone());
-        let next_idx = state_diffs.len() - 1;
-
-        if let StateDiff::CompletedGame(ginfo) = &state_diff {
-            self.pending_games().await?.remove(&ginfo.token_root);
-            storage
-                .delete(&format!("pending-game-{}", ginfo.token_root))
-                .await?;
-        }
-
-        storage
-            .put(&format!("state-diff-{}", next_idx), &state_diff)
+    /// return the stake to the off-chain balance, the game never reaches the canister
+    async fn refund(
+        &mut self,
+        token_root: Principal,
+        amount: Nat,
+        settlement_id: String,
+    ) -> Result<()> {
+        let expires_at = self
+            .ledger
+            .refund(token_root, amount, settlement_id, Date::now().as_millis())
             .await?;
 
-        Ok(())
+        self.schedule_alarm_by(expires_at).await
     }
 
-    async fn add_state_diff(&mut self, state_diff: StateDiff) -> Result<()> {
-        self.add_state_diff_inner(state_diff).await?;
-        self.queue_settle_balance().await?;
+    /// settle the off-chain state if anything is pending
+    async fn settle_pending_balance(&mut self) -> Result<()> {
+        if self.ledger.state_diffs().await?.is_empty() {
+            return Ok(());
+        }
+        let Some(user_canister) = self.try_get_user_canister().await else {
+            console_warn!("state diffs without user_canister set?!");
+            return Ok(());
+        };
 
-        Ok(())
+        self.settle_balance(user_canister).await
     }
 
     async fn settle_balance(&mut self, user_canister: Principal) -> Result<()> {
-        let mut storage = self.storage();
-        let to_settle = self.off_chain_balance_delta.read(&storage).await?.clone();
-
-        let earnings = self.off_chain_earning_delta().await?.clone();
-        self.off_chain_earning_delta = Some(0u32.into());
-        storage.delete("off_chain_earning_delta").await?;
-
-        let state_diffs = std::mem::take(self.state_diffs().await?);
-        storage
-            .delete_multiple(
-                (0..state_diffs.len())
-                    .map(|i| format!("state-diff-{i}"))
-                    .collect(),
-            )
-            .await?;
-
-        let mut delta_delta = BigInt::from(0u32);
-        let state_diffs_conv = state_diffs
+        let settlement = self.ledger.take_settlement().await?;
+        let state_diffs = settlement
+            .state_diffs
             .iter()
-            .map(|diff| {
-                match diff {
-                    StateDiff::CompletedGame(info) => {
-                        delta_delta += BigInt::from(info.pumps + info.dumps) * GDOLLR_TO_E8S;
-                        delta_delta -= info.reward.clone().0.to_bigint().unwrap();
-                    }
-                    StateDiff::CreatorReward(rew) => {
-                        delta_delta -= rew.clone().0.to_bigint().unwrap();
-                    }
-                }
-                diff.clone().into()
-            })
+            .cloned()
+            .map(PumpNDumpStateDiff::from)
             .collect();
 
-        self.off_chain_balance_delta
-            .update(&mut storage, |delta| *delta += delta_delta)
-            .await?;
-
         let res = self
             .backend
-            .reconcile_user_state(user_canister, state_diffs_conv)
+            .reconcile_user_state(user_canister, state_diffs)
             .await;
 
         if let Err(e) = res {
-            self.off_chain_balance_delta
-                .set(&mut storage, to_settle)
-                .await?;
-            self.state_diffs = Some(state_diffs.clone());
-            self.off_chain_earning_delta = Some(earnings.clone());
-
-            storage.put("off_chain_earning_delta", &earnings).await?;
-
-            for (i, state_diff) in state_diffs.into_iter().enumerate() {
-                storage.put(&format!("state-diff-{i}"), &state_diff).await?;
-            }
-
+            self.ledger.restore_settlement(settlement).await?;
             return Err(e);
         }
 
@@ -597,14 +537,15 @@ impl UserEphemeralState {
 
     async fn effective_game_count(&mut self, user_canister: Principal) -> Result<u64> {
         let on_chain_count = self.backend.game_count(user_canister).await?;
-        let off_chain_count = self.state_diffs().await?.len() + self.pending_games().await?.len();
+        let off_chain_count =
+            self.ledger.state_diffs().await?.len() + self.ledger.pending_games().await?.len();
 
         Ok(on_chain_count + off_chain_count as u64)
     }
 
     async fn effective_net_earnings(&mut self, user_canister: Principal) -> Result<Nat> {
         let on_chain_earnings = self.backend.net_earnings(user_canister).await?;
-        let off_chain_earnings = self.off_chain_earning_delta().await?.clone();
+        let off_chain_earnings = self.ledger.off_chain_earning_delta().await?.clone();
 
         Ok(on_chain_earnings + off_chain_earnings)
     }
@@ -617,19 +558,17 @@ impl DurableObject for UserEphemeralState {
 
         let backend = StateBackend::new(&env).unwrap();
 
+        let state = Rc::new(state);
+
         Self {
+            ledger: OffChainLedger::new(state.clone()),
             state,
             env,
-            off_chain_balance_delta: StorageCell::new("off_chain_balance_delta", || {
-                BigInt::from(0)
-            }),
-            off_chain_earning_delta: None,
             user_canister: None,
-            state_diffs: None,
-            pending_games: None,
             dolr_treasury: DolrTreasury::default(),
             backend,
             metrics: metrics(),
+            nonces: NonceStore::default(),
         }
     }
 
@@ -671,10 +610,13 @@ impl DurableObject for UserEphemeralState {
                 this.set_user_canister(decr_req.user_canister).await?;
 
                 let bal = this.effective_balance(decr_req.user_canister).await?;
-                if bal < GDOLLR_TO_E8S {
+                if bal < Nat::from(GDOLLR_TO_E8S) * decr_req.count {
                     return Response::error("Not enough balance", 400);
                 }
-                let res = this.decrement(decr_req.token_root).await;
+                let res = this
+                    .ledger
+                    .decrement(decr_req.token_root, decr_req.count)
+                    .await;
                 if let Err(e) = res {
                     return Response::error(format!("failed to decrement: {e}"), 500);
                 }
@@ -686,7 +628,34 @@ impl DurableObject for UserEphemeralState {
                 let reward_req: AddRewardReq = req.json().await?;
 
                 this.set_user_canister(reward_req.user_canister).await?;
-                this.add_state_diff(reward_req.state_diff).await?;
+                if let Some(settlement_id) = &reward_req.settlement_id {
+                    if this.ledger.settlement_received(settlement_id).await? {
+                        return Response::ok("done");
+                    }
+                }
+                this.add_state_diff(reward_req.state_diff, reward_req.settlement_id)
+                    .await?;
+
+                Response::ok("done")
+            })
+            .post_async("/refund", |mut req, ctx| async move {
+                let this = ctx.data;
+                let refund_req: RefundReq = req.json().await?;
+
+                this.set_user_canister(refund_req.user_canister).await?;
+                if this
+                    .ledger
+                    .settlement_received(&refund_req.settlement_id)
+                    .await?
+                {
+                    return Response::ok("done");
+                }
+                this.refund(
+                    refund_req.token_root,
+                    refund_req.amount,
+                    refund_req.settlement_id,
+                )
+                .await?;
 
                 Response::ok("done")
             })
@@ -695,6 +664,9 @@ impl DurableObject for UserEphemeralState {
                 let claim_req: ClaimGdollrReq = req.json().await?;
 
                 this.set_user_canister(claim_req.user_canister).await?;
+                if let Some(err_res) = this.consume_replay_key(&claim_req.replay_key).await? {
+                    return Ok(err_res);
+                }
 
                 this.claim_gdollr(claim_req.user_canister, claim_req.amount)
                     .await
@@ -704,6 +676,9 @@ impl DurableObject for UserEphemeralState {
                 let claim_req: ClaimGdollrReq = req.json().await?;
 
                 this.set_user_canister(claim_req.user_canister).await?;
+                if let Some(err_res) = this.consume_replay_key(&claim_req.replay_key).await? {
+                    return Ok(err_res);
+                }
 
                 this.claim_gdollr_v2(claim_req.user_canister, claim_req.amount)
                     .await
@@ -742,7 +717,8 @@ impl DurableObject for UserEphemeralState {
                         .map(|p| UncommittedGameInfo::Pending { token_root: *p })
                         .collect::<Vec<_>>();
                     let completed_games =
-                        this.state_diffs()
+                        this.ledger
+                            .state_diffs()
                             .await?
                             .iter()
                             .filter_map(|diff| match diff {
@@ -761,17 +737,29 @@ impl DurableObject for UserEphemeralState {
     }
 
     async fn alarm(&mut self) -> Result<Response> {
-        let Some(user_canister) = self.try_get_user_canister().await else {
-            console_warn!("alarm set without user_canister set?!");
-            return Response::ok("not ready");
-        };
-
-        if self.state_diffs().await?.is_empty() {
-            console_warn!("alarm set without any updates?!");
-            return Response::ok("not required");
+        if let Err(e) = self.settle_pending_balance().await {
+            console_error!("failed to settle balance: {e}");
+            if let Err(e) = self.queue_settle_balance_inner().await {
+                console_error!("failed to requeue balance settlement: {e}");
+            }
         }
 
-        self.settle_balance(user_canister).await?;
+        let next_prune = match self
+            .ledger
+            .prune_received_settlements(Date::now().as_millis())
+            .await
+        {
+            Ok(next_expiry) => next_expiry,
+            Err(e) => {
+                console_error!("failed to prune received settlements: {e}");
+                Some(Date::now().as_millis() + USER_STATE_RECONCILE_TIME_MS as u64)
+            }
+        };
+        if let Some(next_prune) = next_prune {
+            if let Err(e) = self.schedule_alarm_by(next_prune).await {
+                console_error!("failed to schedule settlement pruning: {e}");
+            }
+        }
 
         Response::ok("done")
     }
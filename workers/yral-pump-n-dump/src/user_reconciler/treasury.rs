@@ -2,7 +2,7 @@ use candid::Nat;
 use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
 use worker::{Date, Result};
-use worker_utils::storage::SafeStorage;
+use worker_utils::storage::{Migration, SafeStorage, VersionedSchema};
 
 use crate::consts::MAXIMUM_DOLR_TREASURY_PER_DAY_PER_USER;
 
@@ -12,6 +12,12 @@ struct DolrTreasuryInner {
     last_reset_epoch: u64,
 }
 
+impl VersionedSchema for DolrTreasuryInner {
+    type Value = Self;
+
+    const MIGRATIONS: &'static [Migration] = &[];
+}
+
 impl Default for DolrTreasuryInner {
     fn default() -> Self {
         Self {
@@ -25,13 +31,13 @@ impl Default for DolrTreasuryInner {
 pub struct DolrTreasury(Option<DolrTreasuryInner>);
 
 impl DolrTreasury {
-    async fn get_or_init(&mut self, storage: &SafeStorage) -> Result<&mut DolrTreasuryInner> {
+    async fn get_or_init(&mut self, storage: &mut SafeStorage) -> Result<&mut DolrTreasuryInner> {
         if self.0.is_some() {
             return Ok(self.0.as_mut().unwrap());
         }
 
         let dolr_treasury_limit = storage
-            .get("dolr-treasury-limit")
+            .get_versioned::<DolrTreasuryInner>("dolr-treasury-limit")
             .await?
             .unwrap_or_default();
         self.0 = Some(dolr_treasury_limit);
@@ -42,7 +48,9 @@ impl DolrTreasury {
         let treasury = self.get_or_init(storage).await?;
         if Date::now().as_millis() - (24 * 3600 * 1000) >= treasury.last_reset_epoch {
             *treasury = DolrTreasuryInner::default();
-            storage.put("dolr-treasury-limit", treasury).await?;
+            storage
+                .put_versioned::<DolrTreasuryInner>("dolr-treasury-limit", treasury)
+                .await?;
         };
 
         Ok(treasury)
@@ -54,7 +62,9 @@ impl DolrTreasury {
             return Err(worker::Error::RustError("daily limit reached".into()));
         }
         treasury.amount -= amount.0;
-        storage.put("dolr-treasury-limit", treasury).await?;
+        storage
+            .put_versioned::<DolrTreasuryInner>("dolr-treasury-limit", treasury)
+            .await?;
 
         Ok(())
     }
@@ -63,7 +73,9 @@ impl DolrTreasury {
         let treasury = self.treasury(storage).await?;
         treasury.amount =
             (treasury.amount.clone() + amount.0).min(MAXIMUM_DOLR_TREASURY_PER_DAY_PER_USER.into());
-        storage.put("dolr-treasury-limit", treasury).await?;
+        storage
+            .put_versioned::<DolrTreasuryInner>("dolr-treasury-limit", treasury)
+            .await?;
 
         Ok(())
     }